@@ -47,9 +47,9 @@ fn main() -> anyhow::Result<()> {
     // Escribe CSV en data/od_today.csv
     fs::create_dir_all("data").ok();
     let mut w = csv::Writer::from_path("data/od_today.csv")?;
-    w.write_record(&["date","origin_h3","dest_h3","n_trucks","n_cars","conf"])?;
+    w.write_record(["date","origin_h3","dest_h3","n_trucks","n_cars","conf"])?;
     for (o,d,nt,nc,conf) in rows {
-        w.write_record(&[
+        w.write_record([
             date.to_string(),
             o, d,
             format!("{:.0}", nt),
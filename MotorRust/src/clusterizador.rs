@@ -1,15 +1,17 @@
 
 //! Agrupacion de pedidos sobre celdas S2 (sin overlapping), version compatible con s2 = 0.0.13
 
-use axum::Json;
+use axum::{extract::State, Json};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
 use s2::cellid::CellID;
 use s2::cell::Cell;
 use s2::latlng::LatLng;
 use s2::point::Point;
 
 use crate::models::types::PedidoPoints;
+use crate::tiles::{LayerBuilder, TileCache, TileId, Value};
 
 /// Convierte lat/lon (grados) en una celda S2 con nivel determinado
 #[inline]
@@ -22,7 +24,7 @@ fn s2_cell(lat: f64, lon: f64, level: u8) -> CellID {
 
 /// Obtiene los vertices del poligono de una celda S2
 fn cell_vertices(cell: &CellID) -> Vec<[f64; 2]> {
-    let s2cell = Cell::from(cell.clone());
+    let s2cell = Cell::from(*cell);
     let mut coords = Vec::new();
     for v in 0..4 {
         let vert = s2cell.vertex(v);
@@ -41,8 +43,64 @@ fn cell_vertices(cell: &CellID) -> Vec<[f64; 2]> {
     coords
 }
 
+/// Agrupaciones que se conservan para `/tiles/orders/{id}/...` (las más antiguas caducan)
+const ORDERS_RETAINED: usize = 32;
+
+/// Últimas agrupaciones calculadas, servidas como capa de teselas en `/tiles/orders/{id}/{z}/{x}/{y}.mvt`.
+/// Cada `/orders/filter` devuelve su `orders_id`: las peticiones de un cliente no pisan las de otro.
+#[derive(Default)]
+pub struct OrderZones {
+    /// Último id asignado y agrupaciones retenidas (la más reciente al final)
+    recent: RwLock<(u64, VecDeque<Arc<OrdersSnapshot>>)>,
+}
+
+#[derive(Default)]
+pub struct OrdersSnapshot {
+    /// `orders_id` devuelto por `/orders/filter`
+    pub id: u64,
+    pub veh: String,
+    pub zones: Vec<(CellID, usize)>,
+    pub tiles: TileCache,
+}
+
+impl OrderZones {
+    /// Agrupación `id`, si no ha caducado
+    pub fn get(&self, id: u64) -> Option<Arc<OrdersSnapshot>> {
+        self.recent.read().expect("zonas S2").1.iter().find(|s| s.id == id).cloned()
+    }
+
+    fn publish(&self, veh: &str, zones: Vec<(CellID, usize)>) -> u64 {
+        let mut g = self.recent.write().expect("zonas S2");
+        g.0 += 1;
+        let id = g.0;
+        g.1.push_back(Arc::new(OrdersSnapshot { id, veh: veh.to_string(), zones, tiles: TileCache::default() }));
+        while g.1.len() > ORDERS_RETAINED {
+            g.1.pop_front();
+        }
+        id
+    }
+}
+
+/// Capa `orders_s2_zones` con las mismas propiedades que el GeoJSON de `/orders/filter`
+pub fn orders_layer(tile: &TileId, snap: &OrdersSnapshot) -> LayerBuilder {
+    let mut layer = LayerBuilder::new("orders_s2_zones");
+    for (cell, count) in &snap.zones {
+        let props = vec![
+            ("s2_cell", Value::Str(cell.to_token())),
+            ("pedidos", Value::U64(*count as u64)),
+            ("vehicle_type", Value::Str(snap.veh.clone())),
+            ("level", Value::U64(cell.level())),
+        ];
+        layer.add_polygon(tile, cell.0, &cell_vertices(cell), props);
+    }
+    layer
+}
+
 /// API: agrupacion de pedidos usando S2 (sin overlapping)
-pub async fn global_orders(Json(pedidos): Json<PedidoPoints>) -> Json<serde_json::Value> {
+pub async fn global_orders(
+    State(orders): State<Arc<OrderZones>>,
+    Json(pedidos): Json<PedidoPoints>,
+) -> Json<serde_json::Value> {
     let l6 = 10u8;  // ~4 km 
     let l7 = 12u8;  // ~1 km
     let l8 = 14u8;  // ~250 m
@@ -114,9 +172,12 @@ pub async fn global_orders(Json(pedidos): Json<PedidoPoints>) -> Json<serde_json
         }));
     }
 
+    let id = orders.publish(&pedidos.veh, counts.into_iter().collect());
+
     let gj = json!({
         "type": "FeatureCollection",
         "name": "orders_s2_zones",
+        "orders_id": id,
         "crs": { "type": "name", "properties": { "name": "EPSG:4326" }},
         "features": features
     });
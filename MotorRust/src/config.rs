@@ -0,0 +1,699 @@
+//! config.rs — Configuración por capas de `AppCfg` y del `DelayCfg` H3
+//!
+//! Orden de precedencia (de menor a mayor):
+//! 1. `AppCfg::default()` y `h3types::DelayCfg::default()`
+//! 2. Fichero TOML o YAML (`--config <ruta>` o `MADGRID_CONFIG`)
+//! 3. Variables de entorno `MADGRID_*` (`MADGRID_H3_RES=8`, `MADGRID_DELAY__BPR_A=0.2`)
+//!    — solo las que nombran un campo; las desconocidas y las de secretos `env:` se ignoran
+//! 4. Flags de línea de comandos (`--h3-res 8`, `--set delay.bpr_a=0.2`)
+//!
+//! Los parámetros del modelo van en la sección `[delay]` del fichero. `delay.res`,
+//! `delay.min_conf_for_pure_orange` y `delay.max_concurrent_calls` se derivan siempre
+//! de `h3_res`, `min_conf_orange` y `max_concurrent`.
+//!
+//! Varias ciudades en un proceso: una entrada `[[regions]]` por ciudad (ver `RegionCfg`), con su
+//! propio `[regions.delay]` fusionado sobre el `[delay]` global. Sin `[[regions]]` hay una sola
+//! región implícita (`region_id`) construida con los campos globales.
+
+use clap::Parser;
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use toml::{Table, Value};
+
+use crate::models::h3types::DelayCfg as ODDelayCfg;
+use crate::models::types::{AppCfg, FetchCfg, OdColumns, OdS3Cfg, RegionCfg};
+use crate::secrets::SecretSource;
+use crate::source::SourceSpec;
+
+const ENV_PREFIX: &str = "MADGRID_";
+const ENV_CONFIG: &str = "MADGRID_CONFIG";
+const DELAY_SECTION: &str = "delay";
+const REGIONS_KEY: &str = "regions";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("no se pudo leer {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{}: extensión no soportada (usa .toml, .yaml o .yml)", path.display())]
+    Format { path: PathBuf },
+    #[error("{}: {msg}", path.display())]
+    Parse { path: PathBuf, msg: String },
+    #[error("campo `{field}` inválido: {reason}")]
+    Invalid { field: String, reason: String },
+}
+
+impl ConfigError {
+    fn invalid(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::Invalid { field: field.into(), reason: reason.into() }
+    }
+}
+
+/// Configuración efectiva del proceso
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub app: AppCfg,
+    /// `[delay]` global (base de todas las regiones)
+    pub delay: ODDelayCfg,
+    /// Regiones resueltas; nunca vacío
+    pub regions: Vec<RegionSettings>,
+    /// Fichero de configuración usado (si lo hay)
+    pub source: Option<PathBuf>,
+    /// Capas de ENV y CLI originales, para reconstruir la config en una recarga
+    layers: Layers,
+}
+
+/// Región ya resuelta: globales + overrides de su `[[regions]]`
+#[derive(Clone, Debug)]
+pub struct RegionSettings {
+    pub id: String,
+    pub od_url: String,
+    pub od_s3: OdS3Cfg,
+    pub od_columns: OdColumns,
+    /// Validación de fechas O/D (global, ver `AppCfg`)
+    pub od_max_age_days: u32,
+    pub od_max_future_days: u32,
+    pub quarantine_out: Option<String>,
+    pub roadmap_csv: Option<String>,
+    pub t_od_s: u64,
+    pub jsonl_out: Option<String>,
+    pub sqlite_out: Option<String>,
+    pub parquet_out: Option<String>,
+    /// Retención del histórico SQLite (global, ver `AppCfg`)
+    pub history_keep_days: u32,
+    pub history_keep_snapshots: usize,
+    pub orion_url: Option<String>,
+    pub orion_tenant: Option<String>,
+    pub orion_token: Option<SecretSource>,
+    pub delay: ODDelayCfg,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Layers {
+    cli: Cli,
+    env: Vec<(String, String)>,
+}
+
+/// Flags de línea de comandos. Cualquier campo sin flag propio se puede fijar con `--set`.
+#[derive(Clone, Debug, Default, Parser)]
+#[command(name = "madgrid", about = "Malla H3 de delays a partir de O/D + TomTom")]
+pub struct Cli {
+    /// Fichero de configuración (.toml, .yaml o .yml)
+    #[arg(long, short = 'c')]
+    pub config: Option<PathBuf>,
+    /// Dirección/puerto del servidor HTTP
+    #[arg(long)]
+    pub bind: Option<String>,
+    /// URL del O/D diario
+    #[arg(long)]
+    pub od_url: Option<String>,
+    /// Periodicidad de refresco del O/D (segundos)
+    #[arg(long)]
+    pub t_od_s: Option<u64>,
+    /// Resolución H3 de trabajo (0..=15)
+    #[arg(long)]
+    pub h3_res: Option<u8>,
+    /// Umbral de confianza para usar fallback TomTom
+    #[arg(long)]
+    pub min_conf_orange: Option<f32>,
+    /// Concurrencia máxima de llamadas externas
+    #[arg(long)]
+    pub max_concurrent: Option<usize>,
+    /// Fichero con la clave API TomTom (la clave nunca va en la línea de comandos)
+    #[arg(long)]
+    pub tomtom_key_file: Option<PathBuf>,
+    /// Sobrescribe cualquier campo, p.ej. `--set delay.bpr_a=0.2`
+    #[arg(long = "set", value_name = "CLAVE=VALOR")]
+    pub set: Vec<String>,
+}
+
+impl Cli {
+    fn typed_overrides(&self) -> Vec<(&'static str, Value)> {
+        let mut out = Vec::new();
+        if let Some(v) = &self.bind { out.push(("bind", Value::from(v.as_str()))); }
+        if let Some(v) = &self.od_url { out.push(("od_url", Value::from(v.as_str()))); }
+        if let Some(v) = self.t_od_s { out.push(("t_od_s", Value::from(v as i64))); }
+        if let Some(v) = self.h3_res { out.push(("h3_res", Value::from(v as i64))); }
+        if let Some(v) = self.min_conf_orange { out.push(("min_conf_orange", Value::from(v as f64))); }
+        if let Some(v) = self.max_concurrent { out.push(("max_concurrent", Value::from(v as i64))); }
+        if let Some(p) = &self.tomtom_key_file {
+            out.push(("tomtom_key", Value::from(format!("file:{}", p.display()))));
+        }
+        out
+    }
+}
+
+/// Carga la configuración desde los argumentos y el entorno del proceso.
+pub fn load() -> Result<Settings, ConfigError> {
+    load_from(Cli::parse(), std::env::vars())
+}
+
+/// Igual que `load`, pero con CLI y entorno explícitos (tests, binarios auxiliares).
+pub fn load_from(
+    cli: Cli,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> Result<Settings, ConfigError> {
+    let env: Vec<(String, String)> = env
+        .into_iter()
+        .filter(|(k, _)| k.starts_with(ENV_PREFIX))
+        .collect();
+
+    // 1) Defaults: también sirven de plantilla de tipos para ENV y `--set`
+    let defaults = defaults_table()?;
+    let mut doc = defaults.clone();
+
+    // 2) Fichero
+    let source = cli.config.clone().or_else(|| {
+        env.iter()
+            .find(|(k, _)| k == ENV_CONFIG)
+            .map(|(_, v)| PathBuf::from(v))
+    });
+    if let Some(path) = &source {
+        merge(&mut doc, read_file(path)?);
+    }
+
+    // 3) Entorno: MADGRID_DELAY__BPR_A -> delay.bpr_a. Solo campos que existen; las variables
+    //    que son destino de un secreto `env:` (p.ej. MADGRID_ADMIN_TOKEN) no son config
+    let secret_vars = env_secret_targets(&Value::Table(doc.clone()));
+    for (k, raw) in &env {
+        if k == ENV_CONFIG || secret_vars.contains(k) {
+            continue;
+        }
+        let key = k[ENV_PREFIX.len()..].to_ascii_lowercase().replace("__", ".");
+        if !known_key(&defaults, &key) {
+            tracing::warn!("{k}: no corresponde a ningún campo de la configuración, se ignora");
+            continue;
+        }
+        set_raw(&mut doc, &defaults, &key, raw)?;
+    }
+
+    // 4) CLI
+    for (key, v) in cli.typed_overrides() {
+        set_path(&mut doc, key, v);
+    }
+    for kv in &cli.set {
+        let (key, raw) = kv
+            .split_once('=')
+            .ok_or_else(|| ConfigError::invalid("--set", format!("se esperaba CLAVE=VALOR, llegó {kv:?}")))?;
+        set_raw(&mut doc, &defaults, key.trim(), raw)?;
+    }
+
+    build(doc, source, Layers { cli, env })
+}
+
+/// Deserializa y valida un documento ya fusionado.
+fn build(mut doc: Table, source: Option<PathBuf>, layers: Layers) -> Result<Settings, ConfigError> {
+    let delay_tbl = match doc.remove(DELAY_SECTION) {
+        Some(Value::Table(t)) => t,
+        None => Table::new(),
+        Some(_) => return Err(ConfigError::invalid(DELAY_SECTION, "se esperaba una tabla")),
+    };
+    let raw_regions = doc.remove(REGIONS_KEY);
+    let app: AppCfg = deserialize(Value::Table(doc), "")?;
+    let mut delay: ODDelayCfg = deserialize(Value::Table(delay_tbl.clone()), DELAY_SECTION)?;
+
+    // AppCfg manda sobre los campos duplicados del DelayCfg
+    delay.res = app.h3_res;
+    delay.min_conf_for_pure_orange = app.min_conf_orange;
+    delay.max_concurrent_calls = app.max_concurrent;
+
+    let regions = match raw_regions {
+        None => vec![RegionSettings {
+            id: app.region_id.clone(),
+            od_url: app.od_url.clone(),
+            od_s3: app.od_s3.clone(),
+            od_columns: app.od_columns.clone(),
+            od_max_age_days: app.od_max_age_days,
+            od_max_future_days: app.od_max_future_days,
+            quarantine_out: app.quarantine_out.clone(),
+            roadmap_csv: app.roadmap_csv.clone(),
+            t_od_s: app.t_od_s,
+            jsonl_out: app.jsonl_out.clone(),
+            sqlite_out: app.sqlite_out.clone(),
+            parquet_out: app.parquet_out.clone(),
+            history_keep_days: app.history_keep_days,
+            history_keep_snapshots: app.history_keep_snapshots,
+            orion_url: app.orion_url.clone(),
+            orion_tenant: app.orion_tenant.clone(),
+            orion_token: app.orion_token.clone(),
+            delay: delay.clone(),
+        }],
+        Some(raw) => resolve_regions(&app, &delay_tbl, raw)?,
+    };
+
+    let settings = Settings { app, delay, regions, source, layers };
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// Resuelve `[[regions]]`: cada `[regions.delay]` se fusiona sobre el `[delay]` global.
+fn resolve_regions(app: &AppCfg, base_delay: &Table, raw: Value) -> Result<Vec<RegionSettings>, ConfigError> {
+    let Value::Array(items) = raw else {
+        return Err(ConfigError::invalid(REGIONS_KEY, "se esperaba una lista [[regions]]"));
+    };
+    if items.is_empty() {
+        return Err(ConfigError::invalid(REGIONS_KEY, "se necesita al menos una región"));
+    }
+
+    let mut out = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        let field = format!("{REGIONS_KEY}[{i}]");
+        let Value::Table(mut t) = item else {
+            return Err(ConfigError::invalid(field, "se esperaba una tabla"));
+        };
+        let mut delay_tbl = base_delay.clone();
+        match t.remove(DELAY_SECTION) {
+            Some(Value::Table(over)) => merge(&mut delay_tbl, over),
+            None => {}
+            Some(_) => return Err(ConfigError::invalid(format!("{field}.{DELAY_SECTION}"), "se esperaba una tabla")),
+        }
+
+        let rc: RegionCfg = deserialize(Value::Table(t), &field)?;
+        let mut delay: ODDelayCfg =
+            deserialize(Value::Table(delay_tbl), &format!("{REGIONS_KEY}.{}.{DELAY_SECTION}", rc.id))?;
+        delay.res = rc.h3_res.unwrap_or(app.h3_res);
+        delay.min_conf_for_pure_orange = rc.min_conf_orange.unwrap_or(app.min_conf_orange);
+        delay.max_concurrent_calls = app.max_concurrent;
+
+        out.push(RegionSettings {
+            od_url: rc.od_url,
+            od_s3: rc.od_s3.unwrap_or_else(|| app.od_s3.clone()),
+            od_columns: rc.od_columns.unwrap_or_else(|| app.od_columns.clone()),
+            od_max_age_days: app.od_max_age_days,
+            od_max_future_days: app.od_max_future_days,
+            quarantine_out: rc.quarantine_out,
+            roadmap_csv: rc.roadmap_csv,
+            t_od_s: rc.t_od_s.unwrap_or(app.t_od_s),
+            jsonl_out: rc.jsonl_out,
+            sqlite_out: rc.sqlite_out,
+            parquet_out: rc.parquet_out,
+            history_keep_days: app.history_keep_days,
+            history_keep_snapshots: app.history_keep_snapshots,
+            orion_url: rc.orion_url.or_else(|| app.orion_url.clone()),
+            orion_tenant: rc.orion_tenant.or_else(|| app.orion_tenant.clone()),
+            orion_token: rc.orion_token.or_else(|| app.orion_token.clone()),
+            delay,
+            id: rc.id,
+        });
+    }
+    Ok(out)
+}
+
+impl Settings {
+    /// Vuelve a leer el fichero y aplica encima las mismas capas de ENV y CLI.
+    pub fn reload(&self) -> Result<Settings, ConfigError> {
+        load_from(self.layers.cli.clone(), self.layers.env.clone())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        validate_app(&self.app)?;
+        validate_delay(&self.delay).map_err(|e| prefixed(DELAY_SECTION, e))?;
+
+        let mut seen = std::collections::HashSet::new();
+        for r in &self.regions {
+            let prefix = format!("{REGIONS_KEY}.{}", r.id);
+            let id_ok = !r.id.is_empty()
+                && r.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            check(id_ok, &format!("{REGIONS_KEY}.id"), || {
+                format!("{:?} no válido (solo letras, dígitos, '-' y '_')", r.id)
+            })?;
+            check(seen.insert(r.id.as_str()), &format!("{REGIONS_KEY}.id"), || {
+                format!("{:?} duplicado", r.id)
+            })?;
+            check_od_url(&prefix, &r.od_url, &r.od_s3)?;
+            check(r.t_od_s > 0, &format!("{prefix}.t_od_s"), || "debe ser > 0".into())?;
+            if let Some(url) = &r.orion_url {
+                check_url(&format!("{prefix}.orion_url"), url)?;
+            }
+            validate_delay(&r.delay).map_err(|e| prefixed(&format!("{prefix}.{DELAY_SECTION}"), e))?;
+        }
+        Ok(())
+    }
+}
+
+fn prefixed(prefix: &str, e: ConfigError) -> ConfigError {
+    match e {
+        ConfigError::Invalid { field, reason } => ConfigError::invalid(format!("{prefix}.{field}"), reason),
+        other => other,
+    }
+}
+
+// ===============================
+// Validación
+// ===============================
+
+fn check(ok: bool, field: &str, reason: impl FnOnce() -> String) -> Result<(), ConfigError> {
+    if ok { Ok(()) } else { Err(ConfigError::invalid(field, reason())) }
+}
+
+fn check_url(field: &str, url: &str) -> Result<(), ConfigError> {
+    reqwest::Url::parse(url)
+        .map(|_| ())
+        .map_err(|e| ConfigError::invalid(field, format!("URL no válida {url:?}: {e}")))
+}
+
+/// `od_url` con esquema soportado (ver `source`); con `s3://`, además el endpoint de `od_s3`
+fn check_od_url(prefix: &str, url: &str, s3: &OdS3Cfg) -> Result<(), ConfigError> {
+    let field = |name: &str| if prefix.is_empty() { name.to_string() } else { format!("{prefix}.{name}") };
+    let spec = SourceSpec::parse(url).map_err(|e| ConfigError::invalid(field("od_url"), e))?;
+    if matches!(spec, SourceSpec::S3 { .. }) {
+        check_url(&field("od_s3.endpoint"), &s3.endpoint)?;
+    }
+    Ok(())
+}
+
+fn validate_app(c: &AppCfg) -> Result<(), ConfigError> {
+    let port_ok = c
+        .bind
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    check(port_ok, "bind", || format!("se esperaba host:puerto, llegó {:?}", c.bind))?;
+    check_od_url("", &c.od_url, &c.od_s3)?;
+    check(c.t_od_s > 0, "t_od_s", || "debe ser > 0".into())?;
+    check(c.fetch.connect_timeout_s > 0, "fetch.connect_timeout_s", || "debe ser > 0".into())?;
+    check(c.fetch.read_timeout_s > 0, "fetch.read_timeout_s", || "debe ser > 0".into())?;
+    check(c.fetch.backoff_max_ms >= c.fetch.backoff_ms, "fetch.backoff_max_ms", || {
+        format!("{} < backoff_ms ({})", c.fetch.backoff_max_ms, c.fetch.backoff_ms)
+    })?;
+    check(c.h3_res <= 15, "h3_res", || format!("{} fuera de 0..=15", c.h3_res))?;
+    check((0.0..=1.0).contains(&c.min_conf_orange), "min_conf_orange", || {
+        format!("{} fuera de 0..=1", c.min_conf_orange)
+    })?;
+    check(c.max_concurrent > 0, "max_concurrent", || "debe ser > 0".into())?;
+    check(c.history_keep_snapshots > 0, "history_keep_snapshots", || "debe ser > 0".into())?;
+    check(c.snapshots_retained > 0, "snapshots_retained", || "debe ser > 0".into())?;
+    check(c.event_delay_threshold.is_finite() && c.event_delay_threshold >= 0.0, "event_delay_threshold", || {
+        "debe ser >= 0".into()
+    })?;
+    if let Some(url) = &c.orion_url {
+        check_url("orion_url", url)?;
+    }
+    Ok(())
+}
+
+/// Valida el `DelayCfg` H3. Los nombres de campo del error son relativos a `[delay]`.
+pub fn validate_delay(d: &ODDelayCfg) -> Result<(), ConfigError> {
+    let floats = [
+        ("alpha_vol", d.alpha_vol),
+        ("beta_truck_mix", d.beta_truck_mix),
+        ("delay_min", d.delay_min),
+        ("delay_max", d.delay_max),
+        ("min_conf_for_pure_orange", d.min_conf_for_pure_orange),
+        ("truck_factor", d.truck_factor),
+        ("car_factor", d.car_factor),
+        ("bpr_a", d.bpr_a),
+        ("bpr_b", d.bpr_b),
+        ("truck_gamma", d.truck_gamma),
+        ("capacity_percentile", d.capacity_percentile),
+        ("capacity_floor", d.capacity_floor),
+        ("vc_cap", d.vc_cap),
+        ("through_weight", d.through_weight),
+    ];
+    for (field, v) in floats {
+        check(v.is_finite(), field, || format!("{v} no es un número finito"))?;
+    }
+
+    check(d.res <= 15, "res", || format!("{} fuera de 0..=15", d.res))?;
+    check(d.delay_min > 0.0, "delay_min", || "debe ser > 0".into())?;
+    check(d.delay_max >= d.delay_min, "delay_max", || {
+        format!("{} < delay_min ({})", d.delay_max, d.delay_min)
+    })?;
+    check((0.0..=1.0).contains(&d.min_conf_for_pure_orange), "min_conf_for_pure_orange", || {
+        format!("{} fuera de 0..=1", d.min_conf_for_pure_orange)
+    })?;
+    check(d.max_concurrent_calls > 0, "max_concurrent_calls", || "debe ser > 0".into())?;
+    check(d.truck_factor >= 0.0, "truck_factor", || "debe ser >= 0".into())?;
+    check(d.car_factor >= 0.0, "car_factor", || "debe ser >= 0".into())?;
+    check(d.bpr_a >= 0.0, "bpr_a", || "debe ser >= 0".into())?;
+    check(d.bpr_b > 0.0, "bpr_b", || "debe ser > 0".into())?;
+    check(d.truck_gamma >= 0.0, "truck_gamma", || "debe ser >= 0".into())?;
+    check(d.capacity_percentile > 0.0 && d.capacity_percentile <= 1.0, "capacity_percentile", || {
+        format!("{} fuera de (0, 1]", d.capacity_percentile)
+    })?;
+    check(d.capacity_floor > 0.0, "capacity_floor", || "debe ser > 0".into())?;
+    check(d.vc_cap > 0.0, "vc_cap", || "debe ser > 0".into())?;
+    check(d.through_weight >= 0.0, "through_weight", || "debe ser >= 0".into())?;
+    Ok(())
+}
+
+// ===============================
+// Utilidades de capas
+// ===============================
+
+fn defaults_table() -> Result<Table, ConfigError> {
+    let to_table = |field: &str, v: Result<Value, toml::ser::Error>| match v {
+        Ok(Value::Table(t)) => Ok(t),
+        Ok(_) => Err(ConfigError::invalid(field, "los defaults no son una tabla")),
+        Err(e) => Err(ConfigError::invalid(field, e.to_string())),
+    };
+    let mut doc = to_table("", Value::try_from(AppCfg::default()))?;
+    let delay = to_table(DELAY_SECTION, Value::try_from(ODDelayCfg::default()))?;
+    doc.insert(DELAY_SECTION.into(), Value::Table(delay));
+    Ok(doc)
+}
+
+fn read_file(path: &Path) -> Result<Table, ConfigError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|source| ConfigError::Io { path: path.to_path_buf(), source })?;
+    let parse_err = |msg: String| ConfigError::Parse { path: path.to_path_buf(), msg };
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str::<Table>(&text).map_err(|e| parse_err(e.to_string())),
+        Some("yaml" | "yml") => match serde_yaml::from_str::<Value>(&text) {
+            Ok(Value::Table(t)) => Ok(t),
+            Ok(_) => Err(parse_err("el documento raíz debe ser un mapa".into())),
+            Err(e) => Err(parse_err(e.to_string())),
+        },
+        _ => Err(ConfigError::Format { path: path.to_path_buf() }),
+    }
+}
+
+/// Campo de la configuración: con default, o declarado en `AppCfg` / sus secciones aunque
+/// sea opcional (los `None` no aparecen en los defaults)
+fn known_key(defaults: &Table, key: &str) -> bool {
+    if lookup(defaults, key).is_some() {
+        return true;
+    }
+    let (section, field) = key.split_once('.').unwrap_or(("", key));
+    let fields = match section {
+        "" => field_names::<AppCfg>(),
+        DELAY_SECTION => field_names::<ODDelayCfg>(),
+        "od_s3" => field_names::<OdS3Cfg>(),
+        "od_columns" => field_names::<OdColumns>(),
+        "fetch" => field_names::<FetchCfg>(),
+        _ => &[],
+    };
+    fields.contains(&field)
+}
+
+/// Nombres de campo de un struct `Deserialize` (los que pasa a `deserialize_struct`)
+fn field_names<T: DeserializeOwned>() -> &'static [&'static str] {
+    struct Probe(Option<&'static [&'static str]>);
+
+    impl<'de> serde::Deserializer<'de> for &mut Probe {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: serde::de::Visitor<'de>>(self, _v: V) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("solo structs"))
+        }
+
+        fn deserialize_struct<V: serde::de::Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _v: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0 = Some(fields);
+            Err(serde::de::Error::custom("solo los nombres"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+            unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut probe = Probe(None);
+    let _ = T::deserialize(&mut probe);
+    probe.0.unwrap_or(&[])
+}
+
+/// Variables destino de referencias `env:` (texto `"env:VAR"` o tabla `{ env = "VAR" }`)
+fn env_secret_targets(v: &Value) -> Vec<String> {
+    match v {
+        Value::String(s) => s.strip_prefix("env:").map(|var| vec![var.to_string()]).unwrap_or_default(),
+        Value::Table(t) => match t.get("env") {
+            Some(Value::String(var)) if t.len() == 1 => vec![var.clone()],
+            _ => t.values().flat_map(env_secret_targets).collect(),
+        },
+        Value::Array(a) => a.iter().flat_map(env_secret_targets).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Fusión recursiva: las tablas se combinan, el resto de valores se reemplaza.
+fn merge(base: &mut Table, over: Table) {
+    for (k, v) in over {
+        match (base.get_mut(&k), v) {
+            (Some(Value::Table(b)), Value::Table(o)) => merge(b, o),
+            (_, v) => {
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+fn lookup<'a>(doc: &'a Table, key: &str) -> Option<&'a Value> {
+    let mut parts = key.split('.');
+    let mut cur = doc.get(parts.next()?)?;
+    for p in parts {
+        cur = cur.as_table()?.get(p)?;
+    }
+    Some(cur)
+}
+
+fn set_path(doc: &mut Table, key: &str, value: Value) {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().unwrap_or(key);
+    let mut cur = doc;
+    for p in parts {
+        let entry = cur
+            .entry(p.to_string())
+            .or_insert_with(|| Value::Table(Table::new()));
+        if !entry.is_table() {
+            *entry = Value::Table(Table::new());
+        }
+        cur = entry.as_table_mut().expect("tabla recién creada");
+    }
+    cur.insert(last.to_string(), value);
+}
+
+/// Convierte un valor en texto (ENV / `--set`) al tipo del default del mismo campo.
+fn set_raw(doc: &mut Table, defaults: &Table, key: &str, raw: &str) -> Result<(), ConfigError> {
+    let bad = |what: &str| ConfigError::invalid(key, format!("se esperaba {what}, llegó {raw:?}"));
+    let v = match lookup(defaults, key) {
+        Some(Value::Integer(_)) => Value::Integer(raw.trim().parse().map_err(|_| bad("un entero"))?),
+        Some(Value::Float(_)) => Value::Float(raw.trim().parse().map_err(|_| bad("un número"))?),
+        Some(Value::Boolean(_)) => Value::Boolean(raw.trim().parse().map_err(|_| bad("true/false"))?),
+        Some(Value::Array(_) | Value::Table(_)) => toml::from_str::<Table>(&format!("v = {raw}"))
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+            .ok_or_else(|| bad("un valor TOML"))?,
+        // Strings y campos opcionales sin default
+        _ => Value::String(raw.to_string()),
+    };
+    set_path(doc, key, v);
+    Ok(())
+}
+
+fn deserialize<T: DeserializeOwned>(v: Value, prefix: &str) -> Result<T, ConfigError> {
+    serde_path_to_error::deserialize(v).map_err(|e| {
+        let path = e.path().to_string();
+        let field = match (prefix.is_empty(), path.as_str()) {
+            (true, p) => p.to_string(),
+            (false, ".") => prefix.to_string(),
+            (false, p) => format!("{prefix}.{p}"),
+        };
+        ConfigError::invalid(field, e.into_inner().to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn invalid_field(r: Result<Settings, ConfigError>) -> String {
+        match r {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("se esperaba ConfigError::Invalid, llegó {other:?}"),
+        }
+    }
+
+    #[test]
+    fn layers_file_env_cli() {
+        let path = std::env::temp_dir().join(format!("madgrid_cfg_{}.toml", std::process::id()));
+        std::fs::write(&path, "h3_res = 8\nt_od_s = 60\n[delay]\nbpr_a = 0.3\nbpr_b = 5\n").unwrap();
+
+        let cli = Cli {
+            config: Some(path.clone()),
+            t_od_s: Some(30),
+            set: vec!["delay.vc_cap=3.5".into()],
+            ..Default::default()
+        };
+        let s = load_from(cli, env(&[("MADGRID_H3_RES", "9"), ("MADGRID_DELAY__BPR_A", "0.25")])).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(s.app.h3_res, 9);
+        assert_eq!(s.app.t_od_s, 30);
+        assert_eq!(s.delay.res, 9);
+        assert_eq!(s.delay.bpr_a, 0.25);
+        assert_eq!(s.delay.bpr_b, 5.0);
+        assert_eq!(s.delay.vc_cap, 3.5);
+    }
+
+    #[test]
+    fn env_ignores_unknown_keys() {
+        let e = env(&[("MADGRID_FOO", "1"), ("MADGRID_DELAY__NOPE", "2"), ("MADGRID_STATE_DIR", "/var/lib/madgrid")]);
+        let s = load_from(Cli::default(), e).unwrap();
+        assert_eq!(s.app.state_dir.as_deref(), Some("/var/lib/madgrid"));
+    }
+
+    #[test]
+    fn admin_token_example_from_readme() {
+        let path = std::env::temp_dir().join(format!("madgrid_admin_{}.toml", std::process::id()));
+        std::fs::write(&path, "admin_token = \"env:MADGRID_ADMIN_TOKEN\"\n").unwrap();
+        let cli = Cli { config: Some(path.clone()), ..Default::default() };
+        let s = load_from(cli, env(&[("MADGRID_ADMIN_TOKEN", "s3cr3t")])).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(s.app.admin_token, Some(SecretSource::Env("MADGRID_ADMIN_TOKEN".into())));
+    }
+
+    #[test]
+    fn regions_inherit_and_override() {
+        let path = std::env::temp_dir().join(format!("madgrid_regions_{}.toml", std::process::id()));
+        let text = r#"
+            h3_res = 7
+            [delay]
+            bpr_a = 0.2
+            [[regions]]
+            id = "logrono"
+            od_url = "http://localhost/logrono.csv"
+            [[regions]]
+            id = "madrid"
+            od_url = "http://localhost/madrid.csv"
+            h3_res = 8
+            [regions.delay]
+            bpr_b = 3.0
+        "#;
+        std::fs::write(&path, text).unwrap();
+        let cli = Cli { config: Some(path.clone()), ..Default::default() };
+        let s = load_from(cli, Vec::new()).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(s.regions.len(), 2);
+        let (lg, md) = (&s.regions[0], &s.regions[1]);
+        assert_eq!((lg.delay.res, lg.delay.bpr_a, lg.delay.bpr_b), (7, 0.2, 4.0));
+        assert_eq!((md.delay.res, md.delay.bpr_a, md.delay.bpr_b), (8, 0.2, 3.0));
+        assert_eq!(md.roadmap_csv, None);
+    }
+
+    #[test]
+    fn reports_invalid_fields() {
+        let f = invalid_field(load_from(Cli::default(), env(&[("MADGRID_H3_RES", "16")])));
+        assert_eq!(f, "h3_res");
+        let f = invalid_field(load_from(Cli::default(), env(&[("MADGRID_MAX_CONCURRENT", "0")])));
+        assert_eq!(f, "max_concurrent");
+        let f = invalid_field(load_from(Cli::default(), env(&[("MADGRID_DELAY__BPR_B", "abc")])));
+        assert_eq!(f, "delay.bpr_b");
+        let f = invalid_field(load_from(Cli::default(), env(&[("MADGRID_DELAY__CAPACITY_PERCENTILE", "1.5")])));
+        assert_eq!(f, "delay.capacity_percentile");
+    }
+}
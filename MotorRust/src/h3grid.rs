@@ -7,8 +7,8 @@ use chrono::{NaiveDate, SecondsFormat, Utc};
 use futures::{stream, StreamExt};
 use geojson::GeoJson;
 use h3o::{CellIndex, LatLng, Resolution};
-use serde_json::json;
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
 use std::time::Duration;
 use tracing::{debug, info, warn};
@@ -17,6 +17,8 @@ use std::io::{BufRead, BufReader};
 
 
 use crate::models::h3types::*;
+use crate::models::types::{CellChange, DelayStats, Kpis};
+use crate::secrets::Secret;
 
 // ===============================
 // Configuracion y tipos de dominio
@@ -33,13 +35,13 @@ impl Default for DelayCfg {
             max_concurrent_calls: 16,
             truck_factor: 1.4,
             car_factor: 1.0,
-            show_eps: 0.02,
             bpr_a: 0.15,              // intensidad de congestión
             bpr_b: 4.0,               // curvatura
             truck_gamma: 0.4,         // sensibilidad a camiones (0.2–0.6 típico)
             capacity_percentile: 0.9, // percentil para estimar c (0.85–0.95 habitual)
             capacity_floor: 10.0,     // suelo para evitar c muy bajo (ajústalo a tu escala)
             vc_cap: 2.0,              // tope para (v/c) antes de elevar a b (numericamente estable)
+            through_weight: 0.0,      // tráfico de paso desactivado
         }
     }
 }
@@ -51,6 +53,10 @@ impl H3Metrics {
             trips_total: 0.0,
             trips_trucks: 0.0,
             trips_cars: 0.0,
+            trips_out: 0.0,
+            trips_in: 0.0,
+            trips_intra: 0.0,
+            trips_through: 0.0,
             conf_sum: 0.0,
             conf_weight: 0.0,
             delay_orange: 1.0,
@@ -60,6 +66,19 @@ impl H3Metrics {
             vol_norm: 0.0,
         }
     }
+    /// Suma los volúmenes y la confianza de `o` escalados por `k`
+    pub fn add_scaled(&mut self, o: &H3Metrics, k: f32) {
+        self.trips_total += o.trips_total * k;
+        self.trips_trucks += o.trips_trucks * k;
+        self.trips_cars += o.trips_cars * k;
+        self.trips_out += o.trips_out * k;
+        self.trips_in += o.trips_in * k;
+        self.trips_intra += o.trips_intra * k;
+        self.trips_through += o.trips_through * k;
+        self.conf_sum += o.conf_sum * k;
+        self.conf_weight += o.conf_weight * k;
+    }
+
     pub fn conf_cell(&self) -> f32 {
         if self.conf_weight > 0.0 {
             (self.conf_sum / self.conf_weight).clamp(0.0, 1.0)
@@ -67,6 +86,58 @@ impl H3Metrics {
             1.0
         }
     }
+
+    /// Carga que ve el BPR: viajes propios más el tráfico de paso ponderado
+    pub fn load(&self, cfg: &DelayCfg) -> f32 {
+        self.trips_total + cfg.through_weight * self.trips_through
+    }
+
+    /// Métricas reconstruidas de una fila histórica (para repintar un día pasado)
+    pub fn from_row(r: &H3DailyRow) -> Self {
+        Self {
+            cell: r.h3,
+            trips_total: r.trips_total,
+            trips_trucks: r.trips_trucks,
+            trips_cars: r.trips_cars,
+            trips_out: r.trips_out,
+            trips_in: r.trips_in,
+            trips_intra: r.trips_intra,
+            trips_through: r.trips_through,
+            conf_sum: r.conf_cell,
+            conf_weight: 1.0,
+            delay_orange: r.delay_orange,
+            delay_tomtom: r.delay_tomtom,
+            delay_final: r.delay_final,
+            truck_share: r.truck_share,
+            vol_norm: r.vol_norm,
+        }
+    }
+}
+
+impl H3DailyRow {
+    /// `res` es la de la celda (las hijas de hotspot van a `cfg.res + 1`)
+    pub fn from_metrics(date: NaiveDate, snapshot: u64, m: &H3Metrics) -> Self {
+        Self {
+            date,
+            h3: m.cell,
+            res: u8::from(m.cell.resolution()),
+            snapshot,
+            hour: None,
+            trips_total: m.trips_total,
+            trips_trucks: m.trips_trucks,
+            trips_cars: m.trips_cars,
+            trips_out: m.trips_out,
+            trips_in: m.trips_in,
+            trips_intra: m.trips_intra,
+            trips_through: m.trips_through,
+            truck_share: m.truck_share,
+            vol_norm: m.vol_norm,
+            conf_cell: m.conf_cell(),
+            delay_orange: m.delay_orange,
+            delay_tomtom: m.delay_tomtom,
+            delay_final: m.delay_final,
+        }
+    }
 }
 
 
@@ -80,7 +151,7 @@ pub trait TrafficProvider: Send + Sync {
 }
 
 impl TomTomClient {
-    pub fn new(api_key: impl Into<String>, road_map: Option<HashMap<CellIndex, RoadCell>>) -> Self {
+    pub fn new(api_key: Secret, road_map: Option<HashMap<CellIndex, RoadCell>>) -> Self {
         Self {
             http: reqwest::Client::builder()
                 .gzip(true)
@@ -89,7 +160,7 @@ impl TomTomClient {
                 .timeout(Duration::from_secs(8))
                 .build()
                 .expect("reqwest::Client"),
-            api_key: api_key.into(),
+            api_key,
             base_url_absolute: "https://api.tomtom.com/traffic/services/4/flowSegmentData/absolute/10/json".to_string(),
             timeout: Duration::from_secs(8),
             road_map, 
@@ -126,7 +197,7 @@ impl TrafficProvider for TomTomClient {
             &[
                 ("point", format!("{lat},{lon}")),
                 ("unit", "kmph".to_string()),
-                ("key", self.api_key.clone()),
+                ("key", self.api_key.expose().to_string()),
             ],
         )?;
 
@@ -136,6 +207,8 @@ impl TrafficProvider for TomTomClient {
             .timeout(self.timeout)
             .send()
             .await
+            // sin URL: la query lleva la clave
+            .map_err(|e| e.without_url())
             .context("TomTom request failed")?;
 
         if resp.status().is_success() {
@@ -155,8 +228,8 @@ impl TrafficProvider for TomTomClient {
         } else if resp.status().as_u16() == 404 {
             Ok(None)
         } else {
-            warn!("TomTom non-success: {}", resp.status());
-            Ok(None)
+            // Cuenta como fallo en `/status`; el llamador avisa y sigue con Orange
+            anyhow::bail!("TomTom non-success: {}", resp.status())
         }
     }
 }
@@ -202,15 +275,34 @@ impl HistorySink for JsonlSink {
     }
 }
 
+/// Reparte cada lote entre varios sinks (Orion + JSONL local, etc.).
+/// Se intenta en todos y un fallo solo se avisa (su salud sale en `/status` vía `TrackedSink`):
+/// abortar el cálculo haría que el reintento duplicase las filas en los sinks que sí escribieron.
+pub struct FanoutSink<'a> {
+    pub sinks: Vec<&'a dyn HistorySink>,
+}
+
+#[async_trait]
+impl HistorySink for FanoutSink<'_> {
+    async fn persist(&self, rows: &[H3DailyRow]) -> anyhow::Result<()> {
+        for s in &self.sinks {
+            if let Err(e) = s.persist(rows).await {
+                warn!("sink histórico: {e:#}");
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct OrionLdSink {
     pub base_url: String,
     pub tenant: Option<String>,
-    pub token: Option<String>,
+    pub token: Option<Secret>,
     http: reqwest::Client,
 }
 
 impl OrionLdSink {
-    pub fn new(base_url: impl Into<String>, tenant: Option<String>, token: Option<String>) -> Self {
+    pub fn new(base_url: impl Into<String>, tenant: Option<String>, token: Option<Secret>) -> Self {
         Self {
             base_url: base_url.into(),
             tenant,
@@ -223,23 +315,36 @@ impl OrionLdSink {
         let entities: Vec<serde_json::Value> = rows
             .iter()
             .map(|r| {
-                let id = format!("urn:ngsi-ld:H3Delay:{}:{}", r.date, r.h3);
-                json!({
+                let id = match r.hour {
+                    Some(h) => format!("urn:ngsi-ld:H3Delay:{}:{}:h{h:02}", r.date, r.h3),
+                    None => format!("urn:ngsi-ld:H3Delay:{}:{}", r.date, r.h3),
+                };
+                let mut e = json!({
                   "id": id,
                   "type": "H3Delay",
                   "date": { "type":"Property", "value": r.date.to_string() },
                   "h3": { "type":"Property", "value": r.h3.to_string() },
                   "res": { "type":"Property", "value": r.res },
+                  "snapshot": { "type":"Property", "value": r.snapshot },
                   "tripsTotal": { "type":"Property", "value": r.trips_total },
                   "tripsTrucks": { "type":"Property", "value": r.trips_trucks },
                   "tripsCars": { "type":"Property", "value": r.trips_cars },
+                  "tripsOut": { "type":"Property", "value": r.trips_out },
+                  "tripsIn": { "type":"Property", "value": r.trips_in },
+                  "tripsIntra": { "type":"Property", "value": r.trips_intra },
+                  "tripsThrough": { "type":"Property", "value": r.trips_through },
                   "truckShare": { "type":"Property", "value": r.truck_share },
                   "volNorm": { "type":"Property", "value": r.vol_norm },
                   "conf": { "type":"Property", "value": r.conf_cell },
                   "delayOrange": { "type":"Property", "value": r.delay_orange },
                   "delayTomTom": { "type":"Property", "value": r.delay_tomtom },
                   "delayFinal": { "type":"Property", "value": r.delay_final },
-                })
+                });
+                // Orion rechaza propiedades con valor null: las filas diarias van sin `hour`
+                if let Some(h) = r.hour {
+                    e["hour"] = json!({ "type":"Property", "value": h });
+                }
+                e
             })
             .collect();
 
@@ -260,7 +365,7 @@ impl HistorySink for OrionLdSink {
             req = req.header("NGSILD-Tenant", t);
         }
         if let Some(tok) = &self.token {
-            req = req.bearer_auth(tok);
+            req = req.bearer_auth(tok.expose());
         }
 
         let resp = req.json(&payload).send().await?;
@@ -280,7 +385,7 @@ impl HistorySink for OrionLdSink {
 #[inline]
 fn clamp(x: f32, a: f32, b: f32) -> f32 { x.max(a).min(b) }
 
-fn color_from_norm(x: f32) -> &'static str {
+pub(crate) fn color_from_norm(x: f32) -> &'static str {
     const R: [&str; 11] = [
         "#e9f7ef","#d4f2e3","#bfeacc","#a9e3b6","#fff3b0",
         "#ffe08a","#ffc266","#ff9f58","#ff7a55","#f5544f","#d73a49"
@@ -345,43 +450,69 @@ pub fn load_roadmap_csv(path: &str) -> Result<HashMap<CellIndex, RoadCell>> {
 // Núcleo: agregacion O/D y delay
 // ===============================
 
-pub fn aggregate_od_to_h3(records: &[ODRecord], cfg: &DelayCfg) -> Result<HashMap<CellIndex, H3Metrics>> {
-    let mut map: HashMap<CellIndex, H3Metrics> = HashMap::new();
-    let res = Resolution::try_from(cfg.res)?;
-
-    for r in records {
-        let o = CellIndex::from_str(&r.origin_h3).context("origin_h3 inválido")?;
-        let d = CellIndex::from_str(&r.dest_h3).context("dest_h3 inválido")?;
+/// Celdas máximas de un camino O/D para repartir tráfico de paso (más largo se ignora)
+const MAX_THROUGH_PATH: i32 = 200;
 
-        if o.resolution() != res || d.resolution() != res {
-            return Err(anyhow!("OD record con resolución distinta a cfg.res"));
-        }
+/// Suma cada flujo en sus celdas de origen y destino (ya normalizadas con `ingest::normalize_od`).
+/// Un viaje dentro de la misma celda cuenta una vez (`trips_intra`); el resto se separa en
+/// `trips_out` (origen) y `trips_in` (destino). Con `through_weight > 0`, las celdas del camino
+/// H3 entre origen y destino suman el volumen en `trips_through`.
+pub fn aggregate_od_to_h3(flows: &[OdFlow], cfg: &DelayCfg) -> HashMap<CellIndex, H3Metrics> {
+    let mut map: HashMap<CellIndex, H3Metrics> = HashMap::new();
 
+    for r in flows {
         let vol = r.n_trucks * cfg.truck_factor + r.n_cars * cfg.car_factor;
         let conf = r.conf.unwrap_or(1.0).clamp(0.0, 1.0);
         let w = vol.max(1.0);
 
-        // Origen
-        {
-            let e = map.entry(o).or_insert_with(|| H3Metrics::new(o));
-            e.trips_total += vol;
-            e.trips_trucks += r.n_trucks;
-            e.trips_cars += r.n_cars;
-            e.conf_sum += conf * w;
-            e.conf_weight += w;
+        // Parte de cada celda en el origen (a) y en el destino (b); a·b es la parte intra-celda
+        let mut ends: HashMap<CellIndex, (f32, f32)> = HashMap::new();
+        for &(c, k) in &r.origin {
+            ends.entry(c).or_default().0 += k;
+        }
+        for &(c, k) in &r.dest {
+            ends.entry(c).or_default().1 += k;
+        }
+        for (c, (a, b)) in ends {
+            let intra = a * b;
+            let k = a + b - intra;
+            let e = map.entry(c).or_insert_with(|| H3Metrics::new(c));
+            e.trips_out += vol * (a - intra);
+            e.trips_in += vol * (b - intra);
+            e.trips_intra += vol * intra;
+            e.trips_total += vol * k;
+            e.trips_trucks += r.n_trucks * k;
+            e.trips_cars += r.n_cars * k;
+            e.conf_sum += conf * w * k;
+            e.conf_weight += w * k;
         }
-        // Destino
-        {
-            let e = map.entry(d).or_insert_with(|| H3Metrics::new(d));
-            e.trips_total += vol;
-            e.trips_trucks += r.n_trucks;
-            e.trips_cars += r.n_cars;
-            e.conf_sum += conf * w;
-            e.conf_weight += w;
+
+        if cfg.through_weight > 0.0 {
+            add_through(&mut map, r, vol);
         }
     }
 
-    Ok(map)
+    map
+}
+
+/// Reparte `vol` en las celdas intermedias del camino entre la celda principal del origen y
+/// la del destino. Caminos imposibles (pentágonos, caras distintas) o muy largos se ignoran.
+fn add_through(map: &mut HashMap<CellIndex, H3Metrics>, r: &OdFlow, vol: f32) {
+    let main = |parts: &[(CellIndex, f32)]| {
+        parts.iter().max_by(|x, y| x.1.partial_cmp(&y.1).unwrap_or(std::cmp::Ordering::Equal)).map(|p| p.0)
+    };
+    let (Some(o), Some(d)) = (main(&r.origin), main(&r.dest)) else { return };
+    if o == d || !o.grid_distance(d).is_ok_and(|n| n <= MAX_THROUGH_PATH) {
+        return;
+    }
+    let Ok(path) = o.grid_path_cells(d) else { return };
+    let Ok(path) = path.collect::<Result<Vec<_>, _>>() else {
+        debug!("sin camino H3 entre {o} y {d}");
+        return;
+    };
+    for c in &path[1..path.len() - 1] {
+        map.entry(*c).or_insert_with(|| H3Metrics::new(*c)).trips_through += vol;
+    }
 }
 
 
@@ -389,12 +520,20 @@ pub fn aggregate_od_to_h3(records: &[ODRecord], cfg: &DelayCfg) -> Result<HashMa
 // Calculo delay orange
 // ===============================
 
-pub fn compute_delay_orange(metrics: &mut HashMap<CellIndex, H3Metrics>, cfg: &DelayCfg) {
+/// Delay Orange del mapa diario; devuelve la capacidad y el volumen medio usados (para las horas)
+pub fn compute_delay_orange(metrics: &mut HashMap<CellIndex, H3Metrics>, cfg: &DelayCfg) -> (f32, f32) {
+    let (c, mean_vol) = capacity_stats(metrics, cfg);
+    apply_delay_orange(metrics, cfg, c, mean_vol);
+    (c, mean_vol)
+}
+
+/// Capacidad (percentil de volúmenes) y volumen medio por celda del mapa
+fn capacity_stats(metrics: &HashMap<CellIndex, H3Metrics>, cfg: &DelayCfg) -> (f32, f32) {
     let eps = 1e-6_f32;
 
     // --- 1) Estadisticos base por ciudad/diia ---
     //   a) vector de volúmenes por celda (trips_total ya pondera trucks según cfg.*_factor)
-    let mut vols: Vec<f32> = metrics.values().map(|m| m.trips_total.max(0.0)).collect();
+    let mut vols: Vec<f32> = metrics.values().map(|m| m.load(cfg).max(0.0)).collect();
     let n = vols.len().max(1) as f32;
 
     // media para vol_norm (puro display/colores no afecta delay)
@@ -412,19 +551,27 @@ pub fn compute_delay_orange(metrics: &mut HashMap<CellIndex, H3Metrics>, cfg: &D
         let perc = vols.get(idx).copied().unwrap_or(mean_vol);
         perc.max(cfg.capacity_floor).max(eps)
     };
+    (c, mean_vol)
+}
+
+/// BPR-like por celda con capacidad `c` y volumen medio `mean_vol` dados
+fn apply_delay_orange(metrics: &mut HashMap<CellIndex, H3Metrics>, cfg: &DelayCfg, c: f32, mean_vol: f32) {
+    let eps = 1e-6_f32;
 
     // --- 2) Calculo por celda ---
     for m in metrics.values_mut() {
         // señales descriptivas
         let total  = m.trips_total.max(eps);
         let trucks = m.trips_trucks.max(0.0);
+        // carga = viajes propios + tráfico de paso ponderado (igual a total si through_weight = 0)
+        let load   = m.load(cfg).max(eps);
 
         m.truck_share = (trucks / total).clamp(0.0, 1.0);
-        m.vol_norm    = (total / mean_vol).clamp(0.0, 20.0);
+        m.vol_norm    = (load / mean_vol).clamp(0.0, 20.0);
 
         // --- 3) BPR-like ---
         // v/c acotado para estabilidad numerica
-        let vc = (total / c).clamp(0.0, cfg.vc_cap);
+        let vc = (load / c).clamp(0.0, cfg.vc_cap);
 
         // factor de mezcla por camiones (penaliza capacidad efectiva)
         let hv_factor = 1.0 + cfg.truck_gamma * m.truck_share;
@@ -440,6 +587,44 @@ pub fn compute_delay_orange(metrics: &mut HashMap<CellIndex, H3Metrics>, cfg: &D
     }
 }
 
+// ===============================
+// Perfiles horarios
+// ===============================
+
+/// Reparto horario por defecto del tráfico de un día laborable urbano (en %, suma 100).
+/// Se usa cuando el O/D solo trae totales diarios.
+pub const DAILY_PROFILE: [f32; 24] = [
+    0.8, 0.5, 0.4, 0.4, 0.6, 1.5, 3.5, 6.0, 7.6, 6.0, 5.5, 5.5,
+    6.2, 6.4, 5.8, 5.8, 6.2, 7.6, 7.4, 5.8, 4.2, 3.0, 2.0, 1.3,
+];
+
+/// Métricas por (celda, hora). Los flujos con hora van a su franja; los diarios se reparten
+/// con `DAILY_PROFILE`. La capacidad horaria es la diaria por el peso de la hora punta, así que
+/// en hora punta el v/c coincide con el del mapa diario y fuera de ella baja.
+pub fn compute_hours(
+    od: &[OdFlow],
+    day_capacity: f32,
+    day_mean_vol: f32,
+    cfg: &DelayCfg,
+) -> BTreeMap<u8, HashMap<CellIndex, H3Metrics>> {
+    let peak = DAILY_PROFILE.iter().copied().fold(0.0_f32, f32::max) / 100.0;
+    let daily: Vec<OdFlow> = od.iter().filter(|f| f.hour.is_none()).cloned().collect();
+    let daily_map = aggregate_od_to_h3(&daily, cfg);
+
+    let mut hours = BTreeMap::new();
+    for (h, pct) in DAILY_PROFILE.iter().enumerate() {
+        let share = pct / 100.0;
+        let hourly: Vec<OdFlow> = od.iter().filter(|f| f.hour == Some(h as u8)).cloned().collect();
+        let mut map = aggregate_od_to_h3(&hourly, cfg);
+        for (c, m) in &daily_map {
+            map.entry(*c).or_insert_with(|| H3Metrics::new(*c)).add_scaled(m, share);
+        }
+        apply_delay_orange(&mut map, cfg, day_capacity * peak, day_mean_vol / 24.0);
+        hours.insert(h as u8, map);
+    }
+    hours
+}
+
 // ===============================
 // Calculo delay mixto con proveedor externo
 // ===============================
@@ -606,15 +791,172 @@ pub async fn subdivide_hotspots_with_provider(
     Ok(())
 }
 
+// ===============================
+// Consulta por celda / punto
+// ===============================
+
+/// Cómo se resolvió una consulta de celda
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CellMatch {
+    /// La celda pedida (o la celda publicada que contiene el punto: a `base_res` o hija de hotspot)
+    Exact,
+    /// Ancestro más cercano publicado
+    Parent,
+}
+
+/// Celda publicada que cubre `cell`: ella misma o su ancestro más cercano hasta `base_res`.
+pub fn resolve_cell(
+    metrics: &HashMap<CellIndex, H3Metrics>,
+    cell: CellIndex,
+    base_res: Resolution,
+) -> Option<(&H3Metrics, CellMatch)> {
+    if let Some(m) = metrics.get(&cell) {
+        return Some((m, CellMatch::Exact));
+    }
+    let mut res = cell.resolution();
+    while res > base_res {
+        res = res.pred()?;
+        if let Some(m) = cell.parent(res).and_then(|p| metrics.get(&p)) {
+            return Some((m, CellMatch::Parent));
+        }
+    }
+    None
+}
+
+/// Punto -> hija de hotspot (res+1) si el padre se subdividió; si no, la celda a `base_res`.
+/// En ambos casos es la celda publicada que contiene el punto: `Exact`.
+pub fn resolve_point(
+    metrics: &HashMap<CellIndex, H3Metrics>,
+    ll: LatLng,
+    base_res: Resolution,
+) -> Option<(&H3Metrics, CellMatch)> {
+    let fine = base_res.succ().unwrap_or(base_res);
+    [fine, base_res].into_iter().find_map(|r| metrics.get(&ll.to_cell(r))).map(|m| (m, CellMatch::Exact))
+}
+
+/// Diferencias entre dos snapshots: celdas nuevas, desaparecidas y con cambios.
+/// Una celda cambia si `delay_final` varía más de `threshold` o si cambia el uso de TomTom.
+pub fn diff_metrics(
+    old: &HashMap<CellIndex, H3Metrics>,
+    new: &HashMap<CellIndex, H3Metrics>,
+    threshold: f32,
+) -> (Vec<CellIndex>, Vec<CellIndex>, Vec<CellChange>) {
+    let mut added: Vec<CellIndex> = new.keys().filter(|c| !old.contains_key(c)).copied().collect();
+    let mut removed: Vec<CellIndex> = old.keys().filter(|c| !new.contains_key(c)).copied().collect();
+    let mut changed: Vec<CellChange> = new
+        .iter()
+        .filter_map(|(c, n)| {
+            let o = old.get(c)?;
+            let (tt_old, tt_new) = (o.delay_tomtom > 0.0, n.delay_tomtom > 0.0);
+            if (n.delay_final - o.delay_final).abs() <= threshold && tt_old == tt_new {
+                return None;
+            }
+            Some(CellChange {
+                h3: c.to_string(),
+                delay_old: o.delay_final,
+                delay_new: n.delay_final,
+                conf_old: o.conf_cell(),
+                conf_new: n.conf_cell(),
+                used_tomtom_old: tt_old,
+                used_tomtom_new: tt_new,
+            })
+        })
+        .collect();
+    added.sort_unstable();
+    removed.sort_unstable();
+    // Primero los cambios más grandes
+    changed.sort_by(|a, b| {
+        let (da, db) = ((a.delay_new - a.delay_old).abs(), (b.delay_new - b.delay_old).abs());
+        db.total_cmp(&da).then_with(|| a.h3.cmp(&b.h3))
+    });
+    (added, removed, changed)
+}
+
 // ===============================
 // Export:: GeojSON y rutina principal
 // ===============================
 
+/// Filtro de `/map/hex`, evaluado sobre las métricas retenidas del snapshot
+#[derive(Clone, Debug, Default)]
+pub struct HexFilter {
+    /// minLon, minLat, maxLon, maxLat (celdas que solapan el viewport)
+    pub bbox: Option<[f64; 4]>,
+    pub min_delay: Option<f32>,
+    pub used_tomtom: Option<bool>,
+    pub res: Option<u8>,
+    /// Solo hotspots o sus hijas subdivididas
+    pub hotspots_only: bool,
+    /// Oculta celdas con `delay_final <= 1 + eps`
+    pub show_eps: Option<f32>,
+}
+
+impl HexFilter {
+    pub fn is_empty(&self) -> bool {
+        self.bbox.is_none()
+            && self.min_delay.is_none()
+            && self.used_tomtom.is_none()
+            && self.res.is_none()
+            && !self.hotspots_only
+            && self.show_eps.is_none()
+    }
+
+    pub fn matches(&self, m: &H3Metrics, hotspots: &HashSet<CellIndex>, base_res: Resolution) -> bool {
+        let d = m.delay_final;
+        if self.show_eps.is_some_and(|eps| d <= 1.0 + eps) {
+            return false;
+        }
+        if self.min_delay.is_some_and(|min| d < min) {
+            return false;
+        }
+        if self.used_tomtom.is_some_and(|want| want != (m.delay_tomtom > 0.0)) {
+            return false;
+        }
+        if self.res.is_some_and(|r| r != u8::from(m.cell.resolution())) {
+            return false;
+        }
+        if self.hotspots_only {
+            let parent = m.cell.parent(base_res).unwrap_or(m.cell);
+            if !hotspots.contains(&m.cell) && !hotspots.contains(&parent) {
+                return false;
+            }
+        }
+        if let Some([min_lon, min_lat, max_lon, max_lat]) = self.bbox {
+            let verts = m.cell.boundary();
+            let (mut lo_lon, mut lo_lat, mut hi_lon, mut hi_lat) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+            for ll in verts.iter() {
+                lo_lon = lo_lon.min(ll.lng());
+                hi_lon = hi_lon.max(ll.lng());
+                lo_lat = lo_lat.min(ll.lat());
+                hi_lat = hi_lat.max(ll.lat());
+            }
+            if hi_lon < min_lon || lo_lon > max_lon || hi_lat < min_lat || lo_lat > max_lat {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 pub fn to_geojson(metrics: &HashMap<CellIndex, H3Metrics>, cfg: &DelayCfg) -> String {
+    to_geojson_filtered(metrics, cfg, &HexFilter::default(), &[])
+}
+
+/// GeoJSON solo con las celdas que pasan `filter`
+pub fn to_geojson_filtered(
+    metrics: &HashMap<CellIndex, H3Metrics>,
+    cfg: &DelayCfg,
+    filter: &HexFilter,
+    hotspots: &[CellIndex],
+) -> String {
+    let hotspots: HashSet<CellIndex> = hotspots.iter().copied().collect();
+    let base_res = Resolution::try_from(cfg.res).unwrap_or(Resolution::Seven);
     let mut features = Vec::new();
     for (c, m) in metrics {
+        if !filter.matches(m, &hotspots, base_res) {
+            continue;
+        }
         let d = m.delay_final;
-        //if d <= 1.0 + cfg.show_eps { continue; }
 
         let norm = ((d - 1.0) / (cfg.delay_max - 1.0)).clamp(0.0, 1.0);
         let col = color_from_norm(norm);
@@ -651,18 +993,156 @@ pub fn to_geojson(metrics: &HashMap<CellIndex, H3Metrics>, cfg: &DelayCfg) -> St
     GeoJson::from_json_value(gj).unwrap_or(GeoJson::FeatureCollection(Default::default())).to_string()
 }
 
+/// GeoJSON con solo las celdas que difieren entre `old` y `new` (`change`: added/removed/changed).
+/// Las propiedades `*_old` / `*_new` son `null` donde la celda no existe.
+pub fn diff_geojson(
+    old: &HashMap<CellIndex, H3Metrics>,
+    new: &HashMap<CellIndex, H3Metrics>,
+    threshold: f32,
+) -> serde_json::Value {
+    let (added, removed, changed) = diff_metrics(old, new, threshold);
+    let r2 = |v: f32| (f64::from(v) * 100.0).round() / 100.0;
+    let side = |m: Option<&H3Metrics>| match m {
+        Some(m) => (json!(r2(m.delay_final)), json!(r2(m.conf_cell())), json!(m.delay_tomtom > 0.0)),
+        None => (Value::Null, Value::Null, Value::Null),
+    };
+    let feature = |c: CellIndex, change: &str| {
+        let (d_old, c_old, t_old) = side(old.get(&c));
+        let (d_new, c_new, t_new) = side(new.get(&c));
+        json!({
+            "type": "Feature",
+            "geometry": { "type": "Polygon", "coordinates": [cell_polygon_coords(c)] },
+            "properties": {
+                "h3": c.to_string(),
+                "change": change,
+                "delay_old": d_old, "delay_new": d_new,
+                "conf_old": c_old, "conf_new": c_new,
+                "used_tomtom_old": t_old, "used_tomtom_new": t_new,
+            }
+        })
+    };
+
+    let mut features: Vec<Value> = Vec::with_capacity(added.len() + removed.len() + changed.len());
+    features.extend(changed.iter().filter_map(|ch| CellIndex::from_str(&ch.h3).ok()).map(|c| feature(c, "changed")));
+    features.extend(added.into_iter().map(|c| feature(c, "added")));
+    features.extend(removed.into_iter().map(|c| feature(c, "removed")));
+    json!({
+        "type": "FeatureCollection",
+        "name": "hex_delay_h3_diff",
+        "crs": { "type":"name","properties":{"name":"EPSG:4326"} },
+        "threshold": threshold,
+        "features": features
+    })
+}
+
+/// Filtros de `/od/flows`
+#[derive(Debug, Clone, Default)]
+pub struct FlowFilter {
+    /// Celda de origen (o un padre suyo)
+    pub origin: Option<CellIndex>,
+    /// Celda de destino (o un padre suyo)
+    pub dest: Option<CellIndex>,
+    /// Volumen mínimo del par (`n_trucks * truck_factor + n_cars * car_factor`)
+    pub min_volume: f32,
+    /// Pares devueltos, los de más volumen (0 = todos)
+    pub limit: usize,
+}
+
+/// `c` es `f` o una celda contenida en `f`
+fn within(c: CellIndex, f: CellIndex) -> bool {
+    c == f || c.parent(f.resolution()) == Some(f)
+}
+
+/// Líneas de deseo: un LineString entre centroides por par (origen, destino) de celdas, con
+/// los extremos repartidos ya sumados y las horas del día juntas. Ordenado por volumen.
+pub fn od_flows_geojson(flows: &[OdFlow], cfg: &DelayCfg, filter: &FlowFilter) -> Value {
+    #[derive(Default)]
+    struct Pair { trucks: f32, cars: f32, conf_sum: f32, conf_weight: f32 }
+
+    let mut pairs: HashMap<(CellIndex, CellIndex), Pair> = HashMap::new();
+    for f in flows {
+        let vol = f.n_trucks * cfg.truck_factor + f.n_cars * cfg.car_factor;
+        let conf = f.conf.unwrap_or(1.0).clamp(0.0, 1.0);
+        for &(o, ko) in &f.origin {
+            if filter.origin.is_some_and(|x| !within(o, x)) {
+                continue;
+            }
+            for &(d, kd) in &f.dest {
+                if filter.dest.is_some_and(|x| !within(d, x)) {
+                    continue;
+                }
+                let k = ko * kd;
+                let p = pairs.entry((o, d)).or_default();
+                p.trucks += f.n_trucks * k;
+                p.cars += f.n_cars * k;
+                p.conf_sum += conf * vol.max(1.0) * k;
+                p.conf_weight += vol.max(1.0) * k;
+            }
+        }
+    }
+
+    let mut rows: Vec<((CellIndex, CellIndex), Pair, f32)> = pairs
+        .into_iter()
+        .map(|(k, p)| {
+            let vol = p.trucks * cfg.truck_factor + p.cars * cfg.car_factor;
+            (k, p, vol)
+        })
+        .filter(|(_, _, vol)| *vol >= filter.min_volume)
+        .collect();
+    rows.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal).then(a.0.cmp(&b.0)));
+    let total = rows.len();
+    if filter.limit > 0 {
+        rows.truncate(filter.limit);
+    }
+
+    let r2 = |v: f32| (f64::from(v) * 100.0).round() / 100.0;
+    let centroid = |c: CellIndex| {
+        let ll = LatLng::from(c);
+        [ll.lng(), ll.lat()]
+    };
+    let features: Vec<Value> = rows
+        .into_iter()
+        .map(|((o, d), p, vol)| {
+            json!({
+                "type": "Feature",
+                "geometry": { "type": "LineString", "coordinates": [centroid(o), centroid(d)] },
+                "properties": {
+                    "origin": o.to_string(),
+                    "dest": d.to_string(),
+                    "intra": o == d,
+                    "n_trucks": r2(p.trucks),
+                    "n_cars": r2(p.cars),
+                    "volume": r2(vol),
+                    "conf": r2(if p.conf_weight > 0.0 { p.conf_sum / p.conf_weight } else { 0.0 }),
+                }
+            })
+        })
+        .collect();
+    json!({
+        "type": "FeatureCollection",
+        "name": "od_flows",
+        "crs": { "type":"name","properties":{"name":"EPSG:4326"} },
+        "pairs_total": total,
+        "features": features
+    })
+}
+
 pub async fn compute_day(
     date: NaiveDate,
-    od: &[ODRecord],
+    snapshot: u64,
+    od: &[OdFlow],
     cfg: &DelayCfg,
     traffic: Option<&dyn TrafficProvider>,
     sink: Option<&dyn HistorySink>,
-) -> Result<(HashMap<CellIndex, H3Metrics>, String)> {
+) -> Result<DayResult> {
     // 1) Agregacion
-    let mut map = aggregate_od_to_h3(od, cfg)?;
+    let aggregates = aggregate_od_to_h3(od, cfg);
+    let mut map = aggregates.clone();
 
-    // 2) Delay Orange
-    compute_delay_orange(&mut map, cfg);
+    // 2) Delay Orange (diario y por hora, con la misma capacidad de referencia)
+    let (capacity, mean_vol) = compute_delay_orange(&mut map, cfg);
+    let hours = compute_hours(od, capacity, mean_vol, cfg);
+    let hourly_input = od.iter().any(|f| f.hour.is_some());
 
     // 3) Enriquecimiento Traffic Provider
     if let Some(tp) = traffic {
@@ -679,31 +1159,129 @@ pub async fn compute_day(
         }
     }
 
-    // 4) Persistencia histórica
-    if let Some(s) = sink {
-        let rows: Vec<H3DailyRow> = map
-            .values()
-            .map(|m| H3DailyRow {
-                date,
-                h3: m.cell,
-                res: cfg.res,
-                trips_total: m.trips_total,
-                trips_trucks: m.trips_trucks,
-                trips_cars: m.trips_cars,
-                truck_share: m.truck_share,
-                vol_norm: m.vol_norm,
-                conf_cell: m.conf_cell(),
-                delay_orange: m.delay_orange,
-                delay_tomtom: m.delay_tomtom,
-                delay_final: m.delay_final,
-            })
-            .collect();
-        s.persist(&rows).await?;
+    // 4) GeoJSON
+    let gj = to_geojson(&map, cfg);
+    let day = DayResult { metrics: map, geojson: gj, hotspots, hours, hourly_input, aggregates, capacity, mean_vol };
+
+    // 5) Persistencia histórica
+    persist_day(&day, date, snapshot, sink).await?;
+    Ok(day)
+}
+
+/// Suma flujos nuevos (`fresh`) a un día ya calculado. Solo las celdas que tocan recalculan su
+/// delay, con la capacidad de referencia del cálculo completo, y solo ellas consultan TomTom; el
+/// resto conserva su resultado. Un hotspot tocado se vuelve a subdividir desde su agregado nuevo
+/// (sus hijas se recalculan); la lista de hotspots no cambia hasta el siguiente cálculo completo.
+/// `all` son todos los flujos del día, nuevos incluidos, para los mapas horarios. No persiste:
+/// eso queda para `persist_day`.
+pub async fn update_day(
+    prev: &DayResult,
+    fresh: &[OdFlow],
+    all: &[OdFlow],
+    cfg: &DelayCfg,
+    traffic: Option<&dyn TrafficProvider>,
+) -> Result<DayResult> {
+    // 1) Agregados acumulados
+    let delta = aggregate_od_to_h3(fresh, cfg);
+    let mut aggregates = prev.aggregates.clone();
+    for (c, m) in &delta {
+        aggregates.entry(*c).or_insert_with(|| H3Metrics::new(*c)).add_scaled(m, 1.0);
+    }
+
+    // 2) Delay de las celdas tocadas (Orange + TomTom)
+    let mut touched: HashMap<CellIndex, H3Metrics> = delta.keys().map(|c| (*c, aggregates[c].clone())).collect();
+    apply_delay_orange(&mut touched, cfg, prev.capacity, prev.mean_vol);
+    if let Some(tp) = traffic {
+        enrich_with_traffic_provider(&mut touched, cfg, tp).await?;
+    }
+    // Un hotspot subdividido se sirve por sus hijas: se rehacen desde el padre actualizado
+    let hotspots: HashSet<CellIndex> = prev.hotspots.iter().copied().collect();
+    let (mut split, plain): (HashMap<CellIndex, H3Metrics>, HashMap<CellIndex, H3Metrics>) =
+        touched.into_iter().partition(|(c, _)| hotspots.contains(c) && !prev.metrics.contains_key(c));
+    let mut map = prev.metrics.clone();
+    map.extend(plain);
+    let parents: Vec<CellIndex> = split.keys().copied().collect();
+    match traffic {
+        Some(tp) if !parents.is_empty() => {
+            subdivide_hotspots_with_provider(&mut split, cfg, &parents, tp).await?;
+            map.extend(split);
+        }
+        // Sin proveedor no se puede subdividir: las hijas conservan su último resultado
+        _ if !parents.is_empty() => warn!("No se puede recalcular subdivisiones sin proveedor externo"),
+        _ => {}
     }
+    debug!("{} celdas actualizadas con filas nuevas ({} hotspots subdivididos de nuevo)", delta.len(), parents.len());
 
-    // 5) GeoJSON
+    let hours = compute_hours(all, prev.capacity, prev.mean_vol, cfg);
+    let hourly_input = all.iter().any(|f| f.hour.is_some());
     let gj = to_geojson(&map, cfg);
-    Ok((map, gj))
+    Ok(DayResult {
+        metrics: map,
+        geojson: gj,
+        hotspots: prev.hotspots.clone(),
+        hours,
+        hourly_input,
+        aggregates,
+        capacity: prev.capacity,
+        mean_vol: prev.mean_vol,
+    })
+}
+
+/// Filas históricas del día completo y, si el O/D traía horas, de cada hora
+/// (el perfil por defecto se deriva y no se persiste)
+pub async fn persist_day(day: &DayResult, date: NaiveDate, snapshot: u64, sink: Option<&dyn HistorySink>) -> Result<()> {
+    let Some(s) = sink else { return Ok(()) };
+    let mut rows: Vec<H3DailyRow> = day.metrics.values().map(|m| H3DailyRow::from_metrics(date, snapshot, m)).collect();
+    if day.hourly_input {
+        for (h, hm) in &day.hours {
+            rows.extend(hm.values().map(|m| H3DailyRow { hour: Some(*h), ..H3DailyRow::from_metrics(date, snapshot, m) }));
+        }
+    }
+    s.persist(&rows).await
+}
+
+// ===============================
+// KPIs del snapshot
+// ===============================
+
+/// Percentil sobre un slice ya ordenado (mismo redondeo que `detect_hotspots`)
+fn percentile_sorted(sorted: &[f32], p: f32) -> f32 {
+    let idx = ((sorted.len().saturating_sub(1) as f32) * p).round() as usize;
+    sorted.get(idx).copied().unwrap_or(0.0)
+}
+
+/// KPIs a partir de las métricas retenidas. Timestamps, versión y duración los pone el llamador.
+pub fn build_kpis(day: &DayResult, od: &[OdFlow]) -> Kpis {
+    let n = day.metrics.len();
+    let mut cells_by_res = std::collections::BTreeMap::new();
+    for c in day.metrics.keys() {
+        *cells_by_res.entry(u8::from(c.resolution())).or_insert(0) += 1;
+    }
+
+    let mut delays: Vec<f32> = day.metrics.values().map(|m| m.delay_final).collect();
+    delays.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let nf = n.max(1) as f32;
+    let delay_final = DelayStats {
+        min: delays.first().copied().unwrap_or(0.0),
+        mean: delays.iter().sum::<f32>() / nf,
+        p50: percentile_sorted(&delays, 0.5),
+        p90: percentile_sorted(&delays, 0.9),
+        max: delays.last().copied().unwrap_or(0.0),
+    };
+
+    let tomtom = day.metrics.values().filter(|m| m.delay_tomtom > 0.0).count();
+    Kpis {
+        cells: n,
+        cells_by_res,
+        delay_final,
+        tomtom_share: tomtom as f32 / nf,
+        conf_mean: day.metrics.values().map(|m| m.conf_cell()).sum::<f32>() / nf,
+        hotspots: day.hotspots.len(),
+        trucks_total: od.iter().map(|r| r.n_trucks).sum(),
+        cars_total: od.iter().map(|r| r.n_cars).sum(),
+        geojson_bytes: day.geojson.len(),
+        ..Default::default()
+    }
 }
 
 // ===============================
@@ -713,6 +1291,7 @@ pub async fn compute_day(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::types::IngestReport;
     use chrono::NaiveDate;
 
     #[tokio::test]
@@ -727,15 +1306,300 @@ mod tests {
                 date: NaiveDate::from_ymd_opt(2025, 10, 27).unwrap(),
                 origin_h3: h.clone(),
                 dest_h3: h.clone(),
+                origin_lat: None,
+                origin_lon: None,
+                dest_lat: None,
+                dest_lon: None,
                 n_trucks: 120.0,
                 n_cars: 800.0,
                 conf: Some(0.8),
+                hour: None,
             }
         ];
-        let mut cfg = DelayCfg::default();
-        cfg.res = res;
-        let (_map, gj) = compute_day(od[0].date, &od, &cfg, None, None).await?;
-        assert!(gj.contains("FeatureCollection"));
+        let cfg = DelayCfg { res, ..Default::default() };
+        let mut report = IngestReport::default();
+        let od = crate::ingest::normalize_od(&od, Resolution::try_from(res).unwrap(), None, &mut report);
+        assert_eq!(report.accepted, 1);
+        let out = compute_day(od[0].date, 1, &od, &cfg, None, None).await?;
+        assert!(out.geojson.contains("FeatureCollection"));
+        Ok(())
+    }
+
+    /// TomTom solo responde para las hijas (res 8) de los hotspots
+    struct ChildrenOnly;
+
+    #[async_trait]
+    impl TrafficProvider for ChildrenOnly {
+        async fn delay_for_cell(&self, cell: CellIndex) -> Result<Option<(f32, f32)>> {
+            Ok((cell.resolution() == Resolution::Eight).then_some((1.5, 0.6)))
+        }
+    }
+
+    #[tokio::test]
+    async fn build_kpis_counts_tomtom_conf_and_hotspots() -> Result<()> {
+        let a = LatLng::new(42.4627, -2.44498).unwrap().to_cell(Resolution::Seven);
+        let b = LatLng::new(42.47, -2.30).unwrap().to_cell(Resolution::Seven);
+        let date = NaiveDate::from_ymd_opt(2025, 10, 28).unwrap();
+        let flow = |c: CellIndex, trucks: f32, cars: f32| OdFlow {
+            date,
+            origin: vec![(c, 1.0)],
+            dest: vec![(c, 1.0)],
+            n_trucks: trucks,
+            n_cars: cars,
+            conf: Some(0.9),
+            hour: None,
+        };
+        let od = [flow(a, 50.0, 2000.0), flow(b, 10.0, 100.0)];
+        let cfg = DelayCfg { capacity_floor: 1.0, ..Default::default() };
+        let day = compute_day(date, 1, &od, &cfg, Some(&ChildrenOnly), None).await?;
+        // `a` es hotspot: se sirve por sus 7 hijas, todas con TomTom; `b` queda sin proveedor
+        assert_eq!(day.hotspots, vec![a]);
+
+        let k = build_kpis(&day, &od);
+        assert_eq!((k.cells, k.hotspots), (8, 1));
+        assert_eq!((k.cells_by_res.get(&7), k.cells_by_res.get(&8)), (Some(&1), Some(&7)));
+        assert!((k.tomtom_share - 7.0 / 8.0).abs() < 1e-6);
+        assert!((k.conf_mean - (0.9 + 7.0 * 0.6) / 8.0).abs() < 1e-5);
+        assert_eq!((k.trucks_total, k.cars_total), (60.0, 2100.0));
+        assert!(k.delay_final.min <= k.delay_final.p50 && k.delay_final.p90 <= k.delay_final.max);
+        Ok(())
+    }
+
+    #[test]
+    fn hourly_maps_from_profile_and_od_hours() {
+        assert!((DAILY_PROFILE.iter().sum::<f32>() - 100.0).abs() < 1e-3);
+        let ll = LatLng::new(42.4627, -2.44498).unwrap();
+        let (a, b) = (ll.to_cell(Resolution::Seven), LatLng::new(42.47, -2.40).unwrap().to_cell(Resolution::Seven));
+        let flow = |c: CellIndex, cars: f32, hour: Option<u8>| OdFlow {
+            date: NaiveDate::from_ymd_opt(2025, 10, 28).unwrap(),
+            origin: vec![(c, 1.0)],
+            dest: vec![(c, 1.0)],
+            n_trucks: 0.0,
+            n_cars: cars,
+            conf: None,
+            hour,
+        };
+        let cfg = DelayCfg { capacity_floor: 1.0, ..Default::default() };
+
+        // Solo totales diarios: la hora punta reproduce el delay diario y la madrugada baja
+        let od = vec![flow(a, 2000.0, None), flow(b, 500.0, None)];
+        let mut day = aggregate_od_to_h3(&od, &cfg);
+        let (cap, mean) = compute_delay_orange(&mut day, &cfg);
+        let hours = compute_hours(&od, cap, mean, &cfg);
+        assert_eq!(hours.len(), 24);
+        assert!((hours[&8][&a].delay_orange - day[&a].delay_orange).abs() < 1e-4);
+        assert!(hours[&3][&a].delay_orange < hours[&8][&a].delay_orange);
+        assert!((hours[&3][&a].trips_total - day[&a].trips_total * 0.004).abs() < 1e-3);
+
+        // Con horas en el O/D cada flujo cae solo en su franja
+        let od = vec![flow(a, 300.0, Some(8)), flow(b, 100.0, Some(17))];
+        let hours = compute_hours(&od, cap, mean, &cfg);
+        assert!(hours[&8].contains_key(&a) && !hours[&8].contains_key(&b));
+        assert_eq!(hours[&17][&b].trips_cars, 100.0);
+        assert!(hours[&3].is_empty());
+    }
+
+    #[test]
+    fn aggregate_splits_out_in_intra_and_through() {
+        let a = LatLng::new(42.4627, -2.44498).unwrap().to_cell(Resolution::Seven);
+        let b = LatLng::new(42.47, -2.30).unwrap().to_cell(Resolution::Seven);
+        let flow = |o: CellIndex, d: CellIndex, cars: f32| OdFlow {
+            date: NaiveDate::from_ymd_opt(2025, 10, 28).unwrap(),
+            origin: vec![(o, 1.0)],
+            dest: vec![(d, 1.0)],
+            n_trucks: 0.0,
+            n_cars: cars,
+            conf: None,
+            hour: None,
+        };
+        let od = vec![flow(a, a, 50.0), flow(a, b, 100.0)];
+
+        // Sin tráfico de paso: el viaje intra-celda cuenta una vez y no se calculan caminos
+        let map = aggregate_od_to_h3(&od, &DelayCfg::default());
+        assert_eq!(map.len(), 2);
+        let ma = &map[&a];
+        assert_eq!((ma.trips_total, ma.trips_out, ma.trips_in, ma.trips_intra), (150.0, 100.0, 0.0, 50.0));
+        assert_eq!((map[&b].trips_total, map[&b].trips_in), (100.0, 100.0));
+
+        // Con tráfico de paso: las celdas intermedias reciben el volumen y suben de delay
+        let cfg = DelayCfg { through_weight: 0.5, capacity_floor: 1.0, ..Default::default() };
+        let mut map = aggregate_od_to_h3(&od, &cfg);
+        let mid: Vec<_> = map.values().filter(|m| m.trips_through > 0.0).map(|m| m.cell).collect();
+        assert_eq!(mid.len() as i32, a.grid_distance(b).unwrap() - 1);
+        assert!(mid.iter().all(|c| map[c].trips_through == 100.0 && map[c].trips_total == 0.0));
+        assert_eq!((map[&a].trips_through, map[&b].trips_through), (0.0, 0.0));
+        compute_delay_orange(&mut map, &cfg);
+        assert!(map[&mid[0]].delay_orange > cfg.delay_min);
+    }
+
+    #[tokio::test]
+    async fn update_day_touches_only_new_cells() -> Result<()> {
+        let a = LatLng::new(42.4627, -2.44498).unwrap().to_cell(Resolution::Seven);
+        let b = LatLng::new(42.47, -2.30).unwrap().to_cell(Resolution::Seven);
+        let date = NaiveDate::from_ymd_opt(2025, 10, 28).unwrap();
+        let flow = |c: CellIndex, cars: f32| OdFlow {
+            date,
+            origin: vec![(c, 1.0)],
+            dest: vec![(c, 1.0)],
+            n_trucks: 0.0,
+            n_cars: cars,
+            conf: Some(0.9),
+            hour: None,
+        };
+        let cfg = DelayCfg { capacity_floor: 1.0, ..Default::default() };
+        let mut prev = compute_day(date, 1, &[flow(a, 400.0), flow(b, 100.0)], &cfg, None, None).await?;
+        // Simula un resultado TomTom previo en `a`: debe sobrevivir a un lote que no la toca
+        prev.metrics.get_mut(&a).unwrap().delay_tomtom = 1.7;
+
+        let fresh = [flow(b, 300.0)];
+        let all = [flow(a, 400.0), flow(b, 100.0), flow(b, 300.0)];
+        let day = update_day(&prev, &fresh, &all, &cfg, None).await?;
+        assert_eq!(day.metrics[&a].delay_tomtom, 1.7);
+        assert_eq!((day.aggregates[&b].trips_cars, day.metrics[&b].trips_cars), (400.0, 400.0));
+        assert!(day.metrics[&b].delay_orange > prev.metrics[&b].delay_orange);
+        // Misma capacidad de referencia: `b` queda como `a` con el mismo volumen
+        assert!((day.metrics[&b].delay_orange - prev.metrics[&a].delay_orange).abs() < 1e-5);
+        assert_eq!(day.capacity, prev.capacity);
+
+        // Con proveedor `a` es hotspot y se sirve por sus hijas
+        let mut prev = compute_day(date, 1, &[flow(a, 400.0), flow(b, 100.0)], &cfg, Some(&ChildrenOnly), None).await?;
+        assert_eq!(prev.hotspots, vec![a]);
+        let children: Vec<CellIndex> = a.children(Resolution::Eight).collect();
+        for c in &children {
+            prev.metrics.get_mut(c).unwrap().delay_tomtom = 9.0;
+        }
+        // Un lote que no toca `a` conserva sus hijas
+        let day = update_day(&prev, &fresh, &all, &cfg, Some(&ChildrenOnly)).await?;
+        assert!(children.iter().all(|c| day.metrics[c].delay_tomtom == 9.0));
+        // Uno que la toca subdivide de nuevo: hijas recalculadas y sin el padre publicado
+        let fresh = [flow(a, 200.0)];
+        let all = [flow(a, 400.0), flow(b, 100.0), flow(a, 200.0)];
+        let day = update_day(&prev, &fresh, &all, &cfg, Some(&ChildrenOnly)).await?;
+        assert!(children.iter().all(|c| day.metrics[c].delay_tomtom == 1.5));
+        assert!(!day.metrics.contains_key(&a));
+        assert_eq!((day.aggregates[&a].trips_cars, day.metrics.len()), (600.0, 8));
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn od_flows_top_pairs_and_filters() {
+        let a = LatLng::new(42.4627, -2.44498).unwrap().to_cell(Resolution::Seven);
+        let b = LatLng::new(42.47, -2.30).unwrap().to_cell(Resolution::Seven);
+        let (b1, b2) = {
+            let mut ch = b.children(Resolution::Eight);
+            (ch.next().unwrap(), ch.next().unwrap())
+        };
+        let flow = |o: Vec<(CellIndex, f32)>, d: Vec<(CellIndex, f32)>, trucks: f32, cars: f32, hour| OdFlow {
+            date: NaiveDate::from_ymd_opt(2025, 10, 28).unwrap(),
+            origin: o,
+            dest: d,
+            n_trucks: trucks,
+            n_cars: cars,
+            conf: Some(0.5),
+            hour,
+        };
+        // a→b en dos horas se suma; a→(b1, b2) repartido da dos pares
+        let od = vec![
+            flow(vec![(a, 1.0)], vec![(b, 1.0)], 10.0, 100.0, Some(8)),
+            flow(vec![(a, 1.0)], vec![(b, 1.0)], 0.0, 50.0, Some(9)),
+            flow(vec![(a, 1.0)], vec![(b1, 0.5), (b2, 0.5)], 0.0, 40.0, None),
+            flow(vec![(b, 1.0)], vec![(a, 1.0)], 0.0, 5.0, None),
+        ];
+        let cfg = DelayCfg { truck_factor: 2.0, ..Default::default() };
+
+        let gj = od_flows_geojson(&od, &cfg, &FlowFilter { limit: 2, ..Default::default() });
+        assert_eq!(gj["pairs_total"], 4);
+        let f = gj["features"].as_array().unwrap();
+        assert_eq!(f.len(), 2);
+        let p = &f[0]["properties"];
+        assert_eq!((p["origin"].as_str(), p["dest"].as_str()), (Some(a.to_string().as_str()), Some(b.to_string().as_str())));
+        assert_eq!((p["n_trucks"].as_f64(), p["n_cars"].as_f64(), p["volume"].as_f64()), (Some(10.0), Some(150.0), Some(170.0)));
+        assert_eq!(f[0]["geometry"]["coordinates"].as_array().unwrap().len(), 2);
+
+        // Destino por celda padre + volumen mínimo
+        let filter = FlowFilter { dest: Some(b), min_volume: 20.0, ..Default::default() };
+        let gj = od_flows_geojson(&od, &cfg, &filter);
+        assert_eq!(gj["pairs_total"], 3);
+        let filter = FlowFilter { origin: Some(b), ..Default::default() };
+        assert_eq!(od_flows_geojson(&od, &cfg, &filter)["features"][0]["properties"]["n_cars"], 5.0);
+    }
+
+    #[test]
+    fn resolve_point_prefers_hotspot_child() {
+        let base = Resolution::Seven;
+        let ll = LatLng::new(42.4627, -2.44498).unwrap();
+        let parent = ll.to_cell(base);
+        let child = ll.to_cell(Resolution::Eight);
+        let sibling = parent.children(Resolution::Eight).find(|c| *c != child).unwrap();
+
+        // Celda sin subdividir: el punto cae en ella, a la resolución base
+        let mut metrics = HashMap::from([(parent, H3Metrics::new(parent))]);
+        let (m, how) = resolve_point(&metrics, ll, base).unwrap();
+        assert_eq!((m.cell, how), (parent, CellMatch::Exact));
+        // Una celda hija pedida explícitamente se resuelve por su padre publicado
+        assert_eq!(resolve_cell(&metrics, child, base).map(|(m, how)| (m.cell, how)), Some((parent, CellMatch::Parent)));
+
+        // Hotspot subdividido: el punto resuelve a su hija
+        metrics.remove(&parent);
+        metrics.insert(child, H3Metrics::new(child));
+        let (m, how) = resolve_point(&metrics, ll, base).unwrap();
+        assert_eq!((m.cell, how), (child, CellMatch::Exact));
+        assert!(resolve_cell(&metrics, sibling, base).is_none());
+    }
+
+    #[test]
+    fn hex_filter_bbox_and_hotspots() {
+        let base = Resolution::Seven;
+        let ll = LatLng::new(42.4627, -2.44498).unwrap();
+        let parent = ll.to_cell(base);
+        let child = ll.to_cell(Resolution::Eight);
+        let mut m = H3Metrics::new(child);
+        m.delay_final = 1.5;
+        let hot = HashSet::from([parent]);
+
+        let f = HexFilter { bbox: Some([-2.5, 42.4, -2.4, 42.5]), min_delay: Some(1.2), hotspots_only: true, ..Default::default() };
+        assert!(f.matches(&m, &hot, base));
+        assert!(!f.matches(&m, &HashSet::new(), base));
+        let far = HexFilter { bbox: Some([-3.8, 40.3, -3.6, 40.5]), ..Default::default() };
+        assert!(!far.matches(&m, &hot, base));
+        assert!(!HexFilter { res: Some(7), ..Default::default() }.matches(&m, &hot, base));
+        assert!(!HexFilter { show_eps: Some(0.6), ..Default::default() }.matches(&m, &hot, base));
+        assert!(!HexFilter { used_tomtom: Some(true), ..Default::default() }.matches(&m, &hot, base));
+    }
+
+    #[test]
+    fn diff_metrics_threshold() {
+        let ll = LatLng::new(42.4627, -2.44498).unwrap();
+        let a = ll.to_cell(Resolution::Seven);
+        let mut ring = a.grid_disk::<Vec<_>>(1).into_iter().filter(|c| *c != a);
+        let (b, c) = (ring.next().unwrap(), ring.next().unwrap());
+        let with_delay = |cell: CellIndex, d: f32| {
+            let mut m = H3Metrics::new(cell);
+            m.delay_final = d;
+            (cell, m)
+        };
+        let old = HashMap::from([with_delay(a, 1.10), with_delay(b, 1.30)]);
+        let new = HashMap::from([with_delay(a, 1.12), with_delay(c, 1.00)]);
+
+        let (added, removed, changed) = diff_metrics(&old, &new, 0.05);
+        assert_eq!((added, removed), (vec![c], vec![b]));
+        assert!(changed.is_empty());
+        let (_, _, changed) = diff_metrics(&old, &new, 0.01);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].h3, a.to_string());
+    }
+
+    #[test]
+    fn orion_hour_only_on_hourly_rows() {
+        let cell = LatLng::new(42.4627, -2.44498).unwrap().to_cell(Resolution::Seven);
+        let date = NaiveDate::from_ymd_opt(2025, 10, 27).unwrap();
+        let daily = H3DailyRow::from_metrics(date, 1, &H3Metrics::new(cell));
+        let hourly = H3DailyRow { hour: Some(8), ..daily.clone() };
+        let sink = OrionLdSink::new("http://orion", None, None);
+        let payload = sink.build_entities_payload(&[daily, hourly]);
+        let entities = payload["entities"].as_array().unwrap();
+        assert!(entities[0].get("hour").is_none());
+        assert_eq!(entities[1]["hour"]["value"], 8);
+        assert!(entities[1]["id"].as_str().unwrap().ends_with(":h08"));
+    }
+}
@@ -0,0 +1,220 @@
+//! jsonl.rs — `JsonlStore`: histórico sobre el JSONL de `JsonlSink`
+//!
+//! Indexa el fichero por offsets (sin cargarlo en memoria) y sigue lo que se va añadiendo.
+//! Como el loop reescribe el mismo día en cada refresco (con otro `snapshot`), de cada fecha se sirven
+//! solo las filas de su snapshot más reciente: una celda que ya no sale en él no se arrastra.
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use h3o::CellIndex;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::warn;
+
+use super::{HistoryDay, HistoryQuery, HistoryStore};
+use crate::models::h3types::H3DailyRow;
+
+/// Posición de una línea en el fichero
+#[derive(Clone, Copy, Debug)]
+struct Span {
+    offset: u64,
+    len: u32,
+}
+
+/// Filas de un día en su snapshot más reciente
+#[derive(Default)]
+struct DaySpans {
+    snapshot: u64,
+    /// Última fila de cada (celda, hora) dentro de ese snapshot
+    cells: HashMap<(CellIndex, Option<u8>), Span>,
+}
+
+#[derive(Default)]
+struct JsonlIndex {
+    /// Bytes ya indexados
+    indexed: u64,
+    by_date: BTreeMap<NaiveDate, DaySpans>,
+}
+
+pub struct JsonlStore {
+    path: PathBuf,
+    index: Mutex<JsonlIndex>,
+}
+
+impl JsonlStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), index: Mutex::new(JsonlIndex::default()) }
+    }
+
+    /// Indexa lo añadido desde la última consulta (o todo si el fichero se truncó/rotó)
+    fn refresh(&self, idx: &mut JsonlIndex) -> Result<()> {
+        let len = match std::fs::metadata(&self.path) {
+            Ok(m) => m.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                *idx = JsonlIndex::default();
+                return Ok(());
+            }
+            Err(e) => return Err(e).with_context(|| format!("no se pudo leer {}", self.path.display())),
+        };
+        if len < idx.indexed {
+            *idx = JsonlIndex::default();
+        }
+        if len == idx.indexed {
+            return Ok(());
+        }
+
+        let mut f = File::open(&self.path).with_context(|| format!("no se pudo abrir {}", self.path.display()))?;
+        f.seek(SeekFrom::Start(idx.indexed))?;
+        let mut reader = BufReader::new(f);
+        let mut offset = idx.indexed;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+            // Línea a medio escribir: se indexa en la siguiente consulta
+            if n == 0 || !line.ends_with('\n') {
+                break;
+            }
+            match serde_json::from_str::<H3DailyRow>(line.trim_end()) {
+                Ok(r) => {
+                    let day = idx.by_date.entry(r.date).or_default();
+                    if r.snapshot > day.snapshot {
+                        *day = DaySpans { snapshot: r.snapshot, cells: HashMap::new() };
+                    }
+                    if r.snapshot == day.snapshot {
+                        day.cells.insert((r.h3, r.hour), Span { offset, len: n as u32 });
+                    }
+                }
+                Err(e) => warn!("{}: línea en byte {offset} ignorada: {e}", self.path.display()),
+            }
+            offset += n as u64;
+        }
+        idx.indexed = offset;
+        Ok(())
+    }
+
+    fn read_rows(&self, spans: &mut [Span]) -> Result<Vec<H3DailyRow>> {
+        spans.sort_unstable_by_key(|s| s.offset);
+        let mut f = File::open(&self.path).with_context(|| format!("no se pudo abrir {}", self.path.display()))?;
+        let mut out = Vec::with_capacity(spans.len());
+        let mut buf = Vec::new();
+        for s in spans.iter() {
+            f.seek(SeekFrom::Start(s.offset))?;
+            buf.resize(s.len as usize, 0);
+            f.read_exact(&mut buf)?;
+            out.push(serde_json::from_slice(&buf).context("fila JSONL indexada ilegible")?);
+        }
+        Ok(out)
+    }
+}
+
+impl HistoryStore for JsonlStore {
+    fn query(&self, q: &HistoryQuery) -> Result<Vec<H3DailyRow>> {
+        let mut spans: Vec<Span> = {
+            let mut idx = self.index.lock().expect("índice JSONL");
+            self.refresh(&mut idx)?;
+            idx.by_date
+                .iter()
+                .filter(|(d, _)| q.date_ok(**d))
+                .flat_map(|(_, day)| match q.h3 {
+                    Some(c) => day.cells.get(&(c, q.hour)).copied().into_iter().collect::<Vec<_>>(),
+                    None => day.cells.iter().filter(|((_, h), _)| *h == q.hour).map(|(_, s)| *s).collect(),
+                })
+                .collect()
+        };
+        let mut rows: Vec<H3DailyRow> = self.read_rows(&mut spans)?.into_iter().filter(|r| q.row_ok(r)).collect();
+        rows.sort_by(|a, b| a.date.cmp(&b.date).then_with(|| a.h3.cmp(&b.h3)));
+        Ok(rows)
+    }
+
+    fn days(&self) -> Result<Vec<HistoryDay>> {
+        let mut idx = self.index.lock().expect("índice JSONL");
+        self.refresh(&mut idx)?;
+        Ok(idx
+            .by_date
+            .iter()
+            .map(|(d, day)| HistoryDay { date: *d, cells: day.cells.keys().filter(|(_, h)| h.is_none()).count() })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use h3o::{LatLng, Resolution};
+    use std::io::Write;
+
+    fn row(date: NaiveDate, h3: CellIndex, delay: f32) -> String {
+        row_in(1, date, h3, delay)
+    }
+
+    fn row_in(snapshot: u64, date: NaiveDate, h3: CellIndex, delay: f32) -> String {
+        let r = H3DailyRow {
+            date,
+            h3,
+            res: u8::from(h3.resolution()),
+            snapshot,
+            hour: None,
+            trips_total: 1.0,
+            trips_trucks: 0.0,
+            trips_cars: 1.0,
+            trips_out: 1.0,
+            trips_in: 0.0,
+            trips_intra: 0.0,
+            trips_through: 0.0,
+            truck_share: 0.0,
+            vol_norm: 0.0,
+            conf_cell: 1.0,
+            delay_orange: delay,
+            delay_tomtom: 0.0,
+            delay_final: delay,
+        };
+        serde_json::to_string(&r).unwrap() + "\n"
+    }
+
+    #[test]
+    fn jsonl_store_dedupes_and_follows_appends() {
+        let path = std::env::temp_dir().join(format!("madgrid_hist_{}.jsonl", std::process::id()));
+        let ll = LatLng::new(42.4627, -2.44498).unwrap();
+        let (a, b) = (ll.to_cell(Resolution::Seven), ll.to_cell(Resolution::Eight));
+        let d1 = NaiveDate::from_ymd_opt(2025, 10, 27).unwrap();
+        let d2 = d1.succ_opt().unwrap();
+        std::fs::write(&path, row(d1, a, 1.1) + &row(d1, b, 1.4) + &row(d1, a, 1.2)).unwrap();
+
+        let store = JsonlStore::new(&path);
+        let q = HistoryQuery { h3: Some(a), ..Default::default() };
+        let rows = store.query(&q).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].delay_final, 1.2);
+
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(row(d2, a, 1.5).as_bytes()).unwrap();
+        let rows = store.query(&HistoryQuery { from: Some(d2), ..q.clone() }).unwrap();
+        assert_eq!(rows.iter().map(|r| r.date).collect::<Vec<_>>(), vec![d2]);
+        let res8 = store.query(&HistoryQuery { res: Some(8), ..Default::default() }).unwrap();
+        assert_eq!(res8.len(), 1);
+        assert_eq!(store.days().unwrap().iter().map(|d| d.cells).collect::<Vec<_>>(), vec![2, 1]);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn jsonl_store_serves_latest_snapshot_per_date() {
+        let path = std::env::temp_dir().join(format!("madgrid_hist_snap_{}.jsonl", std::process::id()));
+        let a = LatLng::new(42.4627, -2.44498).unwrap().to_cell(Resolution::Seven);
+        let b = LatLng::new(42.47, -2.30).unwrap().to_cell(Resolution::Seven);
+        let c = LatLng::new(42.40, -2.50).unwrap().to_cell(Resolution::Seven);
+        let d1 = NaiveDate::from_ymd_opt(2025, 10, 27).unwrap();
+        // El snapshot 2 recalcula el día y ya no incluye `a` ni `b`; una fila tardía del 1 no cuenta
+        let text = row_in(1, d1, a, 1.1) + &row_in(1, d1, b, 1.2) + &row_in(2, d1, c, 1.3) + &row_in(1, d1, a, 1.4);
+        std::fs::write(&path, text).unwrap();
+
+        let store = JsonlStore::new(&path);
+        let rows = store.query(&HistoryQuery::default()).unwrap();
+        assert_eq!(rows.iter().map(|r| (r.h3, r.snapshot)).collect::<Vec<_>>(), vec![(c, 2)]);
+        assert!(store.query(&HistoryQuery { h3: Some(a), ..Default::default() }).unwrap().is_empty());
+        assert_eq!(store.days().unwrap().iter().map(|d| d.cells).collect::<Vec<_>>(), vec![1]);
+        std::fs::remove_file(&path).ok();
+    }
+}
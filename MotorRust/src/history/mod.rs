@@ -0,0 +1,62 @@
+//! history — Lectura del histórico diario por celda (`H3DailyRow`)
+//!
+//! Los sinks escriben; un `HistoryStore` permite consultar lo escrito por celda, rango de fechas
+//! y resolución. Implementaciones:
+//! - `JsonlStore`: indexa por offsets el JSONL de `JsonlSink`
+//! - `SqliteHistory`: fichero SQLite local que es a la vez sink y store
+//!
+//! `ParquetSink` solo escribe (particiones para el equipo de datos).
+
+mod jsonl;
+mod parquet;
+mod sqlite;
+
+pub use jsonl::JsonlStore;
+pub use parquet::ParquetSink;
+pub use sqlite::{Retention, SqliteHistory};
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use h3o::CellIndex;
+
+use crate::models::h3types::H3DailyRow;
+
+/// Filtro de consulta (todo opcional; rango de fechas inclusivo)
+#[derive(Clone, Debug, Default)]
+pub struct HistoryQuery {
+    pub h3: Option<CellIndex>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub res: Option<u8>,
+    /// Hora del día; sin ella, solo filas del día completo
+    pub hour: Option<u8>,
+}
+
+impl HistoryQuery {
+    pub(crate) fn date_ok(&self, d: NaiveDate) -> bool {
+        self.from.is_none_or(|f| d >= f) && self.to.is_none_or(|t| d <= t)
+    }
+
+    pub(crate) fn row_ok(&self, r: &H3DailyRow) -> bool {
+        self.date_ok(r.date)
+            && self.h3.is_none_or(|c| c == r.h3)
+            && self.res.is_none_or(|x| x == r.res)
+            && self.hour == r.hour
+    }
+}
+
+/// Días disponibles en el histórico (`cells` cuenta las filas del día completo)
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct HistoryDay {
+    pub date: NaiveDate,
+    pub cells: usize,
+}
+
+/// Histórico consultable. Las implementaciones son síncronas (ficheros locales);
+/// la API las llama desde `spawn_blocking`.
+pub trait HistoryStore: Send + Sync {
+    /// Filas que cumplen `q`, ordenadas por fecha y celda
+    fn query(&self, q: &HistoryQuery) -> Result<Vec<H3DailyRow>>;
+    /// Días con datos (más antiguo primero)
+    fn days(&self) -> Result<Vec<HistoryDay>>;
+}
@@ -0,0 +1,177 @@
+//! parquet.rs — `ParquetSink`: histórico particionado en Parquet
+//!
+//! Escribe `<raíz>/date=YYYY-MM-DD/res=7/part.parquet` con un esquema Arrow tipado.
+//! Cada persistencia reescribe la partición (fichero temporal + rename) y borra las `res=*` del
+//! mismo día que el snapshot ya no trae (p.ej. hijas de un hotspot que dejó de serlo), así que
+//! queda el último snapshot de cada día y resolución.
+
+use anyhow::{Context, Result};
+use arrow_array::{ArrayRef, Date32Array, Float32Array, RecordBatch, StringArray, UInt64Array, UInt8Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::h3grid::HistorySink;
+use crate::models::h3types::H3DailyRow;
+
+pub struct ParquetSink {
+    pub root: PathBuf,
+}
+
+impl ParquetSink {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Ruta de la partición de un día y resolución
+    pub fn partition(&self, date: NaiveDate, res: u8) -> PathBuf {
+        self.root.join(format!("date={date}")).join(format!("res={res}")).join("part.parquet")
+    }
+}
+
+/// Esquema de `H3DailyRow`: `h3` como UInt64 (índice nativo) y `h3_str` en texto
+pub fn daily_schema() -> SchemaRef {
+    let f32_col = |name: &str| Field::new(name, DataType::Float32, false);
+    Arc::new(Schema::new(vec![
+        Field::new("date", DataType::Date32, false),
+        Field::new("h3", DataType::UInt64, false),
+        Field::new("h3_str", DataType::Utf8, false),
+        Field::new("res", DataType::UInt8, false),
+        Field::new("snapshot", DataType::UInt64, false),
+        f32_col("trips_total"),
+        f32_col("trips_trucks"),
+        f32_col("trips_cars"),
+        f32_col("truck_share"),
+        f32_col("vol_norm"),
+        f32_col("conf_cell"),
+        f32_col("delay_orange"),
+        f32_col("delay_tomtom"),
+        f32_col("delay_final"),
+        Field::new("hour", DataType::UInt8, true),
+        f32_col("trips_out"),
+        f32_col("trips_in"),
+        f32_col("trips_intra"),
+        f32_col("trips_through"),
+    ]))
+}
+
+pub fn daily_batch(rows: &[H3DailyRow]) -> Result<RecordBatch> {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).expect("epoch");
+    let f32s = |f: fn(&H3DailyRow) -> f32| Arc::new(Float32Array::from_iter_values(rows.iter().map(f))) as ArrayRef;
+    let cols: Vec<ArrayRef> = vec![
+        Arc::new(Date32Array::from_iter_values(rows.iter().map(|r| (r.date - epoch).num_days() as i32))),
+        Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| u64::from(r.h3)))),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.h3.to_string()))),
+        Arc::new(UInt8Array::from_iter_values(rows.iter().map(|r| r.res))),
+        Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| r.snapshot))),
+        f32s(|r| r.trips_total),
+        f32s(|r| r.trips_trucks),
+        f32s(|r| r.trips_cars),
+        f32s(|r| r.truck_share),
+        f32s(|r| r.vol_norm),
+        f32s(|r| r.conf_cell),
+        f32s(|r| r.delay_orange),
+        f32s(|r| r.delay_tomtom),
+        f32s(|r| r.delay_final),
+        Arc::new(UInt8Array::from_iter(rows.iter().map(|r| r.hour))),
+        f32s(|r| r.trips_out),
+        f32s(|r| r.trips_in),
+        f32s(|r| r.trips_intra),
+        f32s(|r| r.trips_through),
+    ];
+    Ok(RecordBatch::try_new(daily_schema(), cols)?)
+}
+
+fn write_partition(path: &Path, rows: &[H3DailyRow]) -> Result<()> {
+    let dir = path.parent().context("partición sin directorio")?;
+    std::fs::create_dir_all(dir).with_context(|| format!("no se pudo crear {}", dir.display()))?;
+    let tmp = path.with_extension("parquet.tmp");
+    let props = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+    let mut w = ArrowWriter::try_new(File::create(&tmp)?, daily_schema(), Some(props))?;
+    w.write(&daily_batch(rows)?)?;
+    w.close()?;
+    std::fs::rename(&tmp, path).with_context(|| format!("no se pudo escribir {}", path.display()))?;
+    Ok(())
+}
+
+/// Borra las particiones `res=*` de un día que no están en `keep`
+fn prune_partitions(day_dir: &Path, keep: &BTreeSet<u8>) -> Result<()> {
+    let entries = match std::fs::read_dir(day_dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("no se pudo leer {}", day_dir.display())),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        let res = name.strip_prefix("res=").and_then(|r| r.parse::<u8>().ok());
+        if res.is_some_and(|r| !keep.contains(&r)) {
+            std::fs::remove_dir_all(&path)
+                .with_context(|| format!("no se pudo borrar {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl HistorySink for ParquetSink {
+    async fn persist(&self, rows: &[H3DailyRow]) -> anyhow::Result<()> {
+        let mut parts: BTreeMap<(NaiveDate, u8), Vec<H3DailyRow>> = BTreeMap::new();
+        for r in rows {
+            parts.entry((r.date, r.res)).or_default().push(r.clone());
+        }
+        let mut days: BTreeMap<PathBuf, BTreeSet<u8>> = BTreeMap::new();
+        for (d, res) in parts.keys() {
+            days.entry(self.root.join(format!("date={d}"))).or_default().insert(*res);
+        }
+        let jobs: Vec<(PathBuf, Vec<H3DailyRow>)> =
+            parts.into_iter().map(|((d, res), rows)| (self.partition(d, res), rows)).collect();
+        tokio::task::spawn_blocking(move || {
+            jobs.iter().try_for_each(|(path, rows)| write_partition(path, rows))?;
+            days.iter().try_for_each(|(dir, keep)| prune_partitions(dir, keep))
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+    use h3o::{LatLng, Resolution};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[tokio::test]
+    async fn writes_partitioned_typed_parquet() {
+        let root = std::env::temp_dir().join(format!("madgrid_parquet_{}", std::process::id()));
+        let ll = LatLng::new(42.4627, -2.44498).unwrap();
+        let date = NaiveDate::from_ymd_opt(2025, 10, 28).unwrap();
+        let row = |res: Resolution| {
+            let m = crate::models::h3types::H3Metrics::new(ll.to_cell(res));
+            H3DailyRow::from_metrics(date, 7, &m)
+        };
+        let sink = ParquetSink::new(&root);
+        sink.persist(&[row(Resolution::Seven), row(Resolution::Eight)]).await.unwrap();
+
+        let path = sink.partition(date, 8);
+        assert!(path.ends_with("date=2025-10-28/res=8/part.parquet"));
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap().build().unwrap();
+        let batch = reader.next().unwrap().unwrap();
+        assert_eq!(batch.schema(), daily_schema());
+        let h3 = batch.column(1).as_any().downcast_ref::<UInt64Array>().unwrap();
+        assert_eq!(h3.len(), 1);
+        assert_eq!(h3.value(0), u64::from(ll.to_cell(Resolution::Eight)));
+
+        // Un snapshot posterior sin hijas res 8 retira su partición del día
+        sink.persist(&[row(Resolution::Seven)]).await.unwrap();
+        assert!(sink.partition(date, 7).exists() && !path.exists() && !path.parent().unwrap().exists());
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
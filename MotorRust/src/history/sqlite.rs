@@ -0,0 +1,332 @@
+//! sqlite.rs — `SqliteHistory`: histórico en un fichero SQLite local (sink + store)
+//!
+//! Clave primaria (date, h3, res, hour, snapshot): volver a persistir el mismo snapshot sobrescribe
+//! sus filas, y cada recálculo del día añade un snapshot nuevo. `hour = -1` es el día completo. La retención recorta snapshots
+//! antiguos de cada día y días fuera de ventana. El esquema se versiona con `PRAGMA user_version`.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use rusqlite::{params, params_from_iter, types::Value as SqlValue, Connection, OptionalExtension};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+use super::{HistoryDay, HistoryQuery, HistoryStore};
+use crate::h3grid::HistorySink;
+use crate::models::h3types::H3DailyRow;
+
+/// Migraciones en orden; `user_version` = número de migraciones aplicadas
+const MIGRATIONS: &[&str] = &[
+    // v1: tabla diaria por celda y snapshot
+    "CREATE TABLE h3_daily (
+        date          TEXT    NOT NULL,
+        h3            TEXT    NOT NULL,
+        res           INTEGER NOT NULL,
+        snapshot      INTEGER NOT NULL,
+        trips_total   REAL    NOT NULL,
+        trips_trucks  REAL    NOT NULL,
+        trips_cars    REAL    NOT NULL,
+        truck_share   REAL    NOT NULL,
+        vol_norm      REAL    NOT NULL,
+        conf_cell     REAL    NOT NULL,
+        delay_orange  REAL    NOT NULL,
+        delay_tomtom  REAL    NOT NULL,
+        delay_final   REAL    NOT NULL,
+        PRIMARY KEY (date, h3, res, snapshot)
+    ) WITHOUT ROWID;",
+    // v2: consultas por celda
+    "CREATE INDEX h3_daily_h3_date ON h3_daily (h3, date);",
+    // v3: filas horarias (la hora entra en la clave; -1 = día completo)
+    "CREATE TABLE h3_daily_v3 (
+        date          TEXT    NOT NULL,
+        h3            TEXT    NOT NULL,
+        res           INTEGER NOT NULL,
+        snapshot      INTEGER NOT NULL,
+        trips_total   REAL    NOT NULL,
+        trips_trucks  REAL    NOT NULL,
+        trips_cars    REAL    NOT NULL,
+        truck_share   REAL    NOT NULL,
+        vol_norm      REAL    NOT NULL,
+        conf_cell     REAL    NOT NULL,
+        delay_orange  REAL    NOT NULL,
+        delay_tomtom  REAL    NOT NULL,
+        delay_final   REAL    NOT NULL,
+        hour          INTEGER NOT NULL DEFAULT -1,
+        PRIMARY KEY (date, h3, res, hour, snapshot)
+    ) WITHOUT ROWID;
+    INSERT INTO h3_daily_v3 SELECT *, -1 FROM h3_daily;
+    DROP TABLE h3_daily;
+    ALTER TABLE h3_daily_v3 RENAME TO h3_daily;
+    CREATE INDEX h3_daily_h3_date ON h3_daily (h3, date);",
+    // v4: reparto del volumen (salida / llegada / intra-celda / de paso)
+    "ALTER TABLE h3_daily ADD COLUMN trips_out     REAL NOT NULL DEFAULT 0;
+    ALTER TABLE h3_daily ADD COLUMN trips_in      REAL NOT NULL DEFAULT 0;
+    ALTER TABLE h3_daily ADD COLUMN trips_intra   REAL NOT NULL DEFAULT 0;
+    ALTER TABLE h3_daily ADD COLUMN trips_through REAL NOT NULL DEFAULT 0;",
+];
+
+const COLUMNS: &str = "date, h3, res, snapshot, trips_total, trips_trucks, trips_cars, truck_share, \
+                       vol_norm, conf_cell, delay_orange, delay_tomtom, delay_final, hour, \
+                       trips_out, trips_in, trips_intra, trips_through";
+
+/// Política de retención (se aplica tras cada escritura)
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    /// Días conservados contando hacia atrás desde la fecha más reciente (0 = sin límite)
+    pub keep_days: u32,
+    /// Snapshots conservados por día, los más recientes (>= 1)
+    pub keep_snapshots: usize,
+}
+
+pub struct SqliteHistory {
+    path: PathBuf,
+    conn: Arc<Mutex<Connection>>,
+    retention: Retention,
+}
+
+impl SqliteHistory {
+    /// Abre (o crea) el fichero y aplica las migraciones pendientes
+    pub fn open(path: impl AsRef<Path>, retention: Retention) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).ok();
+        }
+        let mut conn =
+            Connection::open(&path).with_context(|| format!("no se pudo abrir {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut conn).with_context(|| format!("migración de {}", path.display()))?;
+        Ok(Self { path, conn: Arc::new(Mutex::new(conn)), retention })
+    }
+
+    /// Snapshots guardados de un día (más antiguo primero)
+    pub fn snapshots(&self, date: NaiveDate) -> Result<Vec<u64>> {
+        let conn = self.conn.lock().expect("conexión SQLite");
+        let mut st = conn.prepare("SELECT DISTINCT snapshot FROM h3_daily WHERE date = ?1 ORDER BY snapshot")?;
+        let ids = st.query_map([date.to_string()], |r| r.get::<_, i64>(0))?;
+        Ok(ids.collect::<rusqlite::Result<Vec<_>>>()?.into_iter().map(|i| i as u64).collect())
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |r| r.get::<_, i64>(0))? as usize;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!("esquema v{version} más nuevo que este binario (v{})", MIGRATIONS.len());
+    }
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+        tx.commit()?;
+        info!("SQLite histórico: migración v{} aplicada", i + 1);
+    }
+    Ok(())
+}
+
+fn upsert(conn: &mut Connection, rows: &[H3DailyRow], retention: Retention) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut st = tx.prepare_cached(&format!(
+            "INSERT OR REPLACE INTO h3_daily ({COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)"
+        ))?;
+        for r in rows {
+            st.execute(params![
+                r.date.to_string(),
+                r.h3.to_string(),
+                r.res,
+                r.snapshot as i64,
+                r.trips_total,
+                r.trips_trucks,
+                r.trips_cars,
+                r.truck_share,
+                r.vol_norm,
+                r.conf_cell,
+                r.delay_orange,
+                r.delay_tomtom,
+                r.delay_final,
+                r.hour.map_or(-1, i64::from),
+                r.trips_out,
+                r.trips_in,
+                r.trips_intra,
+                r.trips_through,
+            ])?;
+        }
+    }
+
+    // Retención: snapshots por día (solo días tocados) y ventana de días
+    let dates: BTreeSet<String> = rows.iter().map(|r| r.date.to_string()).collect();
+    for d in &dates {
+        tx.execute(
+            "DELETE FROM h3_daily WHERE date = ?1 AND snapshot NOT IN (
+                SELECT DISTINCT snapshot FROM h3_daily WHERE date = ?1 ORDER BY snapshot DESC LIMIT ?2)",
+            params![d, retention.keep_snapshots.max(1) as i64],
+        )?;
+    }
+    if retention.keep_days > 0 {
+        let newest: Option<String> = tx.query_row("SELECT MAX(date) FROM h3_daily", [], |r| r.get(0)).optional()?.flatten();
+        if let Some(newest) = newest.and_then(|d| NaiveDate::from_str(&d).ok()) {
+            let cutoff = newest - chrono::Duration::days(i64::from(retention.keep_days) - 1);
+            tx.execute("DELETE FROM h3_daily WHERE date < ?1", [cutoff.to_string()])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn read_row(r: &rusqlite::Row<'_>) -> rusqlite::Result<H3DailyRow> {
+    let bad = |i: usize, e: String| {
+        rusqlite::Error::FromSqlConversionFailure(i, rusqlite::types::Type::Text, e.into())
+    };
+    let date: String = r.get(0)?;
+    let h3: String = r.get(1)?;
+    Ok(H3DailyRow {
+        date: NaiveDate::from_str(&date).map_err(|e| bad(0, e.to_string()))?,
+        h3: h3o::CellIndex::from_str(&h3).map_err(|e| bad(1, e.to_string()))?,
+        res: r.get(2)?,
+        snapshot: r.get::<_, i64>(3)? as u64,
+        hour: u8::try_from(r.get::<_, i64>(13)?).ok(),
+        trips_total: r.get(4)?,
+        trips_trucks: r.get(5)?,
+        trips_cars: r.get(6)?,
+        trips_out: r.get(14)?,
+        trips_in: r.get(15)?,
+        trips_intra: r.get(16)?,
+        trips_through: r.get(17)?,
+        truck_share: r.get(7)?,
+        vol_norm: r.get(8)?,
+        conf_cell: r.get(9)?,
+        delay_orange: r.get(10)?,
+        delay_tomtom: r.get(11)?,
+        delay_final: r.get(12)?,
+    })
+}
+
+#[async_trait]
+impl HistorySink for SqliteHistory {
+    async fn persist(&self, rows: &[H3DailyRow]) -> anyhow::Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let (conn, rows, retention) = (self.conn.clone(), rows.to_vec(), self.retention);
+        tokio::task::spawn_blocking(move || upsert(&mut conn.lock().expect("conexión SQLite"), &rows, retention))
+            .await?
+            .with_context(|| format!("SQLite histórico {}", self.path.display()))
+    }
+}
+
+impl HistoryStore for SqliteHistory {
+    /// Solo el snapshot más reciente de cada día
+    fn query(&self, q: &HistoryQuery) -> Result<Vec<H3DailyRow>> {
+        let mut sql = format!(
+            "SELECT {COLUMNS} FROM h3_daily d
+             WHERE snapshot = (SELECT MAX(snapshot) FROM h3_daily WHERE date = d.date)"
+        );
+        let mut args: Vec<SqlValue> = Vec::new();
+        if let Some(c) = q.h3 {
+            sql.push_str(" AND h3 = ?");
+            args.push(SqlValue::Text(c.to_string()));
+        }
+        if let Some(f) = q.from {
+            sql.push_str(" AND date >= ?");
+            args.push(SqlValue::Text(f.to_string()));
+        }
+        if let Some(t) = q.to {
+            sql.push_str(" AND date <= ?");
+            args.push(SqlValue::Text(t.to_string()));
+        }
+        if let Some(res) = q.res {
+            sql.push_str(" AND res = ?");
+            args.push(SqlValue::Integer(i64::from(res)));
+        }
+        sql.push_str(" AND hour = ?");
+        args.push(SqlValue::Integer(q.hour.map_or(-1, i64::from)));
+        sql.push_str(" ORDER BY date, h3");
+
+        let conn = self.conn.lock().expect("conexión SQLite");
+        let mut st = conn.prepare(&sql)?;
+        let rows = st.query_map(params_from_iter(args), read_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    fn days(&self) -> Result<Vec<HistoryDay>> {
+        let conn = self.conn.lock().expect("conexión SQLite");
+        let mut st = conn.prepare(
+            "SELECT date, COUNT(*) FROM h3_daily d
+             WHERE snapshot = (SELECT MAX(snapshot) FROM h3_daily WHERE date = d.date) AND hour = -1
+             GROUP BY date ORDER BY date",
+        )?;
+        let days = st.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)))?;
+        let mut out = Vec::new();
+        for d in days {
+            let (date, cells) = d?;
+            out.push(HistoryDay { date: NaiveDate::from_str(&date)?, cells: cells as usize });
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use h3o::{LatLng, Resolution};
+
+    fn row(date: NaiveDate, snapshot: u64, delay: f32) -> H3DailyRow {
+        let h3 = LatLng::new(42.4627, -2.44498).unwrap().to_cell(Resolution::Seven);
+        H3DailyRow {
+            date,
+            h3,
+            res: 7,
+            snapshot,
+            hour: None,
+            trips_total: 1.0,
+            trips_trucks: 0.0,
+            trips_cars: 1.0,
+            trips_out: 1.0,
+            trips_in: 0.0,
+            trips_intra: 0.0,
+            trips_through: 0.0,
+            truck_share: 0.0,
+            vol_norm: 0.0,
+            conf_cell: 1.0,
+            delay_orange: delay,
+            delay_tomtom: 0.0,
+            delay_final: delay,
+        }
+    }
+
+    #[tokio::test]
+    async fn upsert_retention_and_reopen() {
+        let path = std::env::temp_dir().join(format!("madgrid_hist_{}.sqlite", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let d1 = NaiveDate::from_ymd_opt(2025, 10, 27).unwrap();
+        let d3 = NaiveDate::from_ymd_opt(2025, 10, 29).unwrap();
+        let retention = Retention { keep_days: 2, keep_snapshots: 2 };
+
+        let db = SqliteHistory::open(&path, retention).unwrap();
+        db.persist(&[row(d1, 1, 1.1)]).await.unwrap();
+        db.persist(&[row(d1, 1, 1.2)]).await.unwrap(); // mismo snapshot: upsert
+        db.persist(&[row(d1, 2, 1.3)]).await.unwrap();
+        db.persist(&[row(d1, 3, 1.4)]).await.unwrap();
+        assert_eq!(db.snapshots(d1).unwrap(), vec![2, 3]);
+        let rows = db.query(&HistoryQuery::default()).unwrap();
+        assert_eq!((rows.len(), rows[0].delay_final, rows[0].snapshot), (1, 1.4, 3));
+
+        // Las filas horarias no se mezclan con las del día completo
+        db.persist(&[H3DailyRow { hour: Some(8), delay_final: 2.0, ..row(d1, 3, 1.4) }]).await.unwrap();
+        assert_eq!(db.query(&HistoryQuery::default()).unwrap()[0].delay_final, 1.4);
+        let h8 = db.query(&HistoryQuery { hour: Some(8), ..Default::default() }).unwrap();
+        assert_eq!((h8.len(), h8[0].hour, h8[0].delay_final), (1, Some(8), 2.0));
+        assert_eq!(db.days().unwrap()[0].cells, 1);
+
+        // d3 deja d1 fuera de la ventana de 2 días
+        db.persist(&[row(d3, 4, 1.0)]).await.unwrap();
+        drop(db);
+        let db = SqliteHistory::open(&path, retention).unwrap();
+        assert_eq!(db.days().unwrap().iter().map(|d| d.date).collect::<Vec<_>>(), vec![d3]);
+        for ext in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{ext}", path.display())).ok();
+        }
+    }
+}
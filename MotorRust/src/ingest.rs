@@ -0,0 +1,852 @@
+//! ingest.rs — Lectura del fichero O/D descargado -> `Vec<ODRecord>`
+//!
+//! Formatos aceptados: CSV, TSV, Parquet y CSV/TSV comprimido con gzip. Se detecta por la
+//! firma del contenido (`PAR1`, `1f 8b`) y, si no la hay, por el `Content-Type` de la
+//! descarga; entre CSV y TSV decide la cabecera. Los nombres de columna salen de
+//! `OdColumns`, así que un export con `fecha,origen,destino,...` entra por el mismo camino.
+//! En Parquet, la fecha puede ser Date32 o texto y las celdas H3 texto o UInt64.
+//!
+//! Cada extremo puede llegar como celda H3 (a cualquier resolución) o como lat/lon;
+//! `normalize_od` lo lleva a `cfg.res`.
+//!
+//! Cada fila se valida al leerla (`OdRules`): conteos negativos, `conf` fuera de 0..1,
+//! H3 o coordenadas inválidas, fechas futuras o viejas y pares O/D repetidos. Una fila mala
+//! se cuenta en el `IngestReport`, se aparta para la cuarentena y no aborta el lote.
+//!
+//! Con `OdColumns::watermark` cada fila lleva una marca creciente (timestamp o id de lote):
+//! las filas con marca <= `OdRules::watermark` ya se ingirieron y se saltan sin leer el resto.
+
+use anyhow::{anyhow, bail, Context, Result};
+use arrow_array::{Array, ArrayRef, Date32Array, Float64Array, RecordBatch, StringArray, UInt64Array};
+use arrow_schema::DataType;
+use bytes::Bytes;
+use chrono::NaiveDate;
+use flate2::read::MultiGzDecoder;
+use h3o::{CellIndex, LatLng, Resolution};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::models::h3types::{ODRecord, OdFlow, RoadCell};
+use crate::models::types::{IngestReport, OdColumns, QuarantineRow};
+
+/// Firma de fichero Parquet (cabecera y pie)
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+/// Firma gzip (RFC 1952)
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OdFormat {
+    Csv,
+    Tsv,
+    Parquet,
+    /// CSV o TSV comprimido; el separador se decide tras descomprimir
+    GzipCsv,
+}
+
+/// Formato del fichero: primero la firma del contenido (no miente), luego el `Content-Type`
+/// y, para texto, el separador que más aparece en la cabecera.
+pub fn detect_format(content_type: Option<&str>, bytes: &[u8]) -> OdFormat {
+    if bytes.starts_with(PARQUET_MAGIC) {
+        return OdFormat::Parquet;
+    }
+    if bytes.starts_with(GZIP_MAGIC) {
+        return OdFormat::GzipCsv;
+    }
+    let mime = content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|m| m.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    match mime.as_str() {
+        "text/tab-separated-values" => OdFormat::Tsv,
+        "text/csv" => OdFormat::Csv,
+        m if m.contains("parquet") => OdFormat::Parquet,
+        _ => sniff_delimited(bytes),
+    }
+}
+
+fn sniff_delimited(bytes: &[u8]) -> OdFormat {
+    let header = bytes.split(|&b| b == b'\n').next().unwrap_or_default();
+    let count = |c: u8| header.iter().filter(|&&b| b == c).count();
+    if count(b'\t') > count(b',') {
+        OdFormat::Tsv
+    } else {
+        OdFormat::Csv
+    }
+}
+
+/// Detecta el formato y parsea con el mapeo de columnas indicado.
+/// Solo falla si el fichero entero no se puede leer (formato, cabecera, columnas);
+/// las filas inválidas se cuentan en `report`.
+pub fn parse_od(
+    bytes: &[u8],
+    content_type: Option<&str>,
+    cols: &OdColumns,
+    rules: &OdRules,
+    report: &mut IngestReport,
+) -> Result<Vec<ODRecord>> {
+    match detect_format(content_type, bytes) {
+        OdFormat::Parquet => parse_od_parquet(Bytes::copy_from_slice(bytes), cols, rules, report),
+        OdFormat::Csv => parse_od_delimited(bytes, b',', cols, rules, report),
+        OdFormat::Tsv => parse_od_delimited(bytes, b'\t', cols, rules, report),
+        OdFormat::GzipCsv => {
+            // Mismo tope que la descarga (`max_body_mb`): un gzip pequeño puede inflar a GiB
+            let max = rules.max_inflated;
+            let mut raw = Vec::new();
+            MultiGzDecoder::new(bytes)
+                .take(if max > 0 { max + 1 } else { u64::MAX })
+                .read_to_end(&mut raw)
+                .context("OD gzip: descompresión")?;
+            if max > 0 && raw.len() as u64 > max {
+                bail!("OD gzip: descomprimido pasa de max_body_mb ({max} bytes)");
+            }
+            // Un .parquet.gz también vale; si no, CSV o TSV según la cabecera
+            match detect_format(None, &raw) {
+                OdFormat::Parquet => parse_od_parquet(Bytes::from(raw), cols, rules, report),
+                OdFormat::Tsv => parse_od_delimited(&raw, b'\t', cols, rules, report),
+                _ => parse_od_delimited(&raw, b',', cols, rules, report),
+            }
+        }
+    }
+}
+
+// ===============================
+// Validación por fila
+// ===============================
+
+/// Reglas de validación de un lote; las fechas se miden contra `today`
+#[derive(Clone, Debug)]
+pub struct OdRules {
+    pub today: NaiveDate,
+    /// Días hacia atrás aceptados (0 = sin límite)
+    pub max_age_days: u32,
+    /// Días hacia delante aceptados
+    pub max_future_days: u32,
+    /// Última marca de agua ingerida: las filas con marca <= esta se saltan
+    pub watermark: Option<i64>,
+    /// Bytes máximos de un fichero gzip ya descomprimido (0 = sin límite)
+    pub max_inflated: u64,
+}
+
+impl OdRules {
+    pub fn new(max_age_days: u32, max_future_days: u32, max_inflated: u64) -> Self {
+        let today = chrono::Utc::now().date_naive();
+        Self { today, max_age_days, max_future_days, watermark: None, max_inflated }
+    }
+
+    /// Fecha más antigua que `max_age_days`
+    pub fn too_old(&self, date: NaiveDate) -> bool {
+        self.max_age_days > 0 && (self.today - date).num_days() > i64::from(self.max_age_days)
+    }
+
+    /// Quita de las filas acumuladas las que ya pasan de `max_age_days`; devuelve cuántas
+    pub fn prune(&self, rows: &mut Vec<ODRecord>) -> usize {
+        let before = rows.len();
+        rows.retain(|r| !self.too_old(r.date));
+        before - rows.len()
+    }
+}
+
+/// Marca de agua de una fila: entero (id de lote) o timestamp (RFC 3339 o `YYYY-MM-DD HH:MM:SS`,
+/// UTC) en milisegundos unix. Las marcas de un mismo fichero deben ser del mismo tipo.
+pub fn parse_watermark(v: &str) -> std::result::Result<i64, String> {
+    let v = v.trim();
+    if let Ok(n) = v.parse::<i64>() {
+        return Ok(n);
+    }
+    if let Ok(ts) = chrono::DateTime::parse_from_rfc3339(v) {
+        return Ok(ts.timestamp_millis());
+    }
+    ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"]
+        .iter()
+        .find_map(|f| chrono::NaiveDateTime::parse_from_str(v, f).ok())
+        .map(|ts| ts.and_utc().timestamp_millis())
+        .ok_or_else(|| format!("marca de agua inválida {v:?}"))
+}
+
+/// Anota la marca de la fila en `report`; `Ok(true)` si ya estaba ingerida (y la cuenta en `skipped`)
+fn already_ingested(v: &str, rules: &OdRules, report: &mut IngestReport) -> std::result::Result<bool, String> {
+    let w = parse_watermark(v)?;
+    report.watermark = Some(report.watermark.map_or(w, |m| m.max(w)));
+    if rules.watermark.is_some_and(|m| w <= m) {
+        report.skipped += 1;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Validador de un lote: las reglas más los pares O/D ya vistos (fila donde aparecieron)
+struct Validator<'a> {
+    rules: &'a OdRules,
+    seen: HashMap<String, usize>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(rules: &'a OdRules) -> Self {
+        Self { rules, seen: HashMap::new() }
+    }
+
+    fn check(&mut self, r: &ODRecord, row: usize) -> std::result::Result<(), String> {
+        let days = (r.date - self.rules.today).num_days();
+        if days > i64::from(self.rules.max_future_days) {
+            return Err(format!("fecha futura {}", r.date));
+        }
+        if self.rules.too_old(r.date) {
+            return Err(format!("fecha de hace más de {} días {}", self.rules.max_age_days, r.date));
+        }
+        for (name, v) in [("n_trucks", r.n_trucks), ("n_cars", r.n_cars)] {
+            if !v.is_finite() || v < 0.0 {
+                return Err(format!("{name} negativo o no finito {v}"));
+            }
+        }
+        if let Some(c) = r.conf.filter(|c| !(0.0..=1.0).contains(c)) {
+            return Err(format!("conf {c} fuera de 0..1"));
+        }
+        if let Some(h) = r.hour.filter(|h| *h > 23) {
+            return Err(format!("hora {h} fuera de 0..23"));
+        }
+        let origin = check_endpoint(&r.origin_h3, r.origin_lat, r.origin_lon).map_err(|e| format!("origen: {e}"))?;
+        let dest = check_endpoint(&r.dest_h3, r.dest_lat, r.dest_lon).map_err(|e| format!("destino: {e}"))?;
+        let key = format!("{}|{:?}|{origin}|{dest}", r.date, r.hour);
+        if let Some(first) = self.seen.get(&key) {
+            return Err(format!("par O/D repetido (ya en la fila {first})"));
+        }
+        self.seen.insert(key, row);
+        Ok(())
+    }
+}
+
+/// Un extremo válido (H3 que parsea o lat/lon en rango); devuelve su clave para duplicados
+fn check_endpoint(h3: &str, lat: Option<f64>, lon: Option<f64>) -> std::result::Result<String, String> {
+    if !h3.is_empty() {
+        return CellIndex::from_str(h3).map(|c| c.to_string()).map_err(|_| format!("H3 inválido {h3:?}"));
+    }
+    let (Some(lat), Some(lon)) = (lat, lon) else {
+        return Err("sin celda H3 ni lat/lon".into());
+    };
+    LatLng::new(lat, lon).map_err(|e| format!("coordenadas inválidas ({lat}, {lon}): {e}"))?;
+    Ok(format!("{lat},{lon}"))
+}
+
+/// Añade las filas apartadas de `report` al fichero de cuarentena (JSONL, una por línea)
+pub async fn write_quarantine(path: &Path, report: &IngestReport) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    if report.quarantined.is_empty() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await.ok();
+    }
+    let mut f = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    let mut buf = String::new();
+    for QuarantineRow { row, error, raw } in &report.quarantined {
+        let line = serde_json::json!({
+            "ts_utc": report.ts_utc,
+            "source": report.source,
+            "row": row,
+            "error": error,
+            "raw": raw,
+        });
+        buf.push_str(&line.to_string());
+        buf.push('\n');
+    }
+    f.write_all(buf.as_bytes()).await?;
+    Ok(())
+}
+
+// ===============================
+// CSV / TSV
+// ===============================
+
+/// Posición de cada campo de `ODRecord` en la cabecera (sin distinguir mayúsculas).
+/// Cada extremo necesita la columna H3, el par lat/lon o ambos.
+struct HeaderIdx {
+    date: usize,
+    origin: Option<usize>,
+    dest: Option<usize>,
+    origin_ll: Option<(usize, usize)>,
+    dest_ll: Option<(usize, usize)>,
+    trucks: usize,
+    cars: usize,
+    conf: Option<usize>,
+    hour: Option<usize>,
+    watermark: Option<usize>,
+}
+
+impl HeaderIdx {
+    fn new(headers: &csv::StringRecord, cols: &OdColumns) -> Result<Self> {
+        let find = |name: &str| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(name.trim()));
+        let need = |name: &str| find(name).ok_or_else(|| anyhow!("OD CSV: falta la columna {name:?}"));
+        let pair = |lat: &str, lon: &str| find(lat).zip(find(lon));
+        let idx = Self {
+            date: need(&cols.date)?,
+            origin: find(&cols.origin_h3),
+            dest: find(&cols.dest_h3),
+            origin_ll: pair(&cols.origin_lat, &cols.origin_lon),
+            dest_ll: pair(&cols.dest_lat, &cols.dest_lon),
+            trucks: need(&cols.n_trucks)?,
+            cars: need(&cols.n_cars)?,
+            conf: find(&cols.conf),
+            hour: find(&cols.hour),
+            watermark: cols.watermark.as_deref().map(need).transpose()?,
+        };
+        if idx.origin.is_none() && idx.origin_ll.is_none() {
+            bail!("OD CSV: falta la columna {:?} (o {:?}/{:?})", cols.origin_h3, cols.origin_lat, cols.origin_lon);
+        }
+        if idx.dest.is_none() && idx.dest_ll.is_none() {
+            bail!("OD CSV: falta la columna {:?} (o {:?}/{:?})", cols.dest_h3, cols.dest_lat, cols.dest_lon);
+        }
+        Ok(idx)
+    }
+}
+
+/// Campo numérico opcional: vacío -> `None`
+fn opt_num<T: FromStr>(v: &str, name: &str) -> std::result::Result<Option<T>, String> {
+    if v.is_empty() {
+        return Ok(None);
+    }
+    v.parse().map(Some).map_err(|_| format!("{name} no numérico {v:?}"))
+}
+
+fn num<T: FromStr>(v: &str, name: &str) -> std::result::Result<T, String> {
+    opt_num(v, name)?.ok_or_else(|| format!("{name} vacío"))
+}
+
+/// Hora del día: `8`, `08`, `08:00` u `08:00:00` -> 8; vacío -> `None`
+fn parse_hour(v: &str) -> std::result::Result<Option<u8>, String> {
+    let h = v.split(':').next().unwrap_or_default().trim();
+    opt_num(h, "hora").map_err(|_| format!("hora inválida {v:?}"))
+}
+
+pub fn parse_od_delimited(
+    bytes: &[u8],
+    delimiter: u8,
+    cols: &OdColumns,
+    rules: &OdRules,
+    report: &mut IngestReport,
+) -> Result<Vec<ODRecord>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .delimiter(delimiter)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(bytes);
+    let idx = HeaderIdx::new(rdr.headers().context("OD CSV: cabecera")?, cols)?;
+    let mut validator = Validator::new(rules);
+    let mut od_rows: Vec<ODRecord> = Vec::new();
+    let sep = char::from(delimiter).to_string();
+
+    for (i, rec) in rdr.records().enumerate() {
+        // Lo ya ingerido se salta antes de leer nada más
+        let seen = match (idx.watermark, &rec) {
+            (Some(j), Ok(r)) => already_ingested(r.get(j).unwrap_or_default(), rules, report),
+            _ => Ok(false),
+        };
+        if seen == Ok(true) {
+            continue;
+        }
+        report.rows += 1;
+        let raw = rec.as_ref().ok().map(|r| r.iter().collect::<Vec<_>>().join(&sep));
+        let parsed = seen.and(rec.map_err(|e| e.to_string())).and_then(|rec| {
+            let field = |j: usize| rec.get(j).unwrap_or_default();
+            let ll = |p: Option<(usize, usize)>, lat: &str, lon: &str| -> std::result::Result<_, String> {
+                match p {
+                    Some((a, b)) => Ok((opt_num::<f64>(field(a), lat)?, opt_num::<f64>(field(b), lon)?)),
+                    None => Ok((None, None)),
+                }
+            };
+            let date = NaiveDate::parse_from_str(field(idx.date), &cols.date_format)
+                .map_err(|_| format!("fecha inválida {:?}", field(idx.date)))?;
+            let (origin_lat, origin_lon) = ll(idx.origin_ll, &cols.origin_lat, &cols.origin_lon)?;
+            let (dest_lat, dest_lon) = ll(idx.dest_ll, &cols.dest_lat, &cols.dest_lon)?;
+            Ok(ODRecord {
+                date,
+                origin_h3: idx.origin.map(field).unwrap_or_default().to_string(),
+                dest_h3: idx.dest.map(field).unwrap_or_default().to_string(),
+                origin_lat,
+                origin_lon,
+                dest_lat,
+                dest_lon,
+                n_trucks: num(field(idx.trucks), &cols.n_trucks)?,
+                n_cars: num(field(idx.cars), &cols.n_cars)?,
+                conf: idx.conf.map(field).map(|c| opt_num(c, &cols.conf)).transpose()?.flatten(),
+                hour: idx.hour.map(field).map(parse_hour).transpose()?.flatten(),
+            })
+        });
+        match parsed.and_then(|r| validator.check(&r, i + 1).map(|_| r)) {
+            Ok(r) => od_rows.push(r),
+            Err(e) => report.quarantine(i + 1, e, raw),
+        }
+    }
+    Ok(od_rows)
+}
+
+// ===============================
+// Parquet
+// ===============================
+
+pub fn parse_od_parquet(
+    bytes: Bytes,
+    cols: &OdColumns,
+    rules: &OdRules,
+    report: &mut IngestReport,
+) -> Result<Vec<ODRecord>> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(bytes)
+        .context("OD Parquet: cabecera")?
+        .build()
+        .context("OD Parquet: lector")?;
+    let mut validator = Validator::new(rules);
+    let mut out = Vec::new();
+    for batch in reader {
+        let batch = batch.context("OD Parquet: lote")?;
+        batch_to_records(&batch, cols, &mut validator, &mut out, report)?;
+    }
+    Ok(out)
+}
+
+fn column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a ArrayRef> {
+    batch.column_by_name(name).ok_or_else(|| anyhow!("OD Parquet: falta la columna {name:?}"))
+}
+
+/// Fechas por fila: nula -> `None`; texto que no casa con el formato -> error de esa fila
+fn dates(col: &ArrayRef, format: &str) -> Result<Vec<std::result::Result<Option<NaiveDate>, String>>> {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).expect("epoch");
+    match col.data_type() {
+        DataType::Date32 => {
+            let a = col.as_any().downcast_ref::<Date32Array>().expect("Date32");
+            Ok((0..a.len())
+                .map(|i| Ok(a.is_valid(i).then(|| epoch + chrono::Duration::days(i64::from(a.value(i))))))
+                .collect())
+        }
+        _ => {
+            let a = arrow_cast::cast(col, &DataType::Utf8)?;
+            let a = a.as_any().downcast_ref::<StringArray>().expect("Utf8");
+            Ok((0..a.len())
+                .map(|i| {
+                    if a.is_null(i) {
+                        return Ok(None);
+                    }
+                    NaiveDate::parse_from_str(a.value(i), format)
+                        .map(Some)
+                        .map_err(|_| format!("fecha inválida {:?}", a.value(i)))
+                })
+                .collect())
+        }
+    }
+}
+
+/// Celdas H3 en texto; si la columna es UInt64 se convierte el índice nativo
+/// (un entero que no es celda queda tal cual y lo rechaza `normalize_od`)
+fn cells(col: &ArrayRef) -> Result<Vec<String>> {
+    if let Some(a) = col.as_any().downcast_ref::<UInt64Array>() {
+        return Ok((0..a.len())
+            .map(|i| match CellIndex::try_from(a.value(i)) {
+                Ok(c) => c.to_string(),
+                Err(_) => a.value(i).to_string(),
+            })
+            .collect());
+    }
+    let a = arrow_cast::cast(col, &DataType::Utf8)?;
+    let a = a.as_any().downcast_ref::<StringArray>().expect("Utf8");
+    Ok((0..a.len()).map(|i| a.value(i).to_string()).collect())
+}
+
+fn floats(col: &ArrayRef) -> Result<Float64Array> {
+    let a = arrow_cast::cast(col, &DataType::Float64)?;
+    Ok(a.as_any().downcast_ref::<Float64Array>().expect("Float64").clone())
+}
+
+fn batch_to_records(
+    batch: &RecordBatch,
+    cols: &OdColumns,
+    validator: &mut Validator,
+    out: &mut Vec<ODRecord>,
+    report: &mut IngestReport,
+) -> Result<()> {
+    let opt_floats = |name: &str| batch.column_by_name(name).map(floats).transpose();
+    let opt_cells = |name: &str| batch.column_by_name(name).map(cells).transpose();
+    let date = dates(column(batch, &cols.date)?, &cols.date_format)?;
+    let origin = opt_cells(&cols.origin_h3)?;
+    let dest = opt_cells(&cols.dest_h3)?;
+    let (o_lat, o_lon) = (opt_floats(&cols.origin_lat)?, opt_floats(&cols.origin_lon)?);
+    let (d_lat, d_lon) = (opt_floats(&cols.dest_lat)?, opt_floats(&cols.dest_lon)?);
+    if origin.is_none() && o_lat.as_ref().zip(o_lon.as_ref()).is_none() {
+        bail!("OD Parquet: falta la columna {:?} (o {:?}/{:?})", cols.origin_h3, cols.origin_lat, cols.origin_lon);
+    }
+    if dest.is_none() && d_lat.as_ref().zip(d_lon.as_ref()).is_none() {
+        bail!("OD Parquet: falta la columna {:?} (o {:?}/{:?})", cols.dest_h3, cols.dest_lat, cols.dest_lon);
+    }
+    let trucks = floats(column(batch, &cols.n_trucks)?)?;
+    let cars = floats(column(batch, &cols.n_cars)?)?;
+    let conf = opt_floats(&cols.conf)?;
+    let hour = match batch.column_by_name(&cols.hour) {
+        Some(col) => Some(arrow_cast::cast(col, &DataType::Utf8)?),
+        None => None,
+    };
+    let hour = hour.as_ref().map(|a| a.as_any().downcast_ref::<StringArray>().expect("Utf8"));
+    let watermark = match cols.watermark.as_deref() {
+        Some(name) => Some(arrow_cast::cast(column(batch, name)?, &DataType::Utf8)?),
+        None => None,
+    };
+    let watermark = watermark.as_ref().map(|a| a.as_any().downcast_ref::<StringArray>().expect("Utf8"));
+    let at = |a: &Option<Float64Array>, i: usize| a.as_ref().filter(|a| a.is_valid(i)).map(|a| a.value(i));
+    let text = |a: &Option<Vec<String>>, i: usize| a.as_ref().map(|a| a[i].clone()).unwrap_or_default();
+
+    for i in 0..batch.num_rows() {
+        let seen = match watermark {
+            Some(a) if a.is_null(i) => Err("marca de agua vacía".to_string()),
+            Some(a) => already_ingested(a.value(i), validator.rules, report),
+            None => Ok(false),
+        };
+        if seen == Ok(true) {
+            continue;
+        }
+        report.rows += 1;
+        // Fila del fichero (las saltadas por la marca de agua también cuentan)
+        let row = report.rows + report.skipped;
+        if let Err(e) = seen {
+            report.quarantine(row, e, None);
+            continue;
+        }
+        if trucks.is_null(i) || cars.is_null(i) {
+            report.quarantine(row, format!("sin {}/{}", cols.n_trucks, cols.n_cars), None);
+            continue;
+        }
+        let date = match &date[i] {
+            Ok(Some(d)) => *d,
+            Ok(None) => {
+                report.quarantine(row, "fecha vacía".into(), None);
+                continue;
+            }
+            Err(e) => {
+                report.quarantine(row, e.clone(), None);
+                continue;
+            }
+        };
+        let hour = match hour.filter(|a| a.is_valid(i)).map(|a| parse_hour(a.value(i))).transpose() {
+            Ok(h) => h.flatten(),
+            Err(e) => {
+                report.quarantine(row, e, None);
+                continue;
+            }
+        };
+        let rec = ODRecord {
+            date,
+            origin_h3: text(&origin, i),
+            dest_h3: text(&dest, i),
+            origin_lat: at(&o_lat, i),
+            origin_lon: at(&o_lon, i),
+            dest_lat: at(&d_lat, i),
+            dest_lon: at(&d_lon, i),
+            n_trucks: trucks.value(i) as f32,
+            n_cars: cars.value(i) as f32,
+            conf: at(&conf, i).map(|c| c as f32),
+            hour,
+        };
+        match validator.check(&rec, row) {
+            Ok(()) => out.push(rec),
+            Err(e) => report.quarantine(row, e, serde_json::to_string(&rec).ok()),
+        }
+    }
+    Ok(())
+}
+
+// ===============================
+// Normalización a `cfg.res`
+// ===============================
+
+/// Niveles máximos que se reparte una celda gruesa (7³ = 343 hijas)
+const MAX_SPREAD_LEVELS: u8 = 3;
+
+/// Un extremo del O/D en celdas de `res` con su peso; `true` si hubo que normalizarlo
+fn endpoint(
+    h3: &str,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    res: Resolution,
+    road_map: Option<&HashMap<CellIndex, RoadCell>>,
+) -> std::result::Result<(Vec<(CellIndex, f32)>, bool), String> {
+    if h3.is_empty() {
+        let (Some(lat), Some(lon)) = (lat, lon) else {
+            return Err("sin celda H3 ni lat/lon".into());
+        };
+        let ll = LatLng::new(lat, lon).map_err(|e| format!("coordenadas inválidas ({lat}, {lon}): {e}"))?;
+        return Ok((vec![(ll.to_cell(res), 1.0)], true));
+    }
+
+    let c = CellIndex::from_str(h3).map_err(|_| format!("H3 inválido {h3:?}"))?;
+    let cres = c.resolution();
+    if cres == res {
+        return Ok((vec![(c, 1.0)], false));
+    }
+    if cres > res {
+        // Más fina: se sube al padre
+        let p = c.parent(res).expect("padre a resolución más gruesa");
+        return Ok((vec![(p, 1.0)], true));
+    }
+    if u8::from(res) - u8::from(cres) > MAX_SPREAD_LEVELS {
+        return Err(format!("H3 {h3} demasiado grueso (res {cres}) para repartir a res {res}"));
+    }
+
+    // Más gruesa: se reparte entre las hijas por longitud de vía; sin roadmap (o sin vías), a partes iguales
+    let children: Vec<CellIndex> = c.children(res).collect();
+    let lens: Vec<f64> = children
+        .iter()
+        .map(|ch| road_map.and_then(|m| m.get(ch)).map_or(0.0, |rc| rc.total_len_m.max(0.0)))
+        .collect();
+    let total: f64 = lens.iter().sum();
+    let parts = if total > 0.0 {
+        children
+            .into_iter()
+            .zip(lens)
+            .filter(|(_, l)| *l > 0.0)
+            .map(|(ch, l)| (ch, (l / total) as f32))
+            .collect()
+    } else {
+        let k = 1.0 / children.len() as f32;
+        children.into_iter().map(|ch| (ch, k)).collect()
+    };
+    Ok((parts, true))
+}
+
+/// Lleva cada registro a celdas de `res` y completa el `report` (aceptadas, normalizadas,
+/// descartadas sin volumen y rechazadas). `rows` lo cuenta `parse_od`.
+pub fn normalize_od(
+    records: &[ODRecord],
+    res: Resolution,
+    road_map: Option<&HashMap<CellIndex, RoadCell>>,
+    report: &mut IngestReport,
+) -> Vec<OdFlow> {
+    let mut out = Vec::with_capacity(records.len());
+    for (i, r) in records.iter().enumerate() {
+        let flow = endpoint(&r.origin_h3, r.origin_lat, r.origin_lon, res, road_map)
+            .map_err(|e| format!("origen: {e}"))
+            .and_then(|o| {
+                let d = endpoint(&r.dest_h3, r.dest_lat, r.dest_lon, res, road_map)
+                    .map_err(|e| format!("destino: {e}"))?;
+                Ok((o, d))
+            });
+        let ((origin, o_norm), (dest, d_norm)) = match flow {
+            Ok(x) => x,
+            Err(e) => {
+                report.reject(format!("registro {}: {e}", i + 1));
+                continue;
+            }
+        };
+        if r.n_trucks + r.n_cars <= 0.0 {
+            report.dropped += 1;
+            continue;
+        }
+        if o_norm || d_norm {
+            report.normalized += 1;
+        } else {
+            report.accepted += 1;
+        }
+        out.push(OdFlow {
+            date: r.date,
+            origin,
+            dest,
+            n_trucks: r.n_trucks,
+            n_cars: r.n_cars,
+            conf: r.conf,
+            hour: r.hour,
+        });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Float32Array, StringArray};
+    use arrow_schema::{Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    fn rules() -> OdRules {
+        OdRules { today: NaiveDate::from_ymd_opt(2025, 10, 28).unwrap(), max_age_days: 30, max_future_days: 1, watermark: None, max_inflated: 0 }
+    }
+
+    #[test]
+    fn parquet_and_csv_give_same_records() {
+        let c = "873929a4affffff".parse::<CellIndex>().unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("date", DataType::Utf8, false),
+            Field::new("origin_h3", DataType::UInt64, false),
+            Field::new("dest_h3", DataType::Utf8, false),
+            Field::new("n_trucks", DataType::Float32, false),
+            Field::new("n_cars", DataType::Float32, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["2025-10-28"])),
+                Arc::new(UInt64Array::from(vec![u64::from(c)])),
+                Arc::new(StringArray::from(vec![c.to_string()])),
+                Arc::new(Float32Array::from(vec![40.0])),
+                Arc::new(Float32Array::from(vec![900.0])),
+            ],
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        let mut w = ArrowWriter::try_new(&mut buf, schema, None).unwrap();
+        w.write(&batch).unwrap();
+        w.close().unwrap();
+
+        let cols = OdColumns::default();
+        let mut report = IngestReport::default();
+        let pq = parse_od(&buf, None, &cols, &rules(), &mut report).unwrap();
+        let csv = format!("date,origin_h3,dest_h3,n_trucks,n_cars,conf\n2025-10-28,{c},{c},40,900,\n");
+        let csv = parse_od(csv.as_bytes(), Some("text/csv"), &cols, &rules(), &mut report).unwrap();
+        assert_eq!(pq.len(), 1);
+        assert_eq!((pq[0].date, &pq[0].origin_h3, pq[0].n_cars), (csv[0].date, &csv[0].origin_h3, csv[0].n_cars));
+        assert_eq!(pq[0].conf, None);
+    }
+
+    #[test]
+    fn gzip_tsv_with_mapped_columns() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let c = "873929a4affffff";
+        let tsv = format!("fecha\torigen\tdestino\tcamiones\tcoches\n28/10/2025\t{c}\t{c}\t4\t90\n");
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(tsv.as_bytes()).unwrap();
+        let gz = gz.finish().unwrap();
+
+        assert_eq!(detect_format(Some("application/octet-stream"), &gz), OdFormat::GzipCsv);
+        assert_eq!(detect_format(None, tsv.as_bytes()), OdFormat::Tsv);
+        assert_eq!(detect_format(Some("text/tab-separated-values; charset=utf-8"), b"a,b,c"), OdFormat::Tsv);
+        assert_eq!(detect_format(Some("application/vnd.apache.parquet"), b"x"), OdFormat::Parquet);
+
+        let cols = OdColumns {
+            date: "fecha".into(),
+            origin_h3: "origen".into(),
+            dest_h3: "destino".into(),
+            n_trucks: "camiones".into(),
+            n_cars: "coches".into(),
+            date_format: "%d/%m/%Y".into(),
+            ..OdColumns::default()
+        };
+        let rows = parse_od(&gz, None, &cols, &rules(), &mut IngestReport::default()).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].date, NaiveDate::from_ymd_opt(2025, 10, 28).unwrap());
+        assert_eq!((rows[0].origin_h3.as_str(), rows[0].n_trucks, rows[0].n_cars, rows[0].conf), (c, 4.0, 90.0, None));
+        assert!(parse_od(tsv.as_bytes(), None, &OdColumns::default(), &rules(), &mut IngestReport::default()).is_err());
+
+        // Bomba: 4 MiB de ceros comprimen a unos KiB y pasan del tope de 1 MiB al inflar
+        let mut bomb = GzEncoder::new(Vec::new(), Compression::best());
+        bomb.write_all(&vec![0u8; 4 << 20]).unwrap();
+        let bomb = bomb.finish().unwrap();
+        assert!(bomb.len() < 64 << 10);
+        let capped = OdRules { max_inflated: 1 << 20, ..rules() };
+        let e = parse_od(&bomb, None, &cols, &capped, &mut IngestReport::default()).unwrap_err();
+        assert!(format!("{e:#}").contains("max_body_mb"), "{e:#}");
+        assert_eq!(parse_od(&gz, None, &cols, &capped, &mut IngestReport::default()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn normalizes_mixed_resolutions_and_latlon() {
+        let ll = LatLng::new(42.4627, -2.44498).unwrap();
+        let c7 = ll.to_cell(Resolution::Seven);
+        let c8 = ll.to_cell(Resolution::Eight);
+        let c6 = ll.to_cell(Resolution::Six);
+        let kids: Vec<CellIndex> = c6.children(Resolution::Seven).take(2).collect();
+        let road = |h3: CellIndex, len: f64| {
+            (h3, RoadCell { h3, road_count: 1, total_len_m: len, avg_lat: 0.0, avg_lon: 0.0, primary_ratio: 0.0 })
+        };
+        let road_map: HashMap<_, _> = [road(kids[0], 300.0), road(kids[1], 100.0)].into_iter().collect();
+
+        let csv = format!(
+            "date,origin_h3,dest_h3,origin_lat,origin_lon,dest_lat,dest_lon,n_trucks,n_cars,conf\n\
+             2025-10-28,{c7},{c7},,,,,1,9,\n\
+             2025-10-28,{c8},{c6},,,,,4,0,0.5\n\
+             2025-10-28,,{c7},42.4627,-2.44498,,,1,1,\n\
+             2025-10-28,zzz,{c7},,,,,1,1,\n\
+             2025-10-27,{c7},{c7},,,,,0,0,\n\
+             2025-10-26,{c7},{c7},,,,,muchos,1,\n"
+        );
+        let mut report = IngestReport::default();
+        let rows = parse_od(csv.as_bytes(), None, &OdColumns::default(), &rules(), &mut report).unwrap();
+        let flows = normalize_od(&rows, Resolution::Seven, Some(&road_map), &mut report);
+
+        assert_eq!((report.rows, report.accepted, report.normalized, report.dropped, report.rejected), (6, 1, 2, 1, 2));
+        assert_eq!(report.errors.len(), 2);
+        assert_eq!(flows.len(), 3);
+        assert_eq!(flows[1].origin, vec![(c7, 1.0)]);
+        assert_eq!(flows[1].dest, vec![(kids[0], 0.75), (kids[1], 0.25)]);
+        assert_eq!(flows[2].origin, vec![(c7, 1.0)]);
+    }
+
+    #[test]
+    fn validation_quarantines_bad_rows() {
+        let c = "873929a4affffff";
+        let csv = format!(
+            "date,origin_h3,dest_h3,n_trucks,n_cars,conf\n\
+             2025-10-28,{c},{c},1,9,0.5\n\
+             2025-10-28,{c},{c},2,8,0.5\n\
+             2025-10-28,{c},{c},-1,9,\n\
+             2025-10-28,{c},8739xx,1,9,\n\
+             2025-10-27,{c},{c},1,9,1.5\n\
+             2025-11-02,{c},{c},1,9,\n\
+             2025-08-01,{c},{c},1,9,\n\
+             0000-00-00,{c},{c},1,9,\n\
+             2025-10-29,{c},{c},1,9,\n"
+        );
+        let mut report = IngestReport::default();
+        let rows = parse_od(csv.as_bytes(), None, &OdColumns::default(), &rules(), &mut report).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!((report.rows, report.rejected), (9, 7));
+        let why: Vec<(usize, &str)> = report.quarantined.iter().map(|q| (q.row, q.error.as_str())).collect();
+        assert!(why[0].1.starts_with("par O/D repetido (ya en la fila 1)"), "{why:?}");
+        assert!(why[1].1.starts_with("n_trucks negativo"));
+        assert!(why[2].1.starts_with("destino: H3 inválido"));
+        assert!(why[3].1.starts_with("conf 1.5"));
+        assert!(why[4].1.starts_with("fecha futura"));
+        assert!(why[5].1.starts_with("fecha de hace más de 30 días"));
+        assert!(why[6].1.starts_with("fecha inválida"));
+        assert_eq!(report.quarantined[0].raw.as_deref(), Some(format!("2025-10-28,{c},{c},2,8,0.5").as_str()));
+        assert_eq!(report.errors[0], "fila 2: par O/D repetido (ya en la fila 1)");
+
+        let csv = format!("date,origin_h3,dest_h3,n_trucks,n_cars,hour\n2025-10-28,{c},{c},1,9,08:00\n2025-10-28,{c},{c},1,9,24\n");
+        let mut report = IngestReport::default();
+        let rows = parse_od(csv.as_bytes(), None, &OdColumns::default(), &rules(), &mut report).unwrap();
+        assert_eq!((rows.len(), rows[0].hour, report.errors[0].as_str()), (1, Some(8), "fila 2: hora 24 fuera de 0..23"));
+    }
+
+    #[test]
+    fn watermark_skips_ingested_rows() {
+        let (a, b) = ("873929a4affffff", "873929a4effffff");
+        let cols = OdColumns { watermark: Some("ts".into()), ..Default::default() };
+        let csv = format!(
+            "date,origin_h3,dest_h3,n_trucks,n_cars,ts\n\
+             2025-10-28,{a},{b},1,9,2025-10-28T08:00:00Z\n\
+             2025-10-28,{b},{a},1,9,2025-10-28 09:00:00\n"
+        );
+        let mut report = IngestReport::default();
+        let rows = parse_od(csv.as_bytes(), None, &cols, &rules(), &mut report).unwrap();
+        assert_eq!((rows.len(), report.skipped), (2, 0));
+        let wm = report.watermark;
+        assert_eq!(wm, Some(parse_watermark("2025-10-28T09:00:00+00:00").unwrap()));
+
+        // El mismo fichero con una fila más: solo se lee la nueva (y una marca rota va a cuarentena)
+        let csv = format!("{csv}2025-10-28,{a},{a},2,5,2025-10-28T10:00:00Z\n2025-10-28,{a},{a},2,5,ayer\n");
+        let mut report = IngestReport::default();
+        let rules = OdRules { watermark: wm, ..rules() };
+        let rows = parse_od(csv.as_bytes(), None, &cols, &rules, &mut report).unwrap();
+        assert_eq!((rows.len(), rows[0].n_cars), (1, 5.0));
+        assert_eq!((report.rows, report.skipped, report.rejected), (2, 2, 1));
+        assert_eq!(report.errors[0], "fila 4: marca de agua inválida \"ayer\"");
+        assert!(report.watermark > wm);
+        assert_eq!(parse_watermark("42"), Ok(42));
+
+        // Las filas acumuladas caducan con `max_age_days`, igual que al ingerir
+        let mut all = rows;
+        let later = OdRules { today: NaiveDate::from_ymd_opt(2025, 11, 28).unwrap(), ..rules.clone() };
+        assert_eq!((rules.prune(&mut all), later.prune(&mut all), all.len()), (0, 1, 0));
+    }
+}
@@ -1,28 +1,47 @@
-//! main.rs — Pipeline O/D + TomTom + históricos (sin ENV)
+//! main.rs — Pipeline O/D + TomTom + históricos
+//! Configuración por capas (fichero, `MADGRID_*`, flags): ver `config.rs`
 
+mod config;
+mod history;
+mod ingest;
 mod models;
+mod region;
+mod secrets;
 mod server;
+mod source;
+mod state;
+mod status;
+mod tuning;
 mod h3grid;
 mod clusterizador;
+mod tiles;
 
 
 use anyhow::{Context, Result};
 use once_cell::sync::Lazy;
 use reqwest::Client;
-use std::{sync::Arc, time::Duration};
-use tokio::{signal, sync::RwLock, time::sleep};
-use tracing::{info, warn, Level};
+use std::{collections::BTreeMap, sync::Arc, time::{Duration, Instant}};
+use tokio::{signal, time::sleep};
+use tracing::{info, info_span, warn, Instrument, Level};
 
 use chrono::NaiveDate;
-use models::types::{AppCfg, DataState, DelayCfg};
-use models::h3types::{ DelayCfg as ODDelayCfg,ODRecord,TomTomClient};
+use h3o::Resolution;
+use models::types::{AppCfg, DelayCfg, DiffSummary, IngestReport, SnapshotEvent, SnapshotInfo};
+use models::h3types::{ODRecord, OdFlow, TomTomClient};
+use history::ParquetSink;
+use region::{Region, Regions};
+use state::{SavedFetch, SavedRows, SavedSnapshot, StateDir};
+use status::{ComputeError, CountingProvider, FetchResult, FetchStatus, TrackedSink};
 use h3grid::{
-    compute_day, HistorySink, JsonlSink, OrionLdSink,
+    build_kpis, compute_day, diff_metrics, persist_day, update_day, FanoutSink, HistorySink, JsonlSink, OrionLdSink,
     TrafficProvider,load_roadmap_csv
 };
 
+/// Snapshots que se conservan en `DataState::snapshot_log`
+const SNAPSHOT_LOG_LEN: usize = 100;
+
 #[allow(dead_code)]
-static CFG: Lazy<DelayCfg> = Lazy::new(|| DelayCfg::default());
+static CFG: Lazy<DelayCfg> = Lazy::new(DelayCfg::default);
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -31,28 +50,43 @@ async fn main() -> Result<()> {
         .with_max_level(Level::INFO)
         .init();
 
-    // Carga config por defecto desde tu models::types::AppCfg::default()
-    let cfg = AppCfg::default();
+    // Config por capas: defaults -> fichero -> MADGRID_* -> flags
+    let settings = config::load().context("configuración inválida")?;
+    match &settings.source {
+        Some(p) => info!("Configuración cargada de {}", p.display()),
+        None => info!("Sin fichero de configuración; defaults + entorno + flags"),
+    }
+    let cfg = settings.app.clone();
+
+    // Estado por región (DataState + DelayCfg publicado)
+    let regions = Arc::new(Regions::new(&settings.regions));
 
-    // Estado compartido para la API
-    let data = Arc::new(RwLock::new(DataState {
-        delay_cfg: DelayCfg::default(), // si tu DataState lo sigue usando para algo
-        ..Default::default()
-    }));
+    // DelayCfg H3 en caliente: fichero vigilado + /admin/delay-cfg
+    if cfg.reload_watch_s > 0 {
+        let every = Duration::from_secs(cfg.reload_watch_s);
+        tokio::spawn(tuning::watch_config_file(settings.clone(), regions.clone(), every));
+    }
 
-    // HTTP client con compresion
-    let client = Client::builder().brotli(true).gzip(true).deflate(true).build()?;
+    // HTTP client con compresion y los timeouts de [fetch]
+    let client = server::fetch::client(&cfg.fetch)?;
 
-    // Lanza el loop de O/D -> compute_day -> actualizar estado
-    {
-        let data_c = data.clone();
+    // Lanza un loop de O/D -> compute_day -> actualizar estado por región
+    for region in regions.iter() {
         let client_c = client.clone();
         let cfg_c = cfg.clone();
-        tokio::spawn(async move { fetch_loop_od(client_c, data_c, cfg_c).await; });
+        let region_c = region.clone();
+        let span = info_span!("region", id = %region.id());
+        info!("Región {} -> {}", region.id(), region.cfg.od_url);
+        tokio::spawn(async move { fetch_loop_od(client_c, region_c, cfg_c).await }.instrument(span));
     }
 
     // API
-    let app = server::api::router(server::api::ApiState { data: data.clone() });
+    let app = server::api::router(server::api::ApiState {
+        regions: regions.clone(),
+        admin_token: secrets::resolve("admin_token", cfg.admin_token.as_ref()),
+        orders: Default::default(),
+        ready_max_age_s: cfg.ready_max_age_s,
+    });
     info!("Escuchando en http://{}", cfg.bind);
     let listener = tokio::net::TcpListener::bind(&cfg.bind).await?;
     let serve = axum::serve(listener, app);
@@ -67,90 +101,404 @@ async fn main() -> Result<()> {
 // --------------------------------------
 // Loop OD: descarga -> parse -> compute_day -> estado
 // --------------------------------------
-async fn fetch_loop_od(client: Client, data: Arc<RwLock<DataState>>, cfg: AppCfg) {
-    let mut cache = server::fetch::CacheCtl::default();
-
-    // Mapear AppCfg -> DelayCfg del h3grid
-    let mut od_cfg = ODDelayCfg::default();
-    od_cfg.res = cfg.h3_res;
-    od_cfg.min_conf_for_pure_orange = cfg.min_conf_orange;
-    od_cfg.max_concurrent_calls = cfg.max_concurrent;
+async fn fetch_loop_od(client: Client, region: Arc<Region>, cfg: AppCfg) {
+    let rcfg = &region.cfg;
+    let data = &region.data;
+    let mut cfg_rx = region.tuning.subscribe();
 
     // 1) Cargar roadmap CSV (una vez)
-    let road_map = load_roadmap_csv("data/hex_road_map_logrono.csv").ok();
-    // Provider TomTom (opcional)
-    let tomtom: Option<TomTomClient> = cfg
-    .tomtom_key
-    .clone()
-    .map(|key| TomTomClient::new(key, road_map.clone()));
-
-    // Sinks (opcional): prioriza Orion si está, si no JSONL
-    let orion = cfg
+    let road_map = rcfg.roadmap_csv.as_deref().and_then(|p| match load_roadmap_csv(p) {
+        Ok(m) => Some(m),
+        Err(e) => {
+            warn!("roadmap {p}: {e:#}");
+            None
+        }
+    });
+    // Provider TomTom (opcional): sin clave configurada -> modo "sin proveedor"
+    let tomtom: Option<TomTomClient> = secrets::resolve("tomtom_key", cfg.tomtom_key.as_ref())
+        .map(|key| TomTomClient::new(key, road_map.clone()));
+    if tomtom.is_none() {
+        info!("TomTom desactivado: solo delay Orange");
+    }
+
+    // Sinks (opcional): Orion, JSONL, SQLite y/o Parquet local (el SQLite, o si no el JSONL, alimenta /history)
+    let orion = rcfg
         .orion_url
         .as_ref()
-        .map(|url| OrionLdSink::new(url.clone(), cfg.orion_tenant.clone(), None));
-    let jsonl = cfg.jsonl_out.as_ref().map(|p| JsonlSink::new(p));
+        .map(|url| {
+            let token = secrets::resolve("orion_token", rcfg.orion_token.as_ref());
+            OrionLdSink::new(url.clone(), rcfg.orion_tenant.clone(), token)
+        });
+    let jsonl = rcfg.jsonl_out.as_ref().map(JsonlSink::new);
+    let parquet = rcfg.parquet_out.as_ref().map(ParquetSink::new);
+
+    // TomTom y sinks envueltos para contar llamadas y fallos en `/status`
+    let provider = tomtom.as_ref().map(|t| CountingProvider::new(t));
+    let tracked: Vec<TrackedSink> = [
+        ("orion", orion.as_ref().map(|o| o as &dyn HistorySink)),
+        ("jsonl", jsonl.as_ref().map(|j| j as &dyn HistorySink)),
+        ("sqlite", region.sqlite.as_deref().map(|s| s as &dyn HistorySink)),
+        ("parquet", parquet.as_ref().map(|p| p as &dyn HistorySink)),
+    ]
+    .into_iter()
+    .filter_map(|(name, s)| s.map(|s| TrackedSink::new(name, s)))
+    .collect();
+
+    // Último O/D descargado: permite recalcular al cambiar el DelayCfg sin volver a descargar.
+    // Con marca de agua acumula todas las filas ingeridas y `fresh` guarda las del último lote.
+    let mut last_od: Option<(Vec<ODRecord>, IngestReport)> = None;
+    let mut fresh: Option<(Vec<ODRecord>, IngestReport)> = None;
+    let mut watermark: Option<i64> = None;
+    // Tras un fallo el snapshot publicado no incluye todo lo ingerido: el siguiente cálculo es completo
+    let mut force_full = false;
+    let mut cfg_changed = false;
+    // Un O/D en gzip no puede inflar más de lo que se acepta descargar
+    let max_inflated = cfg.fetch.max_body_mb.saturating_mul(1 << 20);
+
+    let mut source = match source::open(&rcfg.od_url, &client, &rcfg.od_s3, &cfg.fetch) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("O/D {}: {e:#}", rcfg.od_url);
+            data.write().await.last_error = Some(format!("{e:#}"));
+            return;
+        }
+    };
+
+    // Estado del arranque anterior: se publica el snapshot guardado y, si el O/D guardado es de esta
+    // `od_url`, se recuperan sus filas y la caché de la fuente (sin descargar ni consultar TomTom)
+    let state = cfg.state_dir.as_deref().map(|root| StateDir::new(root, region.id()));
+    if let Some(st) = &state {
+        let snapshot = st.load_snapshot();
+        let current = region.tuning.current();
+        // El snapshot vale tal cual solo si salió de ese mismo O/D y con el DelayCfg vigente
+        let mut stale = true;
+        if let Some((saved, payload)) = st.load_fetch().filter(|(s, _)| s.od_url == rcfg.od_url) {
+            let mut report = IngestReport {
+                ts_utc: chrono::Utc::now().to_rfc3339(),
+                source: rcfg.od_url.clone(),
+                file: Some(saved.file.clone()),
+                ..Default::default()
+            };
+            let rules = ingest::OdRules::new(rcfg.od_max_age_days, rcfg.od_max_future_days, max_inflated);
+            // Ingesta incremental: el payload es solo el último lote, se restauran las filas acumuladas
+            let parsed = match saved.accumulated.then(|| st.load_rows(&saved)) {
+                Some(Some(mut saved_rows)) => {
+                    rules.prune(&mut saved_rows.rows);
+                    report = saved_rows.report;
+                    Ok(saved_rows.rows)
+                }
+                Some(None) => Err(anyhow::anyhow!("faltan las filas acumuladas")),
+                None => ingest::parse_od(&payload, saved.content_type.as_deref(), &rcfg.od_columns, &rules, &mut report),
+            };
+            match parsed {
+                Ok(rows) => {
+                    info!("estado: O/D {} restaurado ({} filas)", saved.file.name, rows.len());
+                    source.restore(&saved.source);
+                    watermark = report.watermark;
+                    report.quarantined.clear();
+                    stale = snapshot
+                        .as_ref()
+                        .is_none_or(|s| s.od_sha256() != Some(saved.file.sha256.as_str()) || s.delay_cfg != current.cfg);
+                    last_od = Some((rows, report));
+                }
+                Err(e) => warn!("estado: O/D {}: {e:#}", saved.file.name),
+            }
+        }
+        if let Some(s) = snapshot {
+            info!("estado: snapshot {} restaurado ({} celdas)", s.snapshot_id, s.rows.len());
+            let mut d = data.write().await;
+            s.restore(&mut d);
+            d.cfg_version = current.version;
+        }
+        // Si no corresponde al O/D o al DelayCfg actuales, se recalcula ya con el O/D guardado
+        cfg_changed = stale && last_od.is_some();
+    }
 
     loop {
-        if let Err(e) = async {
-            // 1) DESCARGA O/D (CSV)
-            let od_url = &cfg.od_url;
-            if let Some(bytes) =
-                server::fetch::get_with_cache(&client, od_url, &mut cache).await?
-            {
-                // 2) PARSE CSV -> Vec<ODRecord>
-                let mut rdr =
-                    csv::ReaderBuilder::new().has_headers(true).from_reader(&*bytes);
-                let mut od_rows: Vec<ODRecord> = Vec::new();
-
-                for rec in rdr.deserialize::<ODRecord>() {
-                    let mut r = rec.context("OD CSV parse")?;
-                    if !valid_date(&r.date) {
-                        r.date = chrono::Utc::now().date_naive();
+        // Un fallo de la fuente no cuenta como error de cálculo en `/status`
+        let mut fetch_failed = false;
+        let r = async {
+            // 1) DESCARGA O/D (HTTP, directorio o S3, según `od_url`)
+            let polled = source.poll().await;
+            let (result, file, error) = match &polled {
+                Ok(Some(f)) => (FetchResult::New, Some(f.info.clone()), None),
+                Ok(None) => (FetchResult::NotModified, None, None),
+                Err(e) => (FetchResult::Error, None, Some(format!("{e:#}"))),
+            };
+            data.write().await.status.last_fetch = Some(FetchStatus { ts_utc: chrono::Utc::now(), result, file, error });
+            fetch_failed = polled.is_err();
+            if let Some(file) = polled? {
+                info!("O/D: {} ({} bytes, sha256 {})", file.info.name, file.info.bytes, file.info.sha256);
+                // 2) PARSE (CSV, TSV, Parquet o gzip; se detecta) -> Vec<ODRecord>
+                let incremental = rcfg.od_columns.watermark.is_some() && watermark.is_some() && last_od.is_some();
+                let mut report = IngestReport {
+                    ts_utc: chrono::Utc::now().to_rfc3339(),
+                    source: rcfg.od_url.clone(),
+                    file: Some(file.info.clone()),
+                    incremental,
+                    ..Default::default()
+                };
+                let rules = ingest::OdRules {
+                    watermark: watermark.filter(|_| incremental),
+                    ..ingest::OdRules::new(rcfg.od_max_age_days, rcfg.od_max_future_days, max_inflated)
+                };
+                let parsed =
+                    ingest::parse_od(&file.bytes, file.content_type.as_deref(), &rcfg.od_columns, &rules, &mut report);
+                let ok = parsed.as_ref().is_ok_and(|rows| !rows.is_empty() || report.rows == 0);
+                if let Err(e) = source.done(&file, ok).await {
+                    warn!("O/D {}: {e:#}", file.info.name);
+                }
+                if let Some(st) = state.as_ref().filter(|_| ok) {
+                    // Con marca de agua el fichero puede traer solo su lote (directorio, S3): se guardan
+                    // también las filas acumuladas, que son las que cubre el snapshot
+                    let accumulated = match (&parsed, last_od.as_ref().filter(|_| incremental)) {
+                        (Ok(rows), Some((all, acc))) => {
+                            let mut acc = acc.clone();
+                            if !rows.is_empty() {
+                                acc.absorb(&report);
+                            }
+                            acc.quarantined.clear();
+                            let mut rows = all.iter().chain(rows).cloned().collect();
+                            rules.prune(&mut rows);
+                            Some(SavedRows { sha256: file.info.sha256.clone(), rows, report: acc })
+                        }
+                        _ => None,
+                    };
+                    let saved = SavedFetch {
+                        od_url: rcfg.od_url.clone(),
+                        file: file.info.clone(),
+                        content_type: file.content_type.clone(),
+                        source: source.checkpoint(),
+                        accumulated: accumulated.is_some(),
+                    };
+                    if let Err(e) = st.save_fetch(&saved, &file.bytes, accumulated.as_ref()).await {
+                        warn!("estado: {e:#}");
+                    }
+                }
+                let rows = parsed?;
+                if let Some(path) = &rcfg.quarantine_out {
+                    if let Err(e) = ingest::write_quarantine(path.as_ref(), &report).await {
+                        warn!("cuarentena {path}: {e:#}");
+                    }
+                }
+                report.quarantined.clear();
+                data.write().await.last_ingest = Some(report.clone());
+                watermark = report.watermark.or(watermark);
+                // Sin ninguna fila válida se conserva el snapshot anterior
+                if rows.is_empty() && report.rows > 0 {
+                    anyhow::bail!("O/D sin filas válidas ({} rechazadas, p.ej. {})", report.rejected,
+                        report.errors.first().map(String::as_str).unwrap_or("-"));
+                }
+                match last_od.as_mut().filter(|_| incremental) {
+                    Some((all, acc)) => {
+                        // Lo acumulado envejece: se aplica el mismo `od_max_age_days` que al ingerir, y el
+                        // snapshot se recalcula entero para que desaparezcan también los días viejos
+                        let pruned = rules.prune(all);
+                        if pruned > 0 {
+                            info!("O/D: {pruned} filas acumuladas pasan de od_max_age_days");
+                            force_full = true;
+                        }
+                        if rows.is_empty() {
+                            info!("O/D: sin filas nuevas ({} ya ingeridas, marca de agua {watermark:?})", report.skipped);
+                            if !cfg_changed && !force_full {
+                                return Ok(());
+                            }
+                        } else {
+                            all.extend(rows.iter().cloned());
+                            acc.absorb(&report);
+                            fresh = Some((rows, report));
+                        }
+                    }
+                    None => {
+                        last_od = Some((rows, report));
+                        fresh = None;
                     }
-                    od_rows.push(r);
                 }
+            } else if !cfg_changed && !force_full {
+                // Sin O/D nuevo solo se recalcula por cambio de DelayCfg o para reintentar un cálculo fallido
+                return Ok(());
+            }
+            cfg_changed = false;
+            let Some((od_rows, parse_report)) = last_od.as_ref() else { return Ok(()) };
+
+            // 3) EXEC COMPUTE-DAY con la versión vigente del DelayCfg
+            let version = cfg_rx.borrow_and_update().clone();
+
+            // Incremental: solo si el snapshot actual salió de este mismo DelayCfg y de todo lo anterior
+            let fresh = fresh.take();
+            let base = {
+                let d = data.read().await;
+                (fresh.is_some() && !force_full && d.cfg_version == version.version && !d.days.is_empty())
+                    .then(|| (d.days.clone(), d.od_flows.clone()))
+            };
 
-                // 3) EXEC COMPUTE-DAY
-                let date: NaiveDate = od_rows
-                    .get(0)
-                    .map(|r| r.date)
-                    .unwrap_or_else(|| chrono::Utc::now().date_naive());
-
-                let provider_ref: Option<&dyn TrafficProvider> =
-                    tomtom.as_ref().map(|t| t as &dyn TrafficProvider);
-                let sink_orion: Option<&dyn HistorySink> =
-                    orion.as_ref().map(|o| o as &dyn HistorySink);
-                let sink_jsonl: Option<&dyn HistorySink> =
-                    jsonl.as_ref().map(|j| j as &dyn HistorySink);
-                let sink = sink_orion.or(sink_jsonl);
-
-                let (_map, geojson) =
-                    compute_day(date, &od_rows, &od_cfg, provider_ref, sink)
+            // Normaliza a la resolución vigente (puede cambiar con el DelayCfg): padres, hijas o lat/lon
+            // (en incremental, solo las filas del lote nuevo y con su informe)
+            let (to_normalize, mut report) = match (&base, &fresh) {
+                (Some(_), Some((rows, rep))) => (rows, rep.clone()),
+                _ => (od_rows, parse_report.clone()),
+            };
+            let od_rows = ingest::normalize_od(to_normalize, Resolution::try_from(version.cfg.res)?, road_map.as_ref(), &mut report);
+            if report.rejected > 0 {
+                warn!(
+                    "O/D: {} filas rechazadas de {} (p.ej. {})",
+                    report.rejected,
+                    report.rows,
+                    report.errors.first().map(String::as_str).unwrap_or("-")
+                );
+            }
+            info!(
+                "O/D: {} filas, {} aceptadas, {} normalizadas, {} descartadas, {} rechazadas{}",
+                report.rows, report.accepted, report.normalized, report.dropped, report.rejected,
+                if base.is_some() { " (incremental)" } else { "" }
+            );
+
+            // Un fichero puede traer varios días: se calcula y persiste cada uno bajo su fecha.
+            // En incremental se parte de los flujos y días ya publicados y solo se tocan las fechas nuevas
+            let (mut days, mut by_date) = match &base {
+                Some((days, flows)) => (days.clone(), (**flows).clone()),
+                None => (BTreeMap::new(), BTreeMap::new()),
+            };
+            let mut touched: BTreeMap<NaiveDate, Vec<OdFlow>> = BTreeMap::new();
+            for f in od_rows {
+                by_date.entry(f.date).or_default().push(f.clone());
+                touched.entry(f.date).or_default().push(f);
+            }
+            if by_date.is_empty() {
+                by_date.insert(chrono::Utc::now().date_naive(), Vec::new());
+                touched.insert(chrono::Utc::now().date_naive(), Vec::new());
+            }
+
+            let provider_ref: Option<&dyn TrafficProvider> =
+                provider.as_ref().map(|t| t as &dyn TrafficProvider);
+            let sinks = FanoutSink { sinks: tracked.iter().map(|t| t as &dyn HistorySink).collect() };
+            let sink = (!sinks.sinks.is_empty()).then_some(&sinks as &dyn HistorySink);
+
+            // Id del snapshot (ms unix, estrictamente creciente); va también en las filas históricas
+            let snapshot_id = (chrono::Utc::now().timestamp_millis() as u64).max(data.read().await.snapshot_id + 1);
+            let started = Instant::now();
+            for (date, new) in &touched {
+                let day = match days.get(date).filter(|_| base.is_some()) {
+                    Some(prev) => {
+                        let day = update_day(prev, new, &by_date[date], &version.cfg, provider_ref)
+                            .await
+                            .with_context(|| format!("update_day failed ({date})"))?;
+                        persist_day(&day, *date, snapshot_id, sink).await?;
+                        day
+                    }
+                    None => compute_day(*date, snapshot_id, &by_date[date], &version.cfg, provider_ref, sink)
                         .await
-                        .context("compute_day failed")?;
+                        .with_context(|| format!("compute_day failed ({date})"))?,
+                };
+                days.insert(*date, Arc::new(day));
+            }
+            force_full = false;
+
+            // El snapshot publicado (mapa, KPIs, diff, eventos) es el del día más reciente
+            let (date, day) = days.last_key_value().map(|(d, r)| (*d, r.clone())).expect("al menos un día");
+            let mut kpis = build_kpis(&day, &by_date[&date]);
+            kpis.snapshot_ts_utc = chrono::Utc::now().to_rfc3339();
+            kpis.date = Some(date);
+            kpis.cfg_version = version.version;
+            kpis.recompute_ms = started.elapsed().as_millis() as u64;
+            kpis.ingest = report.clone();
 
-                // 4) ACTUALIZA ESTADO COMPARTIDO PARA LA API
-                {
-                    let mut d = data.write().await;
-                    d.hex_geojson = geojson;
-                    d.snapshot_ts_utc = chrono::Utc::now().to_rfc3339();
+            // 4) ACTUALIZA ESTADO COMPARTIDO PARA LA API
+            let days_n = by_date.len();
+            let saved = state.as_ref().map(|_| SavedSnapshot::new(snapshot_id, date, &version.cfg, &kpis, &day));
+            let event = {
+                let mut d = data.write().await;
+                let (added, removed, changed) =
+                    diff_metrics(&d.metrics, &day.metrics, cfg.event_delay_threshold);
+                d.hex_geojson = day.geojson.clone();
+                d.metrics = Arc::new(day.metrics.clone());
+                d.hotspots = day.hotspots.clone();
+                d.days = days;
+                d.od_flows = Arc::new(by_date);
+                d.snapshot_ts_utc = kpis.snapshot_ts_utc.clone();
+                d.snapshot_id = snapshot_id;
+                let retained = (d.snapshot_id, d.metrics.clone());
+                d.retained.push_back(retained);
+                while d.retained.len() > cfg.snapshots_retained {
+                    d.retained.pop_front();
+                }
+                d.cfg_version = version.version;
+                d.snapshot_cfg = version.cfg.clone();
+                let info = SnapshotInfo {
+                    id: d.snapshot_id,
+                    snapshot_ts_utc: d.snapshot_ts_utc.clone(),
+                    date,
+                    cfg_version: version.version,
+                    cells: kpis.cells,
+                };
+                d.kpis = kpis;
+                d.snapshot_log.push_back(info);
+                while d.snapshot_log.len() > SNAPSHOT_LOG_LEN {
+                    d.snapshot_log.pop_front();
+                }
+                d.last_error = None;
+                d.last_ingest = Some(report);
+                SnapshotEvent {
+                    region: region.id().to_string(),
+                    snapshot_id: d.snapshot_id,
+                    snapshot_ts_utc: d.snapshot_ts_utc.clone(),
+                    date,
+                    cfg_version: version.version,
+                    cells: d.metrics.len(),
+                    diff: DiffSummary::new(added, removed, changed, cfg.event_delay_threshold),
+                }
+            };
+            if let (Some(st), Some(saved)) = (&state, saved) {
+                if let Err(e) = st.save_snapshot(&saved).await {
+                    warn!("estado: {e:#}");
                 }
-                info!("OD recompute OK: date={date}, cells actualizadas");
             }
+            // Sin suscriptores `send` falla: no es un error
+            let _ = region.events.send(Arc::new(event));
+            info!("OD recompute OK: date={date} ({} días), cfg=v{}, cells actualizadas", days_n, version.version);
             Ok::<_, anyhow::Error>(())
         }
-        .await
+        .await;
         {
-            warn!("od_loop: {e:?}");
+            let mut d = data.write().await;
+            let now = chrono::Utc::now();
+            match &r {
+                Ok(()) => {
+                    d.status.last_success_utc = Some(now);
+                    d.status.consecutive_failures = 0;
+                }
+                Err(e) => {
+                    d.status.consecutive_failures += 1;
+                    if !fetch_failed {
+                        d.status.last_compute_error = Some(ComputeError { ts_utc: now, error: format!("{e:#}") });
+                    }
+                }
+            }
+            d.status.tomtom = provider.as_ref().map(CountingProvider::stats).unwrap_or_default();
+            d.status.sinks = tracked.iter().map(|t| (t.name.to_string(), t.health())).collect();
+        }
+        match r {
+            Err(e) => {
+                // Tras un cálculo fallido `last_od` (y la marca de agua) ya incluyen filas sin publicar: el
+                // siguiente ciclo recalcula entero con `last_od` aunque la fuente no traiga nada nuevo.
+                // Un fallo de la propia fuente no toca ese estado
+                force_full |= !fetch_failed;
+                warn!("od_loop: {e:?}");
+                data.write().await.last_error = Some(format!("{e:#}"));
+            }
+            // Quedan ficheros en la bandeja: el siguiente sin esperar
+            Ok(()) if source.pending() => continue,
+            Ok(()) => {}
         }
 
-        // 5) ESPERA
-        sleep(Duration::from_secs(cfg.t_od_s)).await;
+        // 5) ESPERA: siguiente refresco o cambio de DelayCfg (recálculo inmediato)
+        tokio::select! {
+            _ = sleep(Duration::from_secs(rcfg.t_od_s)) => {}
+            Ok(()) = cfg_rx.changed() => {
+                info!("DelayCfg v{} publicado -> recálculo inmediato", cfg_rx.borrow().version);
+                cfg_changed = true;
+            }
+        }
     }
 }
 
-#[inline]
-fn valid_date(d: &NaiveDate) -> bool {
-    d >= &NaiveDate::from_ymd_opt(1971, 1, 1).unwrap()
-}
@@ -3,9 +3,13 @@ use serde_with::{serde_as, DisplayFromStr};
 use chrono::NaiveDate;
 use h3o::CellIndex;
 use std::time::Duration;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+use crate::secrets::Secret;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct DelayCfg {
     /// Resolución H3 de trabajo (p.ej. 7 ~ 1 km²)
     pub res: u8,
@@ -29,9 +33,6 @@ pub struct DelayCfg {
     pub truck_factor: f32,
     pub car_factor: f32,
 
-    /// Mostrar solo delays > 1 + eps en GeoJSON
-    pub show_eps: f32,
-
      // --- nuevo ---
      pub bpr_a: f32,
      pub bpr_b: f32,
@@ -39,6 +40,10 @@ pub struct DelayCfg {
      pub capacity_percentile: f32,
      pub capacity_floor: f32,
      pub vc_cap: f32,
+
+    /// Peso del tráfico de paso (celdas entre origen y destino) en la carga del BPR.
+    /// 0 = sin tráfico de paso (no se calculan rutas)
+    pub through_weight: f32,
 }
 
 /// Registro de O/D para un día (csv/parquet)
@@ -46,10 +51,24 @@ pub struct DelayCfg {
 pub struct ODRecord {
     /// Fecha del día (naive, sin TZ) — recomendado: "YYYY-MM-DD"
     pub date: NaiveDate,
-    /// Celda origen (ID H3 en texto). Se asume a la misma resolución que `cfg.res`
+    /// Celda origen (ID H3 en texto). Puede ir a otra resolución: se normaliza a `cfg.res`
+    #[serde(default)]
     pub origin_h3: String,
     /// Celda destino (ID H3 en texto).
+    #[serde(default)]
     pub dest_h3: String,
+    /// Alternativa a la celda: coordenadas WGS84 del origen/destino
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_lat: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_lon: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dest_lat: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dest_lon: Option<f64>,
+    /// Hora del día (0..=23) si el dato viene por franjas; sin ella son totales diarios
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hour: Option<u8>,
     /// Conteos diarios
     pub n_trucks: f32,
     pub n_cars: f32,
@@ -57,6 +76,19 @@ pub struct ODRecord {
     pub conf: Option<f32>,
 }
 
+/// Registro O/D ya normalizado a `cfg.res`: cada extremo es un reparto en celdas
+/// con pesos que suman 1 (una sola celda salvo que llegara a una resolución más gruesa)
+#[derive(Clone, Debug)]
+pub struct OdFlow {
+    pub date: NaiveDate,
+    pub origin: Vec<(CellIndex, f32)>,
+    pub dest: Vec<(CellIndex, f32)>,
+    pub n_trucks: f32,
+    pub n_cars: f32,
+    pub conf: Option<f32>,
+    pub hour: Option<u8>,
+}
+
 #[derive(Clone, Debug)]
 pub struct H3Metrics {
     pub cell: CellIndex,
@@ -65,6 +97,12 @@ pub struct H3Metrics {
     pub trips_total: f32,
     pub trips_trucks: f32,
     pub trips_cars: f32,
+    /// Reparto de `trips_total`: salen a otra celda, llegan de otra celda, empiezan y acaban aquí
+    pub trips_out: f32,
+    pub trips_in: f32,
+    pub trips_intra: f32,
+    /// Viajes que cruzan la celda (camino H3 entre origen y destino); solo con `through_weight > 0`
+    pub trips_through: f32,
 
     // confianza (media ponderada por volumen)
     pub conf_sum: f32,
@@ -80,18 +118,50 @@ pub struct H3Metrics {
     pub vol_norm: f32,
 }
 
+/// Resultado de `compute_day`
+#[derive(Clone, Debug)]
+pub struct DayResult {
+    pub metrics: HashMap<CellIndex, H3Metrics>,
+    pub geojson: String,
+    /// Celdas hotspot detectadas (a `cfg.res`; subdivididas si había proveedor)
+    pub hotspots: Vec<CellIndex>,
+    /// Métricas por hora (0..=23) a `cfg.res`, solo modelo Orange
+    pub hours: BTreeMap<u8, HashMap<CellIndex, H3Metrics>>,
+    /// `true` si el O/D traía horas; si no, `hours` sale del perfil diario por defecto
+    pub hourly_input: bool,
+    /// Volúmenes agregados a `cfg.res` antes de delays y subdivisión (base de `update_day`)
+    pub aggregates: HashMap<CellIndex, H3Metrics>,
+    /// Capacidad y volumen medio del BPR con los que se calculó el día
+    pub capacity: f32,
+    pub mean_vol: f32,
+}
+
 /// Fila histórica por celda (para sinks)
 
 #[serde_as]
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct H3DailyRow {
     pub date: NaiveDate,
     #[serde_as(as = "DisplayFromStr")]
     pub h3: CellIndex,
     pub res: u8,
+    /// Id del snapshot que produjo la fila (0 en históricos anteriores)
+    #[serde(default)]
+    pub snapshot: u64,
+    /// Hora del día (0..=23) en filas horarias; sin ella, la fila es del día completo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hour: Option<u8>,
     pub trips_total: f32,
     pub trips_trucks: f32,
     pub trips_cars: f32,
+    #[serde(default)]
+    pub trips_out: f32,
+    #[serde(default)]
+    pub trips_in: f32,
+    #[serde(default)]
+    pub trips_intra: f32,
+    #[serde(default)]
+    pub trips_through: f32,
     pub truck_share: f32,
     pub vol_norm: f32,
     pub conf_cell: f32,
@@ -104,7 +174,7 @@ pub struct H3DailyRow {
 /// Cliente TomTom Flow Segment Data (FSD)
 pub struct TomTomClient {
     pub(crate) http: reqwest::Client,
-    pub api_key: String,
+    pub api_key: Secret,
     /// Endpoint base, p.ej: "https://api.tomtom.com/traffic/services/4/flowSegmentData/absolute/10/json"
     pub base_url_absolute: String,
     /// Timeout para cada request
@@ -112,6 +182,17 @@ pub struct TomTomClient {
     pub road_map: Option<HashMap<CellIndex, RoadCell>>,
 }
 
+impl fmt::Debug for TomTomClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TomTomClient")
+            .field("api_key", &self.api_key)
+            .field("base_url_absolute", &self.base_url_absolute)
+            .field("timeout", &self.timeout)
+            .field("road_cells", &self.road_map.as_ref().map(|m| m.len()))
+            .finish()
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct RoadCell {
@@ -2,7 +2,15 @@
 //! Modelos de datos compartidos por el servicio: entradas (sensores/incidencias)
 //! configuración del calculo, KPIs y salidas 
 
+use chrono::NaiveDate;
+use h3o::CellIndex;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+use crate::models::h3types::{DayResult, H3Metrics, OdFlow};
+use crate::secrets::SecretSource;
+use crate::status::LoopStatus;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ParkingZone {
@@ -38,8 +46,124 @@ pub struct SensorTr {
     pub ts_ms: i64,
 }
 
-#[derive(Clone, Debug, Default, Serialize)]
-pub struct Kpis { pub carga: usize, pub inc: usize }
+/// KPIs del snapshot, calculados sobre las `H3Metrics` retenidas (ver `h3grid::build_kpis`)
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Kpis {
+    pub snapshot_ts_utc: String,
+    pub date: Option<NaiveDate>,
+    pub cfg_version: u64,
+    /// Celdas publicadas y su reparto por resolución (los hotspots subdivididos van a res+1)
+    pub cells: usize,
+    pub cells_by_res: BTreeMap<u8, usize>,
+    pub delay_final: DelayStats,
+    /// Fracción de celdas con delay de TomTom (0..1)
+    pub tomtom_share: f32,
+    /// Confianza media por celda (0..1)
+    pub conf_mean: f32,
+    pub hotspots: usize,
+    /// Totales del O/D de entrada
+    pub trucks_total: f32,
+    pub cars_total: f32,
+    pub recompute_ms: u64,
+    pub geojson_bytes: usize,
+    /// Resumen de la ingesta del lote O/D usado
+    pub ingest: IngestReport,
+}
+
+/// Errores de fila que se guardan como muestra en `IngestReport`
+pub const INGEST_MAX_ERRORS: usize = 20;
+
+/// Resumen de un lote O/D: filas leídas y qué se hizo con cada una.
+/// `accepted + normalized + dropped + rejected == rows`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IngestReport {
+    /// Descarga que produjo el lote
+    pub ts_utc: String,
+    pub source: String,
+    pub rows: usize,
+    /// Usadas tal cual (celdas ya a `cfg.res`)
+    pub accepted: usize,
+    /// Con algún extremo subido al padre, repartido entre hijas o calculado desde lat/lon
+    pub normalized: usize,
+    /// Sin volumen: no aportan nada
+    pub dropped: usize,
+    /// Inválidas (campos, H3 o coordenadas); se descartan sin abortar el lote
+    pub rejected: usize,
+    /// Muestra de errores (como mucho `INGEST_MAX_ERRORS`)
+    pub errors: Vec<String>,
+    /// Filas ya ingeridas (marca de agua <= la anterior): se saltan sin leerlas; no cuentan en `rows`
+    pub skipped: usize,
+    /// Mayor marca de agua vista (la anterior si el lote no trae nada nuevo)
+    pub watermark: Option<i64>,
+    /// `true` si el lote se sumó al snapshot anterior en vez de recalcular todo
+    pub incremental: bool,
+    /// Fichero del que sale el lote (auditoría)
+    pub file: Option<SourceFile>,
+    /// Filas apartadas en la lectura, para el fichero de cuarentena
+    #[serde(skip)]
+    pub quarantined: Vec<QuarantineRow>,
+}
+
+/// Fichero O/D entregado por la fuente (`source::OdSource`): nombre, tamaño y SHA-256
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceFile {
+    /// URL, ruta en el directorio o clave S3
+    pub name: String,
+    pub bytes: usize,
+    /// Hex en minúsculas
+    pub sha256: String,
+}
+
+/// Fila O/D rechazada al leer: número de fila (1 = primera de datos), motivo y contenido
+#[derive(Clone, Debug, Serialize)]
+pub struct QuarantineRow {
+    pub row: usize,
+    pub error: String,
+    pub raw: Option<String>,
+}
+
+impl IngestReport {
+    pub fn reject(&mut self, msg: String) {
+        self.rejected += 1;
+        if self.errors.len() < INGEST_MAX_ERRORS {
+            self.errors.push(msg);
+        }
+    }
+
+    /// Acumula los conteos de lectura de un lote incremental (`rows`, `rejected`, errores);
+    /// el resto de campos pasan a ser los del lote nuevo
+    pub fn absorb(&mut self, o: &IngestReport) {
+        self.rows += o.rows;
+        self.rejected += o.rejected;
+        let room = INGEST_MAX_ERRORS.saturating_sub(self.errors.len());
+        self.errors.extend(o.errors.iter().take(room).cloned());
+        self.ts_utc = o.ts_utc.clone();
+        self.source = o.source.clone();
+        self.skipped = o.skipped;
+        self.watermark = o.watermark;
+        self.incremental = o.incremental;
+        self.file = o.file.clone();
+    }
+
+    /// Rechaza una fila leída y la guarda para la cuarentena
+    pub fn quarantine(&mut self, row: usize, error: String, raw: Option<String>) {
+        self.reject(format!("fila {row}: {error}"));
+        self.quarantined.push(QuarantineRow { row, error, raw });
+    }
+}
+
+/// Distribución de `delay_final` entre celdas
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DelayStats {
+    pub min: f32,
+    pub mean: f32,
+    pub p50: f32,
+    pub p90: f32,
+    pub max: f32,
+}
 
 #[derive(Clone, Debug, Serialize)]
 pub struct DelayCfg {
@@ -90,13 +214,140 @@ impl Default for DelayCfg {
     }
 }
 
-#[derive(Clone, Debug)]
+/// Nombres de columna del fichero O/D (CSV, TSV o Parquet) para cada campo de `ODRecord`.
+/// Por defecto, los nombres canónicos; p.ej. para los exports de Orange: `date = "fecha"`,
+/// `origin_h3 = "origen"`, `dest_h3 = "destino"`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OdColumns {
+    pub date: String,
+    pub origin_h3: String,
+    pub dest_h3: String,
+    /// Alternativa a las celdas: coordenadas WGS84 de cada extremo
+    pub origin_lat: String,
+    pub origin_lon: String,
+    pub dest_lat: String,
+    pub dest_lon: String,
+    pub n_trucks: String,
+    pub n_cars: String,
+    /// Opcional en el fichero: si no está, `conf` queda vacío
+    pub conf: String,
+    /// Opcional: hora del día (`8`, `08` o `08:00`); sin ella, los conteos son diarios
+    pub hour: String,
+    /// Formato `chrono` de la fecha cuando llega como texto
+    pub date_format: String,
+    /// Opcional: columna marca de agua (timestamp o id de lote creciente). Con ella la ingesta
+    /// es incremental: solo se leen las filas con marca mayor que la última ingerida
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<String>,
+}
+
+impl Default for OdColumns {
+    fn default() -> Self {
+        Self {
+            date: "date".into(),
+            origin_h3: "origin_h3".into(),
+            dest_h3: "dest_h3".into(),
+            origin_lat: "origin_lat".into(),
+            origin_lon: "origin_lon".into(),
+            dest_lat: "dest_lat".into(),
+            dest_lon: "dest_lon".into(),
+            n_trucks: "n_trucks".into(),
+            n_cars: "n_cars".into(),
+            conf: "conf".into(),
+            hour: "hour".into(),
+            date_format: "%Y-%m-%d".into(),
+            watermark: None,
+        }
+    }
+}
+
+/// Endpoint S3 compatible (AWS, MinIO...) para `od_url = "s3://bucket/prefijo/*.csv"`.
+/// Se accede con rutas `endpoint/bucket/clave` y firma SigV4; sin claves, peticiones anónimas.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OdS3Cfg {
+    /// p.ej. `https://s3.eu-west-1.amazonaws.com` o `http://127.0.0.1:9000`
+    pub endpoint: String,
+    pub region: String,
+    pub access_key: Option<SecretSource>,
+    pub secret_key: Option<SecretSource>,
+}
+
+impl Default for OdS3Cfg {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://127.0.0.1:9000".into(),
+            region: "us-east-1".into(),
+            access_key: None,
+            secret_key: None,
+        }
+    }
+}
+
+/// Descargas HTTP (`od_url` http(s) y objetos S3): reintentos, timeouts y tamaño máximo
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FetchCfg {
+    /// Reintentos tras un fallo transitorio (red, timeout, 408/429/5xx, cuerpo truncado o corrupto)
+    pub retries: u32,
+    /// Espera antes del primer reintento; se dobla en cada uno (con jitter) hasta `backoff_max_ms`
+    pub backoff_ms: u64,
+    pub backoff_max_ms: u64,
+    pub connect_timeout_s: u64,
+    /// Máximo sin recibir datos (no limita la duración total de una descarga grande)
+    pub read_timeout_s: u64,
+    /// Tamaño máximo del cuerpo en MiB, comprimido y descomprimido (0 = sin límite)
+    pub max_body_mb: u64,
+    /// Directorio de los temporales de descarga (por defecto el del sistema)
+    pub tmp_dir: Option<String>,
+}
+
+impl Default for FetchCfg {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff_ms: 500,
+            backoff_max_ms: 30_000,
+            connect_timeout_s: 10,
+            read_timeout_s: 60,
+            max_body_mb: 512,
+            tmp_dir: None,
+        }
+    }
+}
+
+/// Configuración del servicio. Se construye por capas en `config::load`
+/// (defaults -> fichero -> `MADGRID_*` -> flags CLI).
+/// No contiene secretos: solo referencias `SecretSource`, así que su `Debug` es seguro.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct AppCfg {
     /// Dirección/puerto del servidor HTTP (Axum)
     pub bind: String,
 
-    /// Fuente diaria O/D (CSV o CSV-proxy de Parquet)
+    /// Id de la región implícita cuando no hay `[[regions]]`
+    pub region_id: String,
+
+    /// Fuente diaria O/D (CSV, TSV, Parquet o CSV gzip; se detecta al descargar):
+    /// `http(s)://...`, `file:///dir/*.csv` (directorio vigilado) o `s3://bucket/prefijo/*.csv`
     pub od_url: String,
+    /// Endpoint y credenciales para `od_url = "s3://..."`
+    pub od_s3: OdS3Cfg,
+    /// Reintentos, timeouts y tamaño máximo de las descargas
+    pub fetch: FetchCfg,
+
+    /// Mapeo de columnas del fichero O/D
+    pub od_columns: OdColumns,
+    /// Filas O/D con fecha anterior a hoy menos estos días se rechazan (0 = sin límite)
+    pub od_max_age_days: u32,
+    /// Días de margen para fechas futuras (zona horaria del proveedor)
+    pub od_max_future_days: u32,
+    /// Fichero JSONL donde se apartan las filas O/D rechazadas (opcional)
+    pub quarantine_out: Option<String>,
+
+    /// CSV de roadmap H3 <-> vías OSM (generado con `build_hex_road`)
+    pub roadmap_csv: Option<String>,
 
     /// Periodicidad de refresco del O/D (segundos)
     pub t_od_s: u64,
@@ -110,30 +361,79 @@ pub struct AppCfg {
     /// Concurrencia máxima para llamadas a proveedores externos (TomTom)
     pub max_concurrent: usize,
 
-    /// Clave API TomTom (opcional). Si está ausente, no se consulta TomTom.
-    pub tomtom_key: Option<String>,
+    /// Origen de la clave API TomTom (opcional). Si está ausente, no se consulta TomTom.
+    pub tomtom_key: Option<SecretSource>,
 
     /// Persistencia histórica Orion-LD (opcional)
     pub orion_url: Option<String>,
     pub orion_tenant: Option<String>,
+    /// Origen del bearer token de Orion-LD (opcional)
+    pub orion_token: Option<SecretSource>,
 
-    /// Persistencia histórica JSONL local (opcional). Si se define junto a Orion, prima Orion.
+    /// Persistencia histórica JSONL local (opcional). Se escribe también con Orion y alimenta `/history`.
     pub jsonl_out: Option<String>,
+
+    /// Histórico en fichero SQLite local (opcional). Si está, es el que sirve `/history`.
+    pub sqlite_out: Option<String>,
+    /// Histórico en Parquet particionado (`<dir>/date=YYYY-MM-DD/res=N/part.parquet`, opcional)
+    pub parquet_out: Option<String>,
+    /// Retención del SQLite: días hacia atrás desde la fecha más reciente (0 = sin límite)
+    pub history_keep_days: u32,
+    /// Retención del SQLite: snapshots conservados por día
+    pub history_keep_snapshots: usize,
+
+    /// Estado entre reinicios (opcional): caché de descarga, último O/D leído y último snapshot,
+    /// en `<state_dir>/<región>/`
+    pub state_dir: Option<String>,
+
+    /// Cada cuántos segundos se comprueba si el fichero de config cambió (0 = sin recarga)
+    pub reload_watch_s: u64,
+
+    /// `/health/ready` exige en cada región un snapshot más reciente que esto (0 = sin límite)
+    pub ready_max_age_s: u64,
+
+    /// Token para las rutas `/admin/*` (opcional). Sin token, las rutas admin responden 403.
+    pub admin_token: Option<SecretSource>,
+
+    /// Cambio mínimo de `delay_final` para contar una celda como cambiada en `/events`
+    pub event_delay_threshold: f32,
+
+    /// Snapshots (mapas de `H3Metrics`) que se conservan para `/map/hex/diff`
+    pub snapshots_retained: usize,
 }
 
 impl Default for AppCfg {
     fn default() -> Self {
         Self {
             bind: "0.0.0.0:8080".into(),
+            region_id: "default".into(),
             od_url: "http://localhost:8081/od_today.csv".into(), // ejemplo local
+            od_s3: OdS3Cfg::default(),
+            fetch: FetchCfg::default(),
+            od_columns: OdColumns::default(),
+            od_max_age_days: 30,
+            od_max_future_days: 1,
+            quarantine_out: None,
+            roadmap_csv: Some("data/hex_road_map_logrono.csv".into()),
             t_od_s: 900,                // 15 min por defecto
             h3_res: 7,                  // ~1 km²
             min_conf_orange: 0.65,      // conf telco mínima para no usar TomTom
             max_concurrent: 16,         // paralelismo para TomTom
-            tomtom_key: Some("iHC6Mqg1RZQ7LNpJFm23dV4QKNRi28wl".to_string()),
+            tomtom_key: None,           // sin clave -> modo "sin proveedor"
             orion_url: None,
             orion_tenant: None,
+            orion_token: None,
             jsonl_out: None,
+            sqlite_out: None,
+            parquet_out: None,
+            history_keep_days: 0,
+            history_keep_snapshots: 1,
+            state_dir: None,
+            reload_watch_s: 10,
+            ready_max_age_s: 172_800,   // el O/D suele ser diario: 2 días
+            admin_token: None,
+            event_delay_threshold: 0.05,
+            snapshots_retained: 8,
         }
     }
 }
@@ -141,6 +441,45 @@ impl Default for AppCfg {
 
 
 
+/// Entrada `[[regions]]` del fichero de configuración. Lo que no se define aquí
+/// se hereda de la configuración global (`h3_res`, `t_od_s`, `[delay]`, Orion...).
+/// `jsonl_out`, `sqlite_out`, `parquet_out` y `quarantine_out` no se heredan para no mezclar
+/// ficheros de ciudades distintas.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionCfg {
+    pub id: String,
+    pub od_url: String,
+    /// Sustituye entero al `[od_s3]` global
+    #[serde(default)]
+    pub od_s3: Option<OdS3Cfg>,
+    /// Sustituye entero al `[od_columns]` global (lo no indicado vuelve a los nombres canónicos)
+    #[serde(default)]
+    pub od_columns: Option<OdColumns>,
+    #[serde(default)]
+    pub roadmap_csv: Option<String>,
+    #[serde(default)]
+    pub t_od_s: Option<u64>,
+    #[serde(default)]
+    pub h3_res: Option<u8>,
+    #[serde(default)]
+    pub min_conf_orange: Option<f32>,
+    #[serde(default)]
+    pub jsonl_out: Option<String>,
+    #[serde(default)]
+    pub sqlite_out: Option<String>,
+    #[serde(default)]
+    pub parquet_out: Option<String>,
+    #[serde(default)]
+    pub quarantine_out: Option<String>,
+    #[serde(default)]
+    pub orion_url: Option<String>,
+    #[serde(default)]
+    pub orion_tenant: Option<String>,
+    #[serde(default)]
+    pub orion_token: Option<SecretSource>,
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct RoutingCell {
     pub h3: String,  
@@ -157,11 +496,117 @@ pub struct DataState {
 
     pub hex_geojson: String,
 
+    /// Métricas por celda del snapshot actual (de donde salen KPIs y GeoJSON)
+    #[serde(skip)]
+    pub metrics: Arc<HashMap<CellIndex, H3Metrics>>,
+    /// Hotspots detectados en el snapshot actual (a la resolución base)
+    #[serde(skip)]
+    pub hotspots: Vec<CellIndex>,
+
     pub routing_cells: Vec<RoutingCell>,
 
     pub delay_cfg: DelayCfg,
 
     pub snapshot_ts_utc: String,
+    /// Id del snapshot actual (ms unix, estrictamente creciente; 0 = sin snapshot)
+    pub snapshot_id: u64,
+
+    /// Versión del DelayCfg H3 que produjo el snapshot actual
+    pub cfg_version: u64,
+    /// Ese DelayCfg H3: teselas y filtros del snapshot se pintan con él, no con el vigente
+    #[serde(skip)]
+    pub snapshot_cfg: crate::models::h3types::DelayCfg,
+    /// Últimos snapshots publicados (más reciente al final)
+    pub snapshot_log: VecDeque<SnapshotInfo>,
+    /// Métricas de los últimos `snapshots_retained` snapshots (más reciente al final, incluye el actual)
+    #[serde(skip)]
+    pub retained: VecDeque<(u64, Arc<HashMap<CellIndex, H3Metrics>>)>,
+
+    /// Error del último refresco fallido (se limpia al siguiente OK)
+    pub last_error: Option<String>,
+
+    /// Resumen de la última ingesta O/D (`/ingest/last`)
+    pub last_ingest: Option<IngestReport>,
+
+    /// Resultado por fecha del último lote O/D (`/map/hex?date=`); el más reciente es el snapshot actual
+    #[serde(skip)]
+    pub days: BTreeMap<NaiveDate, Arc<DayResult>>,
+
+    /// Flujos normalizados del último lote O/D por fecha (`/od/flows`)
+    #[serde(skip)]
+    pub od_flows: Arc<BTreeMap<NaiveDate, Vec<OdFlow>>>,
+
+    /// Resultado de la fuente, errores, TomTom y sinks según el loop O/D (`/status`)
+    pub status: LoopStatus,
+}
+
+/// Traza de un snapshot: qué config lo produjo
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotInfo {
+    pub id: u64,
+    pub snapshot_ts_utc: String,
+    pub date: NaiveDate,
+    pub cfg_version: u64,
+    pub cells: usize,
+}
+
+/// Mensaje de `/events` al publicar un snapshot
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotEvent {
+    pub region: String,
+    pub snapshot_id: u64,
+    pub snapshot_ts_utc: String,
+    pub date: NaiveDate,
+    pub cfg_version: u64,
+    pub cells: usize,
+    pub diff: DiffSummary,
+}
+
+/// Resumen del cambio respecto al snapshot anterior
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DiffSummary {
+    pub threshold: f32,
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+    pub added_cells: Vec<String>,
+    pub removed_cells: Vec<String>,
+    pub changed_cells: Vec<CellChange>,
+    /// Las listas se cortan a `EVENT_MAX_CELLS`; los contadores son siempre completos
+    pub truncated: bool,
+}
+
+/// Tope de celdas listadas en cada mensaje de `/events`
+pub const EVENT_MAX_CELLS: usize = 500;
+
+impl DiffSummary {
+    pub fn new(added: Vec<CellIndex>, removed: Vec<CellIndex>, mut changed: Vec<CellChange>, threshold: f32) -> Self {
+        let truncated = added.len().max(removed.len()).max(changed.len()) > EVENT_MAX_CELLS;
+        let (n_added, n_removed, n_changed) = (added.len(), removed.len(), changed.len());
+        changed.truncate(EVENT_MAX_CELLS);
+        Self {
+            threshold,
+            added: n_added,
+            removed: n_removed,
+            changed: n_changed,
+            added_cells: added.iter().take(EVENT_MAX_CELLS).map(|c| c.to_string()).collect(),
+            removed_cells: removed.iter().take(EVENT_MAX_CELLS).map(|c| c.to_string()).collect(),
+            changed_cells: changed,
+            truncated,
+        }
+    }
+}
+
+/// Celda presente en los dos snapshots con valores distintos
+#[derive(Clone, Debug, Serialize)]
+pub struct CellChange {
+    pub h3: String,
+    pub delay_old: f32,
+    pub delay_new: f32,
+    pub conf_old: f32,
+    pub conf_new: f32,
+    pub used_tomtom_old: bool,
+    pub used_tomtom_new: bool,
 }
 
 #[derive(Deserialize)] 
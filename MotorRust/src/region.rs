@@ -0,0 +1,130 @@
+//! region.rs — Regiones (ciudades) servidas por un mismo proceso
+//!
+//! Cada región tiene su fuente O/D, roadmap, `DelayCfg` (recargable en caliente), sinks,
+//! loop de refresco y su propio `DataState`. La API las expone bajo `/regions/{id}/...`;
+//! las rutas sin prefijo apuntan a la primera región (modo de una sola ciudad).
+
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+use tracing::warn;
+
+use crate::config::RegionSettings;
+use crate::history::{HistoryStore, JsonlStore, Retention, SqliteHistory};
+use crate::models::types::{DataState, SnapshotEvent};
+use crate::tiles::TileCache;
+use crate::tuning::DelayTuning;
+
+pub struct Region {
+    pub cfg: RegionSettings,
+    pub data: Arc<RwLock<DataState>>,
+    pub tuning: Arc<DelayTuning>,
+    /// Teselas MVT del snapshot actual
+    pub tiles: TileCache,
+    /// Avisos de snapshot nuevo para `/events`
+    pub events: broadcast::Sender<Arc<SnapshotEvent>>,
+    /// Lectura del histórico persistido (si hay sink local)
+    pub history: Option<Arc<dyn HistoryStore>>,
+    /// Histórico SQLite (sink y store a la vez)
+    pub sqlite: Option<Arc<SqliteHistory>>,
+}
+
+/// Mensajes pendientes por suscriptor antes de marcarlo como rezagado
+const EVENTS_BUFFER: usize = 16;
+
+impl Region {
+    pub fn new(cfg: RegionSettings) -> Self {
+        let tuning = Arc::new(DelayTuning::new(cfg.delay.clone()));
+        let retention = Retention {
+            keep_days: cfg.history_keep_days,
+            keep_snapshots: cfg.history_keep_snapshots,
+        };
+        let sqlite = cfg.sqlite_out.as_ref().and_then(|p| match SqliteHistory::open(p, retention) {
+            Ok(db) => Some(Arc::new(db)),
+            Err(e) => {
+                warn!("[{}] histórico SQLite desactivado: {e:#}", cfg.id);
+                None
+            }
+        });
+        let history = match &sqlite {
+            Some(db) => Some(db.clone() as Arc<dyn HistoryStore>),
+            None => cfg.jsonl_out.as_ref().map(|p| Arc::new(JsonlStore::new(p)) as Arc<dyn HistoryStore>),
+        };
+        Self {
+            cfg,
+            data: Arc::new(RwLock::new(DataState::default())),
+            tuning,
+            tiles: TileCache::default(),
+            events: broadcast::channel(EVENTS_BUFFER).0,
+            history,
+            sqlite,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.cfg.id
+    }
+
+    pub async fn status(&self) -> RegionStatus {
+        let d = self.data.read().await;
+        let last = d.snapshot_log.back();
+        RegionStatus {
+            id: self.cfg.id.clone(),
+            od_url: self.cfg.od_url.clone(),
+            h3_res: self.cfg.delay.res,
+            t_od_s: self.cfg.t_od_s,
+            ready: !d.hex_geojson.is_empty(),
+            snapshot_ts_utc: last.map(|s| s.snapshot_ts_utc.clone()),
+            date: last.map(|s| s.date.to_string()),
+            cells: last.map(|s| s.cells).unwrap_or(0),
+            dates: d.days.keys().map(|k| k.to_string()).collect(),
+            cfg_version: self.tuning.current().version,
+            last_error: d.last_error.clone(),
+        }
+    }
+}
+
+/// Resumen por región para `GET /regions`
+#[derive(Clone, Debug, Serialize)]
+pub struct RegionStatus {
+    pub id: String,
+    pub od_url: String,
+    pub h3_res: u8,
+    pub t_od_s: u64,
+    /// Hay un snapshot publicado
+    pub ready: bool,
+    pub snapshot_ts_utc: Option<String>,
+    pub date: Option<String>,
+    pub cells: usize,
+    /// Días del último lote O/D (se sirven con `/map/hex?date=`)
+    pub dates: Vec<String>,
+    pub cfg_version: u64,
+    pub last_error: Option<String>,
+}
+
+/// Conjunto de regiones en el orden de la configuración (nunca vacío)
+pub struct Regions {
+    list: Vec<Arc<Region>>,
+}
+
+impl Regions {
+    pub fn new(settings: &[RegionSettings]) -> Self {
+        assert!(!settings.is_empty(), "config sin regiones");
+        Self {
+            list: settings.iter().cloned().map(|r| Arc::new(Region::new(r))).collect(),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Arc<Region>> {
+        self.list.iter().find(|r| r.id() == id)
+    }
+
+    /// Región de las rutas sin prefijo `/regions/{id}`
+    pub fn default_region(&self) -> &Arc<Region> {
+        &self.list[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Region>> {
+        self.list.iter()
+    }
+}
@@ -0,0 +1,231 @@
+//! secrets.rs — Fuentes de secretos (clave TomTom, token Orion-LD)
+//!
+//! Ningún secreto vive en el binario ni en `AppCfg`: la config solo guarda *de dónde* leerlo.
+//! Formatos aceptados (texto o tabla):
+//! - `"env:TOMTOM_KEY"`                      / `{ env = "TOMTOM_KEY" }`
+//! - `"file:/run/secrets/tomtom_key"`        / `{ file = "/run/secrets/tomtom_key" }` (Docker/K8s)
+//! - `"keyfile:/etc/madgrid/keys.env#TOMTOM_KEY"` / `{ keyfile = { path = "...", name = "TOMTOM_KEY" } }`
+//!
+//! El keyfile es un fichero `NOMBRE=valor` por línea (estilo `.env`, admite `#` y comillas).
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Valor secreto. `Debug` y `Display` nunca muestran el contenido.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Acceso explícito al valor en claro (solo para construir la petición).
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+// ===============================
+// Proveedores
+// ===============================
+
+pub trait SecretProvider: Send + Sync {
+    /// Descripción sin el valor (para logs)
+    fn describe(&self) -> String;
+    fn fetch(&self) -> Result<Secret>;
+}
+
+pub struct EnvProvider {
+    pub var: String,
+}
+
+impl SecretProvider for EnvProvider {
+    fn describe(&self) -> String {
+        format!("env:{}", self.var)
+    }
+    fn fetch(&self) -> Result<Secret> {
+        let v = std::env::var(&self.var).with_context(|| format!("variable {} no definida", self.var))?;
+        non_empty(v, || self.describe())
+    }
+}
+
+pub struct FileProvider {
+    pub path: PathBuf,
+}
+
+impl SecretProvider for FileProvider {
+    fn describe(&self) -> String {
+        format!("file:{}", self.path.display())
+    }
+    fn fetch(&self) -> Result<Secret> {
+        let v = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("no se pudo leer {}", self.path.display()))?;
+        non_empty(v.trim().to_string(), || self.describe())
+    }
+}
+
+pub struct KeyfileProvider {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+impl SecretProvider for KeyfileProvider {
+    fn describe(&self) -> String {
+        format!("keyfile:{}#{}", self.path.display(), self.name)
+    }
+    fn fetch(&self) -> Result<Secret> {
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("no se pudo leer {}", self.path.display()))?;
+        let value = text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| l.split_once('='))
+            .find(|(k, _)| k.trim() == self.name)
+            .map(|(_, v)| v.trim().trim_matches(|c| c == '\'' || c == '"').to_string())
+            .ok_or_else(|| anyhow!("{} no contiene {}", self.path.display(), self.name))?;
+        non_empty(value, || self.describe())
+    }
+}
+
+fn non_empty(v: String, describe: impl FnOnce() -> String) -> Result<Secret> {
+    if v.is_empty() {
+        bail!("secreto vacío en {}", describe());
+    }
+    Ok(Secret(v))
+}
+
+// ===============================
+// Referencia configurable
+// ===============================
+
+/// Referencia a un secreto tal y como aparece en la configuración.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SecretSource {
+    Env(String),
+    File(PathBuf),
+    Keyfile { path: PathBuf, name: String },
+}
+
+impl SecretSource {
+    pub fn provider(&self) -> Box<dyn SecretProvider> {
+        match self {
+            Self::Env(var) => Box::new(EnvProvider { var: var.clone() }),
+            Self::File(path) => Box::new(FileProvider { path: path.clone() }),
+            Self::Keyfile { path, name } => Box::new(KeyfileProvider { path: path.clone(), name: name.clone() }),
+        }
+    }
+
+    pub fn fetch(&self) -> Result<Secret> {
+        self.provider().fetch()
+    }
+}
+
+impl fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.provider().describe())
+    }
+}
+
+impl FromStr for SecretSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, rest) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("se esperaba env:, file: o keyfile: (nunca la clave en claro)"))?;
+        if rest.is_empty() {
+            bail!("referencia de secreto vacía: {s:?}");
+        }
+        match kind {
+            "env" => Ok(Self::Env(rest.to_string())),
+            "file" => Ok(Self::File(rest.into())),
+            "keyfile" => {
+                let (path, name) = rest
+                    .rsplit_once('#')
+                    .ok_or_else(|| anyhow!("keyfile requiere ruta#NOMBRE"))?;
+                Ok(Self::Keyfile { path: path.into(), name: name.to_string() })
+            }
+            other => bail!("fuente de secreto desconocida: {other:?}"),
+        }
+    }
+}
+
+impl Serialize for SecretSource {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretSource {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "lowercase", deny_unknown_fields)]
+        enum Tagged {
+            Env(String),
+            File(PathBuf),
+            Keyfile { path: PathBuf, name: String },
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            Table(Tagged),
+        }
+
+        match Repr::deserialize(d)? {
+            Repr::Text(s) => s.parse().map_err(serde::de::Error::custom),
+            Repr::Table(Tagged::Env(v)) => Ok(Self::Env(v)),
+            Repr::Table(Tagged::File(p)) => Ok(Self::File(p)),
+            Repr::Table(Tagged::Keyfile { path, name }) => Ok(Self::Keyfile { path, name }),
+        }
+    }
+}
+
+/// Resuelve un secreto opcional. Si falla, se avisa y se sigue sin él.
+pub fn resolve(label: &str, source: Option<&SecretSource>) -> Option<Secret> {
+    let src = source?;
+    match src.fetch() {
+        Ok(s) => {
+            tracing::info!("{label}: secreto cargado de {src}");
+            Some(s)
+        }
+        Err(e) => {
+            tracing::warn!("{label}: no se pudo cargar el secreto ({src}): {e:#}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sources_and_reads_keyfile() {
+        let path = std::env::temp_dir().join(format!("madgrid_keys_{}.env", std::process::id()));
+        std::fs::write(&path, "# claves\nOTHER=1\nTOMTOM_KEY='abc123'\n").unwrap();
+
+        let src: SecretSource = format!("keyfile:{}#TOMTOM_KEY", path.display()).parse().unwrap();
+        let secret = src.fetch().unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(secret.expose(), "abc123");
+        assert_eq!(format!("{secret:?}"), "Secret(***)");
+        assert_eq!("env:X".parse::<SecretSource>().unwrap(), SecretSource::Env("X".into()));
+        assert!("abc123".parse::<SecretSource>().is_err());
+    }
+}
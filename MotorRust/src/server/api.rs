@@ -1,43 +1,215 @@
-//! api.rs — Rutas HTTP: /health, /kpis, /map/hex y /orders/filter
+//! api.rs — Rutas HTTP: /health, /status, /regions, /kpis, /map/hex, /od/flows, /tiles, /events, /cells, /history, /orders/filter y /admin/delay-cfg
+//!
+//! Las rutas de datos existen dos veces: `/regions/{id}/...` para cada región y sin
+//! prefijo para la región por defecto (la primera de la configuración).
 
 use axum::{
-    extract::State,
-    response::{IntoResponse, Response},
+    async_trait,
+    extract::{FromRef, FromRequestParts, Path, Query, RawPathParams, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Json, Router,
 };
 use axum::body::Body;
-use axum::http::{header::CONTENT_TYPE, StatusCode};
-use serde::Serialize;
-use std::{sync::Arc};
-use tokio::sync::RwLock;
+use axum::http::{header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE}, request::Parts, HeaderMap, StatusCode};
+use futures::{future::join_all, stream, Stream, StreamExt};
+use h3o::{CellIndex, LatLng, Resolution};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use chrono::NaiveDate;
+use std::{collections::HashMap, convert::Infallible, str::FromStr, sync::Arc};
+use tokio::sync::broadcast::{self, error::RecvError};
 use tower_http::{compression::CompressionLayer, services::ServeDir, cors::CorsLayer};
 
-use crate::{clusterizador::global_orders, models::types::DataState};
+use crate::clusterizador::{global_orders, orders_layer, OrderZones};
+use crate::config::ConfigError;
+use crate::h3grid::{diff_geojson, od_flows_geojson, to_geojson, resolve_cell, resolve_point, to_geojson_filtered, CellMatch, FlowFilter, HexFilter};
+use crate::history::{HistoryQuery, HistoryStore};
+use crate::models::h3types::H3Metrics;
+use crate::region::{Region, Regions};
+use crate::secrets::Secret;
+use crate::status::{readiness, StatusReport};
+use crate::tiles::{encode_tile, hex_layer, TileId, MVT_CONTENT_TYPE};
+use crate::tuning::CfgOrigin;
 
 #[derive(Clone)]
 pub struct ApiState {
-    pub data: Arc<RwLock<DataState>>,
+    pub regions: Arc<Regions>,
+    /// Bearer token exigido en `/admin/*`; sin él las rutas admin responden 403
+    pub admin_token: Option<Secret>,
+    /// Agrupaciones S2 recientes de `/orders/filter`, por `orders_id`
+    pub orders: Arc<OrderZones>,
+    /// Edad máxima del snapshot para `/health/ready` (s, 0 = sin límite)
+    pub ready_max_age_s: u64,
 }
 
+impl FromRef<ApiState> for Arc<OrderZones> {
+    fn from_ref(s: &ApiState) -> Self {
+        s.orders.clone()
+    }
+}
+
+/// Nombre del parámetro de ruta con el id de región
+const REGION_PARAM: &str = "region";
+
 pub fn router(state: ApiState) -> Router {
     Router::new()
         .route("/health", get(|| async { "ok" }))
-        .route("/map/hex", get(get_hex_geojson))
-        .route("/kpis", get(get_kpis))
+        .route("/health/live", get(|| async { "ok" }))
+        .route("/health/ready", get(get_ready))
+        .route("/regions", get(list_regions))
         .route("/orders/filter", post(global_orders))
+        .route("/tiles/orders/:id/:z/:x/:y", get(get_orders_tile))
+        .merge(region_routes())
+        .nest("/regions/:region", region_routes())
         .fallback_service(ServeDir::new("web"))
         .with_state(state)
         .layer(CorsLayer::permissive())
         .layer(CompressionLayer::new())
 }
 
+/// Rutas que dependen de una región (ver `RegionRef`)
+fn region_routes() -> Router<ApiState> {
+    Router::new()
+        .route("/map/hex", get(get_hex_geojson))
+        .route("/map/hex/diff", get(get_hex_diff))
+        .route("/kpis", get(get_kpis))
+        .route("/status", get(get_status))
+        .route("/ingest/last", get(get_ingest_last))
+        .route("/od/flows", get(get_od_flows))
+        .route("/tiles/:z/:x/:y", get(get_hex_tile))
+        .route("/events", get(get_events))
+        .route("/cells/at", get(get_cell_at))
+        .route("/cells/:h3", get(get_cell))
+        .route("/history/days", get(get_history_days))
+        .route("/history/cells/:h3", get(get_history_cell))
+        .route("/history/day/:date/map/hex", get(get_history_day_hex))
+        .route("/admin/delay-cfg", get(get_delay_cfg).put(put_delay_cfg))
+        .route("/admin/delay-cfg/history", get(get_delay_cfg_history))
+}
+
+/// Región de la petición: `/regions/{id}/...` o la región por defecto.
+pub struct RegionRef(pub Arc<Region>);
+
+#[async_trait]
+impl FromRequestParts<ApiState> for RegionRef {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ApiState) -> Result<Self, Self::Rejection> {
+        let params = RawPathParams::from_request_parts(parts, state).await.ok();
+        let id = params.as_ref().and_then(|p| {
+            p.iter().find(|(k, _)| *k == REGION_PARAM).map(|(_, v)| v.to_string())
+        });
+        let Some(id) = id else {
+            return Ok(Self(state.regions.default_region().clone()));
+        };
+        state.regions.get(&id).cloned().map(Self).ok_or_else(|| {
+            (StatusCode::NOT_FOUND, Json(json!({ "error": format!("región desconocida: {id}") })))
+                .into_response()
+        })
+    }
+}
+
+/// 200 si todas las regiones tienen un snapshot de menos de `ready_max_age_s`; si no, 503 con el motivo
+async fn get_ready(State(state): State<ApiState>) -> Response {
+    let now = chrono::Utc::now();
+    let mut not_ready = serde_json::Map::new();
+    for r in state.regions.iter() {
+        if let Err(why) = readiness(&*r.data.read().await, state.ready_max_age_s, now) {
+            not_ready.insert(r.id().to_string(), why.into());
+        }
+    }
+    if not_ready.is_empty() {
+        return "ready".into_response();
+    }
+    (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "ready": false, "regions": not_ready }))).into_response()
+}
+
+/// Estado del loop O/D de la región: fuente, errores, TomTom, sinks y edad del snapshot
+async fn get_status(State(state): State<ApiState>, RegionRef(region): RegionRef) -> impl IntoResponse {
+    let d = region.data.read().await;
+    Json(StatusReport::new(region.id(), &d, state.ready_max_age_s, chrono::Utc::now()))
+}
+
+/// Estado de cada región (snapshot, versión de config, último error)
+async fn list_regions(State(state): State<ApiState>) -> impl IntoResponse {
+    Json(join_all(state.regions.iter().map(|r| r.status())).await)
+}
+
+/// Parámetros de `/map/hex` (todos opcionales; sin ninguno se sirve el GeoJSON cacheado)
+#[derive(Debug, Default, Deserialize)]
+struct HexQuery {
+    /// `minLon,minLat,maxLon,maxLat`
+    bbox: Option<String>,
+    min_delay: Option<f32>,
+    used_tomtom: Option<bool>,
+    res: Option<u8>,
+    #[serde(default)]
+    hotspots_only: bool,
+    /// Oculta celdas con `delay_final <= 1 + show_eps`
+    show_eps: Option<f32>,
+    /// Día del último lote O/D (`YYYY-MM-DD`); por defecto, el más reciente
+    date: Option<NaiveDate>,
+    /// Hora del día (0..=23): mapa Orange de esa franja
+    hour: Option<u8>,
+}
+
+impl HexQuery {
+    fn to_filter(&self) -> Result<HexFilter, String> {
+        let bbox = match &self.bbox {
+            None => None,
+            Some(raw) => {
+                let v: Vec<f64> = raw
+                    .split(',')
+                    .map(|x| x.trim().parse::<f64>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("bbox inválido {raw:?}: {e}"))?;
+                let [min_lon, min_lat, max_lon, max_lat] = v[..] else {
+                    return Err(format!("bbox requiere minLon,minLat,maxLon,maxLat: {raw:?}"));
+                };
+                if min_lon > max_lon || min_lat > max_lat {
+                    return Err(format!("bbox con mínimos mayores que máximos: {raw:?}"));
+                }
+                Some([min_lon, min_lat, max_lon, max_lat])
+            }
+        };
+        if let Some(r) = self.res {
+            Resolution::try_from(r).map_err(|e| format!("res inválida {r}: {e}"))?;
+        }
+        if self.show_eps.is_some_and(|e| e.is_nan() || e < 0.0) {
+            return Err("show_eps debe ser >= 0".into());
+        }
+        Ok(HexFilter {
+            bbox,
+            min_delay: self.min_delay,
+            used_tomtom: self.used_tomtom,
+            res: self.res,
+            hotspots_only: self.hotspots_only,
+            show_eps: self.show_eps,
+        })
+    }
+}
+
+/// Snapshot restaurado de `state_dir`: los días, horas y flujos del lote no se guardan, así que
+/// hasta el primer recálculo solo se sirve el mapa diario
+fn pending_recompute() -> Response {
+    let error = "snapshot restaurado del arranque anterior: días, horas y flujos O/D tras el primer recálculo";
+    (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "error": error }))).into_response()
+}
+
 /// Devuelve el GeoJSON actual con content-type correcto.
-async fn get_hex_geojson(State(state): State<ApiState>) -> Response {
-    let d = state.data.read().await;
-    let body = d.hex_geojson.clone();
+/// `GET /map/hex[?bbox=&min_delay=&used_tomtom=&res=&hotspots_only=&show_eps=]`
+async fn get_hex_geojson(RegionRef(region): RegionRef, Query(q): Query<HexQuery>) -> Response {
+    let filter = match q.to_filter() {
+        Ok(f) => f,
+        Err(msg) => return bad_request(msg),
+    };
+    let d = region.data.read().await;
 
-    if body.is_empty() {
+    if d.hex_geojson.is_empty() {
         // 204 sin cuerpo
         return Response::builder()
             .status(StatusCode::NO_CONTENT)
@@ -45,6 +217,58 @@ async fn get_hex_geojson(State(state): State<ApiState>) -> Response {
             .unwrap();
     }
 
+    if q.hour.is_some_and(|h| h > 23) {
+        return bad_request("hour fuera de 0..=23".into());
+    }
+    if (q.date.is_some() || q.hour.is_some()) && d.days.is_empty() {
+        return pending_recompute();
+    }
+
+    // Otro día del lote (o una hora): se sirve desde `days`
+    let day = match q.date {
+        Some(date) if d.days.keys().next_back() != Some(&date) => match d.days.get(&date) {
+            Some(day) => Some(day.clone()),
+            None => {
+                let dates: Vec<String> = d.days.keys().map(|k| k.to_string()).collect();
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(json!({ "error": format!("día {date} no está en el último lote"), "dates": dates })),
+                )
+                    .into_response();
+            }
+        },
+        _ if q.hour.is_some() => d.days.values().next_back().cloned(),
+        _ => None,
+    };
+
+    // Mapa horario del día elegido: sin hotspots (las horas van a `cfg.res`)
+    if let (Some(h), Some(day)) = (q.hour, &day) {
+        let cfg = &d.snapshot_cfg;
+        let body = match day.hours.get(&h) {
+            Some(metrics) => to_geojson_filtered(metrics, cfg, &filter, &[]),
+            None => to_geojson_filtered(&HashMap::new(), cfg, &filter, &[]),
+        };
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/geo+json; charset=utf-8")
+            .header("x-hourly-profile", if day.hourly_input { "od" } else { "default" })
+            .body(Body::from(body))
+            .unwrap();
+    }
+
+    let body = match (&day, filter.is_empty()) {
+        (None, true) => d.hex_geojson.clone(),
+        (Some(day), true) => day.geojson.clone(),
+        (day, false) => {
+            let cfg = &d.snapshot_cfg;
+            let (metrics, hotspots) = match day {
+                Some(day) => (&day.metrics, &day.hotspots),
+                None => (&*d.metrics, &d.hotspots),
+            };
+            to_geojson_filtered(metrics, cfg, &filter, hotspots)
+        }
+    };
+
     Response::builder()
         .status(StatusCode::OK)
         .header(CONTENT_TYPE, "application/geo+json; charset=utf-8")
@@ -52,23 +276,488 @@ async fn get_hex_geojson(State(state): State<ApiState>) -> Response {
         .unwrap()
 }
 
-/// KPI sencillos: timestamp y conteo aproximado de features.
+#[derive(Debug, Deserialize)]
+struct DiffQuery {
+    from: Option<u64>,
+    to: Option<u64>,
+    /// Cambio mínimo de `delay_final` (por defecto, cualquier cambio)
+    #[serde(default)]
+    threshold: f32,
+}
+
+/// `GET /map/hex/diff?from=&to=`: celdas que cambiaron entre dos snapshots retenidos.
+/// Sin `to` se usa el snapshot actual; sin `from`, el anterior a `to`.
+async fn get_hex_diff(RegionRef(region): RegionRef, Query(q): Query<DiffQuery>) -> Response {
+    if !q.threshold.is_finite() || q.threshold < 0.0 {
+        return bad_request("threshold debe ser >= 0".into());
+    }
+    let d = region.data.read().await;
+    let ids: Vec<u64> = d.retained.iter().map(|(id, _)| *id).collect();
+    let not_found = |what: &str, id: Option<u64>| {
+        let msg = match id {
+            Some(id) => format!("snapshot {what}={id} no retenido"),
+            None => format!("no hay snapshot {what}"),
+        };
+        (StatusCode::NOT_FOUND, Json(json!({ "error": msg, "snapshots": ids }))).into_response()
+    };
+
+    let to_pos = match q.to {
+        Some(id) => d.retained.iter().position(|(s, _)| *s == id),
+        None => d.retained.len().checked_sub(1),
+    };
+    let Some(to_pos) = to_pos else { return not_found("to", q.to) };
+    let from_pos = match q.from {
+        Some(id) => d.retained.iter().position(|(s, _)| *s == id),
+        None => to_pos.checked_sub(1),
+    };
+    let Some(from_pos) = from_pos else { return not_found("from", q.from) };
+
+    let (from, old) = &d.retained[from_pos];
+    let (to, new) = &d.retained[to_pos];
+    let mut gj = diff_geojson(old, new, q.threshold);
+    gj["from"] = json!(from);
+    gj["to"] = json!(to);
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/geo+json; charset=utf-8")
+        .body(Body::from(gj.to_string()))
+        .unwrap()
+}
+
+// ===============================
+// Teselas vectoriales
+// ===============================
+
+fn tile_id(params: &[(String, String)]) -> Result<TileId, String> {
+    let get = |k: &str| params.iter().find(|(n, _)| n == k).map(|(_, v)| v.as_str()).unwrap_or("");
+    TileId::parse(get("z"), get("x"), get("y"))
+}
+
+fn mvt_response(body: bytes::Bytes) -> Response {
+    if body.is_empty() {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, MVT_CONTENT_TYPE)
+        .header(CACHE_CONTROL, "no-cache")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// `GET /tiles/{z}/{x}/{y}.mvt`: capa `hex_delay_h3` del snapshot actual
+async fn get_hex_tile(RegionRef(region): RegionRef, Path(params): Path<Vec<(String, String)>>) -> Response {
+    let tile = match tile_id(&params) {
+        Ok(t) => t,
+        Err(msg) => return bad_request(msg),
+    };
+    let d = region.data.read().await;
+    if d.snapshot_id == 0 {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    // Se cachea por snapshot: se pinta con el DelayCfg que lo produjo, no con el vigente
+    let body = region.tiles.get_or_render(d.snapshot_id, tile, || {
+        encode_tile(&[hex_layer(&tile, &d.metrics, &d.hotspots, &d.snapshot_cfg)])
+    });
+    mvt_response(body)
+}
+
+/// `GET /tiles/orders/{id}/{z}/{x}/{y}.mvt`: capa `orders_s2_zones` de la agrupación `orders_id`
+async fn get_orders_tile(State(orders): State<Arc<OrderZones>>, Path(params): Path<Vec<(String, String)>>) -> Response {
+    let tile = match tile_id(&params) {
+        Ok(t) => t,
+        Err(msg) => return bad_request(msg),
+    };
+    let id = params.iter().find(|(n, _)| n == "id").map(|(_, v)| v.as_str()).unwrap_or("");
+    let Some(snap) = id.parse().ok().and_then(|id| orders.get(id)) else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": format!("agrupación {id} desconocida o caducada") })))
+            .into_response();
+    };
+    let body = snap.tiles.get_or_render(snap.id, tile, || encode_tile(&[orders_layer(&tile, &snap)]));
+    mvt_response(body)
+}
+
+// ===============================
+// Eventos (SSE)
+// ===============================
+
+/// `GET /events`: SSE con un evento `snapshot` por cada snapshot publicado.
+/// Al conectar se envía `hello` con el snapshot vigente; si el cliente se queda atrás, `lagged`.
+async fn get_events(RegionRef(region): RegionRef) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let rx = region.events.subscribe();
+    let hello = {
+        let d = region.data.read().await;
+        json!({
+            "region": region.id(),
+            "snapshot_id": d.snapshot_id,
+            "snapshot_ts_utc": d.snapshot_ts_utc,
+            "cfg_version": d.cfg_version,
+            "cells": d.metrics.len(),
+        })
+    };
+    let first = stream::iter([Ok(Event::default().event("hello").json_data(hello).unwrap_or_default())]);
+    let updates = stream::unfold(rx, |mut rx: broadcast::Receiver<_>| async move {
+        let ev = match rx.recv().await {
+            Ok(msg) => Event::default()
+                .event("snapshot")
+                .id(msg.snapshot_id.to_string())
+                .json_data(&*msg)
+                .unwrap_or_default(),
+            Err(RecvError::Lagged(n)) => Event::default().event("lagged").data(n.to_string()),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(ev), rx))
+    });
+    Sse::new(first.chain(updates)).keep_alive(KeepAlive::default())
+}
+
+/// KPIs del snapshot actual (calculados sobre las `H3Metrics` al publicar el snapshot).
+async fn get_kpis(RegionRef(region): RegionRef) -> impl IntoResponse {
+    Json(region.data.read().await.kpis.clone())
+}
+
+/// Resumen de la última ingesta O/D: filas aceptadas, normalizadas, rechazadas y muestra de errores
+async fn get_ingest_last(RegionRef(region): RegionRef) -> Response {
+    match region.data.read().await.last_ingest.clone() {
+        Some(r) => Json(r).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({ "error": "todavía no se ha ingerido ningún O/D" }))).into_response(),
+    }
+}
+
+/// Pares devueltos por defecto en `/od/flows`
+const OD_FLOWS_DEFAULT_LIMIT: usize = 100;
+
+#[derive(Deserialize, Default)]
+struct FlowQuery {
+    /// Celda H3 de origen (a cualquier resolución; incluye sus hijas)
+    origin: Option<String>,
+    /// Celda H3 de destino (a cualquier resolución; incluye sus hijas)
+    dest: Option<String>,
+    min_volume: Option<f32>,
+    /// Top-N por volumen (0 = todos)
+    limit: Option<usize>,
+    /// Día del último lote O/D; por defecto, el más reciente
+    date: Option<NaiveDate>,
+}
+
+/// Líneas de deseo O/D del último lote
+async fn get_od_flows(RegionRef(region): RegionRef, Query(q): Query<FlowQuery>) -> Response {
+    let cell = |raw: &Option<String>, name: &str| match raw {
+        None => Ok(None),
+        Some(s) => CellIndex::from_str(s.trim()).map(Some).map_err(|e| format!("{name} inválido {s:?}: {e}")),
+    };
+    let filter = match (cell(&q.origin, "origin"), cell(&q.dest, "dest")) {
+        (Ok(origin), Ok(dest)) => FlowFilter {
+            origin,
+            dest,
+            min_volume: q.min_volume.unwrap_or(0.0),
+            limit: q.limit.unwrap_or(OD_FLOWS_DEFAULT_LIMIT),
+        },
+        (Err(e), _) | (_, Err(e)) => return bad_request(e),
+    };
+
+    let (flows, cfg) = {
+        let d = region.data.read().await;
+        if d.od_flows.is_empty() && d.snapshot_id > 0 {
+            return pending_recompute();
+        }
+        (d.od_flows.clone(), d.snapshot_cfg.clone())
+    };
+    let day = match q.date {
+        Some(date) => flows.get(&date).map(|f| (date, f)),
+        None => flows.last_key_value().map(|(d, f)| (*d, f)),
+    };
+    let Some((date, day)) = day else {
+        let dates: Vec<String> = flows.keys().map(|d| d.to_string()).collect();
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "sin flujos O/D para esa fecha", "dates": dates })))
+            .into_response();
+    };
+    let mut gj = od_flows_geojson(day, &cfg, &filter);
+    gj["date"] = json!(date);
+    Json(gj).into_response()
+}
+
+// ===============================
+// Consulta por celda
+// ===============================
+
+/// Métricas de una celda para el router
 #[derive(Serialize)]
-struct Kpis {
-    snapshot_ts_utc: String,
-    features: usize,
-    geojson_bytes: usize,
-}
-
-async fn get_kpis(State(state): State<ApiState>) -> impl IntoResponse {
-    let d = state.data.read().await;
-    let gj = d.hex_geojson.as_str();
-    // Conteo simple sin parsear: cuenta ocurrencias de `"type":"Feature"`
-    let features = gj.matches("\"type\":\"Feature\"").count();
-    let out = Kpis {
-        snapshot_ts_utc: d.snapshot_ts_utc.clone(),
-        features,
-        geojson_bytes: gj.len(),
-    };
-    Json(out)
+struct CellView {
+    /// Celda pedida (o la celda del punto a res+1)
+    requested: String,
+    h3: String,
+    res: u8,
+    matched: CellMatch,
+    delay_final: f32,
+    delay_orange: f32,
+    delay_tomtom: f32,
+    used_tomtom: bool,
+    conf_cell: f32,
+    truck_share: f32,
+    vol_norm: f32,
+    trips_out: f32,
+    trips_in: f32,
+    trips_intra: f32,
+    trips_through: f32,
+}
+
+impl CellView {
+    fn new(requested: CellIndex, m: &H3Metrics, matched: CellMatch) -> Self {
+        Self {
+            requested: requested.to_string(),
+            h3: m.cell.to_string(),
+            res: u8::from(m.cell.resolution()),
+            matched,
+            delay_final: m.delay_final,
+            delay_orange: m.delay_orange,
+            delay_tomtom: m.delay_tomtom,
+            used_tomtom: m.delay_tomtom > 0.0,
+            conf_cell: m.conf_cell(),
+            truck_share: m.truck_share,
+            vol_norm: m.vol_norm,
+            trips_out: m.trips_out,
+            trips_in: m.trips_in,
+            trips_intra: m.trips_intra,
+            trips_through: m.trips_through,
+        }
+    }
+}
+
+fn bad_request(msg: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": msg }))).into_response()
+}
+
+fn base_res(region: &Region) -> Resolution {
+    Resolution::try_from(region.cfg.delay.res).unwrap_or(Resolution::Seven)
+}
+
+/// `GET /cells/{h3}`: la celda o su ancestro publicado. Si se subdividió, lista sus hijas.
+async fn get_cell(RegionRef(region): RegionRef, Path(params): Path<Vec<(String, String)>>) -> Response {
+    let Some((_, raw)) = params.iter().find(|(k, _)| k == "h3") else {
+        return bad_request("falta h3".into());
+    };
+    let cell = match CellIndex::from_str(raw) {
+        Ok(c) => c,
+        Err(e) => return bad_request(format!("h3 inválido {raw:?}: {e}")),
+    };
+
+    let d = region.data.read().await;
+    if let Some((m, matched)) = resolve_cell(&d.metrics, cell, base_res(&region)) {
+        return Json(CellView::new(cell, m, matched)).into_response();
+    }
+    let children: Vec<String> = cell
+        .resolution()
+        .succ()
+        .map(|r| cell.children(r).filter(|c| d.metrics.contains_key(c)).map(|c| c.to_string()).collect())
+        .unwrap_or_default();
+    let body = if children.is_empty() {
+        json!({ "error": "celda sin datos", "h3": cell.to_string() })
+    } else {
+        json!({ "error": "celda subdividida", "h3": cell.to_string(), "children": children })
+    };
+    (StatusCode::NOT_FOUND, Json(body)).into_response()
+}
+
+#[derive(Deserialize)]
+struct AtQuery {
+    lat: f64,
+    lon: f64,
+}
+
+/// `GET /cells/at?lat=..&lon=..`: hija de hotspot si existe; si no, la celda a la resolución base.
+async fn get_cell_at(RegionRef(region): RegionRef, Query(q): Query<AtQuery>) -> Response {
+    let ll = match LatLng::new(q.lat, q.lon) {
+        Ok(ll) => ll,
+        Err(e) => return bad_request(format!("lat/lon inválidos: {e}")),
+    };
+    let base = base_res(&region);
+    let d = region.data.read().await;
+    match resolve_point(&d.metrics, ll, base) {
+        Some((m, matched)) => {
+            // La consulta es la celda del punto a res+1, resuelva a ella o a la de `base`
+            let requested = ll.to_cell(base.succ().unwrap_or(base));
+            Json(CellView::new(requested, m, matched)).into_response()
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "punto fuera de la malla", "lat": q.lat, "lon": q.lon })),
+        )
+            .into_response(),
+    }
+}
+
+// ===============================
+// Histórico
+// ===============================
+
+#[derive(Debug, Deserialize)]
+struct RangeQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    /// Serie de una hora del día (solo si el O/D traía horas)
+    hour: Option<u8>,
+}
+
+fn path_param<'a>(params: &'a [(String, String)], name: &str) -> &'a str {
+    params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str()).unwrap_or("")
+}
+
+/// Ejecuta una consulta al histórico fuera del runtime (lectura de ficheros)
+async fn run_history<T: Send + 'static>(
+    region: &Region,
+    f: impl FnOnce(&dyn HistoryStore) -> anyhow::Result<T> + Send + 'static,
+) -> Result<T, Response> {
+    let Some(store) = region.history.clone() else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "histórico no configurado (sqlite_out o jsonl_out)" })),
+        )
+            .into_response());
+    };
+    match tokio::task::spawn_blocking(move || f(store.as_ref())).await {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(e)) => Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("{e:#}") }))).into_response()),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e.to_string() }))).into_response()),
+    }
+}
+
+/// `GET /history/days`: días con datos y número de celdas
+async fn get_history_days(RegionRef(region): RegionRef) -> Response {
+    match run_history(&region, |s| s.days()).await {
+        Ok(days) => Json(json!({ "region": region.id(), "days": days })).into_response(),
+        Err(r) => r,
+    }
+}
+
+/// `GET /history/cells/{h3}?from=&to=`: serie diaria de una celda
+async fn get_history_cell(
+    RegionRef(region): RegionRef,
+    Path(params): Path<Vec<(String, String)>>,
+    Query(q): Query<RangeQuery>,
+) -> Response {
+    let raw = path_param(&params, "h3");
+    let cell = match CellIndex::from_str(raw) {
+        Ok(c) => c,
+        Err(e) => return bad_request(format!("h3 inválido {raw:?}: {e}")),
+    };
+    let query = HistoryQuery { h3: Some(cell), from: q.from, to: q.to, res: None, hour: q.hour };
+    match run_history(&region, move |s| s.query(&query)).await {
+        Ok(rows) => Json(json!({ "h3": cell.to_string(), "rows": rows })).into_response(),
+        Err(r) => r,
+    }
+}
+
+/// `GET /history/day/{date}/map/hex`: repinta un día pasado. Admite los filtros de `/map/hex`.
+async fn get_history_day_hex(
+    RegionRef(region): RegionRef,
+    Path(params): Path<Vec<(String, String)>>,
+    Query(q): Query<HexQuery>,
+) -> Response {
+    let raw = path_param(&params, "date");
+    let date = match NaiveDate::parse_from_str(raw, "%Y-%m-%d") {
+        Ok(d) => d,
+        Err(e) => return bad_request(format!("fecha inválida {raw:?}: {e}")),
+    };
+    let filter = match q.to_filter() {
+        Ok(f) => f,
+        Err(msg) => return bad_request(msg),
+    };
+    let query = HistoryQuery { from: Some(date), to: Some(date), res: q.res, h3: None, hour: q.hour };
+    let rows = match run_history(&region, move |s| s.query(&query)).await {
+        Ok(rows) => rows,
+        Err(r) => return r,
+    };
+    if rows.is_empty() {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+
+    let metrics: HashMap<CellIndex, H3Metrics> = rows.iter().map(|r| (r.h3, H3Metrics::from_row(r))).collect();
+    let cfg = &region.tuning.current().cfg;
+    let body = if filter.is_empty() {
+        to_geojson(&metrics, cfg)
+    } else {
+        to_geojson_filtered(&metrics, cfg, &filter, &[])
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/geo+json; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+// ===============================
+// Admin: DelayCfg en caliente
+// ===============================
+
+/// Respuesta de rechazo, o `None` si el bearer es el `admin_token`.
+/// Sin `admin_token` configurado las rutas admin quedan cerradas (403).
+fn admin_denied(state: &ApiState, headers: &HeaderMap) -> Option<Response> {
+    let Some(token) = &state.admin_token else {
+        return Some(
+            (StatusCode::FORBIDDEN, Json(json!({ "error": "rutas admin deshabilitadas: falta admin_token" }))).into_response(),
+        );
+    };
+    let bearer = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer "));
+    match bearer {
+        Some(b) if ct_eq(b.as_bytes(), token.expose().as_bytes()) => None,
+        _ => Some((StatusCode::UNAUTHORIZED, Json(json!({ "error": "admin token requerido" }))).into_response()),
+    }
+}
+
+/// Comparación en tiempo constante (solo depende de la longitud)
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn get_delay_cfg(
+    State(state): State<ApiState>,
+    RegionRef(region): RegionRef,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(r) = admin_denied(&state, &headers) {
+        return r;
+    }
+    Json(region.tuning.current().as_ref().clone()).into_response()
+}
+
+/// Aplica un parche parcial (`{"bpr_a":0.2,"vc_cap":2.5}`); el loop O/D recalcula al momento.
+async fn put_delay_cfg(
+    State(state): State<ApiState>,
+    RegionRef(region): RegionRef,
+    headers: HeaderMap,
+    Json(patch): Json<serde_json::Value>,
+) -> Response {
+    if let Some(r) = admin_denied(&state, &headers) {
+        return r;
+    }
+    match region.tuning.patch(patch, CfgOrigin::Api) {
+        Ok(v) => Json(v.as_ref().clone()).into_response(),
+        Err(ConfigError::Invalid { field, reason }) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "error": reason, "field": field })),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({ "error": e.to_string() }))).into_response(),
+    }
+}
+
+/// Cambios de DelayCfg y qué versión produjo cada snapshot.
+async fn get_delay_cfg_history(
+    State(state): State<ApiState>,
+    RegionRef(region): RegionRef,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(r) = admin_denied(&state, &headers) {
+        return r;
+    }
+    let snapshots: Vec<_> = region.data.read().await.snapshot_log.iter().cloned().collect();
+    Json(json!({
+        "region": region.id(),
+        "current": region.tuning.current().version,
+        "changes": region.tuning.history(),
+        "snapshots": snapshots,
+    }))
+    .into_response()
 }
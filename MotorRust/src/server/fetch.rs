@@ -2,33 +2,356 @@
 //!
 //! Cliente HTTP con caché ligera para evitar descargas innecesarias
 //
-//! - Usa `reqwest` para hacer peticiones con soporte de compresion (gzip, br, deflate)
+//! - Usa `reqwest` para hacer peticiones con soporte de compresion (gzip, deflate)
 //! - Implementa un control de caché sencillo con `ETag` y `If-Modified-Since`
 //! - Expone `get_with_cache()` que devuelve los bytes nuevos solo si el recurso cambio
+//! - Reintenta los fallos transitorios con backoff exponencial y jitter (`[fetch]`)
+//! - Lee el cuerpo por trozos, con tamaño máximo, y lo comprueba contra `Content-Length` y
+//!   `Repr-Digest` / `Digest` / `x-amz-checksum-sha256` si vienen. Esas cabeceras describen los
+//!   bytes tal como viajan, así que la descompresión (`Content-Encoding`) se hace aquí, después
+//!   de comprobarlas: el cuerpo se escribe a un temporal (`tmp_dir`) y se infla desde el fichero
+//! - No toca la caché: el llamador guarda los validadores nuevos solo si el fichero se pudo
+//!   leer, así un fichero corrupto se vuelve a descargar en el siguiente ciclo
 //!
 //! Esto reduce consumo de red y evita recargar datos sin cambios
 
 
-use anyhow::Result;
-use reqwest::{Client, StatusCode};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use bytes::Bytes;
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use reqwest::header::{HeaderMap, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::hash::BuildHasher;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
 
+use crate::models::types::FetchCfg;
 
-#[derive(Default, Clone)]
-pub struct CacheCtl { pub etag: Option<String>, pub last_mod: Option<String> }
 
-pub async fn get_with_cache(client: &Client, url: &str, cache: &mut CacheCtl) -> Result<Option<bytes::Bytes>> {
-let mut req = client.get(url);
-if let Some(et) = &cache.etag { req = req.header("If-None-Match", et); }
-if let Some(lm) = &cache.last_mod { req = req.header("If-Modified-Since", lm); }
-let resp = req.send().await?;
-match resp.status() {
-StatusCode::NOT_MODIFIED => Ok(None),
-StatusCode::OK => {
-cache.etag = resp.headers().get("etag").map(|v| v.to_str().ok().unwrap_or("").to_string());
-cache.last_mod = resp.headers().get("last-modified").map(|v| v.to_str().ok().unwrap_or("").to_string());
-let bytes = resp.bytes().await?;
-Ok(Some(bytes))
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct CacheCtl { pub etag: Option<String>, pub last_mod: Option<String>, pub content_type: Option<String> }
+
+/// Cliente HTTP compartido con los timeouts de `[fetch]`. Sin descompresión automática: la hace
+/// `download` tras comprobar el checksum sobre los bytes recibidos
+pub fn client(cfg: &FetchCfg) -> reqwest::Result<Client> {
+    Client::builder()
+        .brotli(false)
+        .gzip(false)
+        .deflate(false)
+        .connect_timeout(Duration::from_secs(cfg.connect_timeout_s))
+        .read_timeout(Duration::from_secs(cfg.read_timeout_s))
+        .build()
+}
+
+/// Respuesta leída entera
+#[derive(Debug)]
+pub struct Fetched {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// SHA-256 (hex) del cuerpo ya descomprimido; vacío si no se leyó cuerpo.
+    /// `headers` ya no trae `Content-Encoding` / `Content-Length` si se descomprimió
+    pub sha256: String,
+}
+
+impl Fetched {
+    /// Validadores para la siguiente petición condicional
+    pub fn cache_ctl(&self) -> CacheCtl {
+        let header = |name: &str| self.headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        CacheCtl { etag: header("etag"), last_mod: header("last-modified"), content_type: header("content-type") }
+    }
+}
+
+/// GET condicional con los validadores de `cache`: `None` si no cambió (304).
+/// Los validadores nuevos (`Fetched::cache_ctl`) se guardan cuando el fichero se ha leído bien.
+pub async fn get_with_cache(client: &Client, cfg: &FetchCfg, url: &str, cache: &CacheCtl) -> Result<Option<Fetched>> {
+    let f = fetch(cfg, url, || {
+        let mut req = client.get(url);
+        if let Some(et) = &cache.etag { req = req.header("If-None-Match", et); }
+        if let Some(lm) = &cache.last_mod { req = req.header("If-Modified-Since", lm); }
+        req
+    })
+    .await?;
+    match f.status {
+        StatusCode::NOT_MODIFIED => Ok(None),
+        s if s.is_success() => Ok(Some(f)),
+        s => bail!("HTTP {} en {}", s, url),
+    }
+}
+
+// ===============================
+// Reintentos
+// ===============================
+
+/// Fallo de un intento: los transitorios se reintentan (con la espera de `Retry-After` si vino)
+enum Failure {
+    Retry(anyhow::Error, Option<Duration>),
+    Fatal(anyhow::Error),
+}
+
+/// GET con reintentos; `make` construye la petición de cada intento (p.ej. para volver a firmarla).
+/// Solo se lee el cuerpo de las respuestas 2xx; el resto de estados se devuelven sin cuerpo.
+pub async fn fetch(cfg: &FetchCfg, url: &str, make: impl Fn() -> RequestBuilder) -> Result<Fetched> {
+    let mut attempt = 0;
+    loop {
+        let (err, after) = match try_once(cfg, url, make()).await {
+            Ok(f) => return Ok(f),
+            Err(Failure::Fatal(e)) => return Err(e),
+            Err(Failure::Retry(e, _)) if attempt >= cfg.retries => {
+                return Err(e.context(format!("{url}: {} intentos fallidos", attempt + 1)))
+            }
+            Err(Failure::Retry(e, after)) => (e, after),
+        };
+        let wait = after.map_or_else(|| backoff(cfg, attempt), |d| d.min(Duration::from_millis(cfg.backoff_max_ms)));
+        attempt += 1;
+        warn!("{err:#}; reintento {attempt}/{} en {wait:?}", cfg.retries);
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// `backoff_ms * 2^intento` (tope `backoff_max_ms`), la mitad fija y la otra mitad aleatoria
+/// para que las regiones que fallan a la vez no reintenten a la vez
+fn backoff(cfg: &FetchCfg, attempt: u32) -> Duration {
+    let cap = cfg.backoff_ms.saturating_mul(1 << attempt.min(20)).min(cfg.backoff_max_ms);
+    let half = cap / 2;
+    let jitter = std::collections::hash_map::RandomState::new().hash_one(attempt) % (cap - half + 1);
+    Duration::from_millis(half + jitter)
+}
+
+async fn try_once(cfg: &FetchCfg, url: &str, req: RequestBuilder) -> Result<Fetched, Failure> {
+    let resp = req.header(ACCEPT_ENCODING, "gzip, deflate").send().await.map_err(|e| {
+        let fatal = e.is_builder();
+        let e = anyhow!(e).context(format!("GET {url}"));
+        if fatal { Failure::Fatal(e) } else { Failure::Retry(e, None) }
+    })?;
+    let status = resp.status();
+    if matches!(status, StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS) || status.is_server_error() {
+        let after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs);
+        return Err(Failure::Retry(anyhow!("HTTP {status} en {url}"), after));
+    }
+    if !status.is_success() {
+        return Ok(Fetched { status, headers: resp.headers().clone(), body: Bytes::new(), sha256: String::new() });
+    }
+    download(cfg, url, resp).await
+}
+
+// ===============================
+// Descarga a temporal
+// ===============================
+
+static TMP_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Lee el cuerpo por trozos a un temporal de `tmp_dir` (que se borra siempre), con límite de
+/// tamaño; comprueba longitud y SHA-256 anunciados sobre esos bytes y después lo descomprime según
+/// `Content-Encoding`, del fichero a memoria
+async fn download(cfg: &FetchCfg, url: &str, resp: Response) -> Result<Fetched, Failure> {
+    let dir = cfg.tmp_dir.as_ref().map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    let path = dir.join(format!("madgrid-{}-{}.part", std::process::id(), TMP_SEQ.fetch_add(1, Ordering::Relaxed)));
+    let r = write_body(cfg, url, resp, &path).await;
+    let _ = tokio::fs::remove_file(&path).await;
+    r
 }
-s => anyhow::bail!("HTTP {} en {}", s, url),
+
+async fn write_body(cfg: &FetchCfg, url: &str, mut resp: Response, path: &Path) -> Result<Fetched, Failure> {
+    let fatal = |e: std::io::Error| Failure::Fatal(anyhow!("{}: {e}", path.display()));
+    let max = cfg.max_body_mb.saturating_mul(1 << 20);
+    let too_big = |n: u64| max > 0 && n > max;
+    let expected = resp.content_length();
+    if let Some(len) = expected.filter(|&n| too_big(n)) {
+        return Err(Failure::Fatal(anyhow!("{url}: {len} bytes supera max_body_mb ({})", cfg.max_body_mb)));
+    }
+
+    // `create_new`: nunca se escribe sobre un fichero que ya estuviera ahí
+    let mut file = tokio::fs::OpenOptions::new().write(true).create_new(true).open(path).await.map_err(fatal)?;
+    let mut hasher = Sha256::new();
+    let mut n = 0u64;
+    while let Some(chunk) = resp.chunk().await.map_err(|e| Failure::Retry(anyhow!(e).context(format!("cuerpo de {url}")), None))? {
+        n += chunk.len() as u64;
+        if too_big(n) {
+            return Err(Failure::Fatal(anyhow!("{url}: más de max_body_mb ({}) MiB", cfg.max_body_mb)));
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(fatal)?;
+    }
+    file.flush().await.map_err(fatal)?;
+    drop(file);
+
+    if let Some(len) = expected.filter(|&len| len != n) {
+        return Err(Failure::Retry(anyhow!("{url}: cuerpo truncado ({n} de {len} bytes)"), None));
+    }
+    let mut headers = resp.headers().clone();
+    let sha = hasher.finalize();
+    if let Some((header, want)) = announced_sha256(&headers) {
+        if want[..] != sha[..] {
+            return Err(Failure::Retry(anyhow!("{url}: el SHA-256 no coincide con {header}"), None));
+        }
+    }
+
+    let encoding = headers.get(CONTENT_ENCODING).and_then(|v| v.to_str().ok()).map(|v| v.trim().to_ascii_lowercase());
+    let (body, sha256) = match encoding.as_deref() {
+        None | Some("identity") => (tokio::fs::read(path).await.map_err(fatal)?, hex::encode(sha)),
+        Some(enc @ ("gzip" | "x-gzip" | "deflate")) => {
+            let (enc, src) = (enc.to_string(), path.to_path_buf());
+            let body = tokio::task::spawn_blocking(move || {
+                let file = std::io::BufReader::new(std::fs::File::open(&src)?);
+                let body = match enc.as_str() {
+                    "deflate" => inflate(ZlibDecoder::new(file), max),
+                    _ => inflate(MultiGzDecoder::new(file), max),
+                };
+                body.with_context(|| format!("descompresión {enc}"))
+            })
+            .await
+            .map_err(|e| Failure::Fatal(anyhow!(e)))?
+            .map_err(|e| Failure::Fatal(e.context(url.to_string())))?;
+            headers.remove(CONTENT_ENCODING);
+            headers.remove(CONTENT_LENGTH);
+            let sha256 = hex::encode(Sha256::digest(&body));
+            (body, sha256)
+        }
+        Some(other) => return Err(Failure::Fatal(anyhow!("{url}: Content-Encoding {other} no soportado"))),
+    };
+    Ok(Fetched { status: resp.status(), headers, body: body.into(), sha256 })
+}
+
+/// Descomprime sin pasar de `max` bytes (0 = sin límite)
+fn inflate(r: impl Read, max: u64) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let limit = if max > 0 { max + 1 } else { u64::MAX };
+    r.take(limit).read_to_end(&mut out)?;
+    if max > 0 && out.len() as u64 > max {
+        bail!("descomprimido pasa de max_body_mb");
+    }
+    Ok(out)
+}
+
+/// SHA-256 anunciado por el servidor: `x-amz-checksum-sha256: b64`, `Repr-Digest: sha-256=:b64:`
+/// o `Digest: SHA-256=b64`
+fn announced_sha256(h: &HeaderMap) -> Option<(&'static str, Vec<u8>)> {
+    let b64 = |v: &str| base64::engine::general_purpose::STANDARD.decode(v.trim().trim_matches(':')).ok();
+    let field = |name: &str| h.get(name).and_then(|v| v.to_str().ok());
+    if let Some(v) = field("x-amz-checksum-sha256") {
+        return b64(v).map(|d| ("x-amz-checksum-sha256", d));
+    }
+    for name in ["repr-digest", "digest"] {
+        let Some(v) = field(name) else { continue };
+        let sha = v.split(',').filter_map(|p| p.trim().split_once('=')).find(|(alg, _)| alg.eq_ignore_ascii_case("sha-256"));
+        if let Some((_, val)) = sha {
+            return b64(val).map(|d| (name, d));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::State, http::header, response::IntoResponse, routing::get, Router};
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    /// `/flaky` falla con 503 las dos primeras veces; `/bad` anuncia un SHA-256 que no es;
+    /// `/chunked` lo mismo sin `Content-Length`; `/gz` va comprimido con el SHA-256 de lo enviado;
+    /// `/big` pasa de 1 MiB y `/bomb` solo al descomprimir
+    async fn server(hits: Arc<AtomicUsize>) -> String {
+        async fn flaky(State(hits): State<Arc<AtomicUsize>>) -> impl IntoResponse {
+            if hits.fetch_add(1, Ordering::SeqCst) < 2 {
+                return (axum::http::StatusCode::SERVICE_UNAVAILABLE, [(header::ETAG, "\"v0\"")], "").into_response();
+            }
+            let digest = format!("sha-256=:{}:", base64::engine::general_purpose::STANDARD.encode(Sha256::digest(b"date\n")));
+            ([(header::ETAG, "\"v1\"".to_string()), (header::HeaderName::from_static("repr-digest"), digest)], "date\n").into_response()
+        }
+        async fn bad(State(hits): State<Arc<AtomicUsize>>) -> impl IntoResponse {
+            hits.fetch_add(1, Ordering::SeqCst);
+            ([(header::HeaderName::from_static("digest"), "SHA-256=AAAA")], "date\n")
+        }
+        async fn chunked(State(hits): State<Arc<AtomicUsize>>) -> impl IntoResponse {
+            hits.fetch_add(1, Ordering::SeqCst);
+            let body = Body::from_stream(futures::stream::iter([Ok::<_, std::io::Error>("da"), Ok("te\n")]));
+            ([(header::HeaderName::from_static("digest"), "SHA-256=AAAA")], body)
+        }
+        fn gz(data: &[u8]) -> impl IntoResponse {
+            let bytes = gzip(data);
+            let digest = format!("sha-256=:{}:", base64::engine::general_purpose::STANDARD.encode(Sha256::digest(&bytes)));
+            ([(header::CONTENT_ENCODING, "gzip".to_string()), (header::HeaderName::from_static("repr-digest"), digest)], bytes)
+        }
+        let app = Router::new()
+            .route("/flaky", get(flaky))
+            .route("/bad", get(bad))
+            .route("/chunked", get(chunked))
+            .route("/gz", get(|| async { gz(b"date\n") }))
+            .route("/bomb", get(|| async { gz(&vec![b'x'; (1 << 20) + 1]) }))
+            .route("/big", get(|| async { vec![b'x'; (1 << 20) + 1] }))
+            .with_state(hits);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn retries_verifies_and_limits_downloads() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let base = server(hits.clone()).await;
+        let tmp = std::env::temp_dir().join(format!("madgrid_fetch_{}", std::process::id()));
+        std::fs::create_dir_all(&tmp).unwrap();
+        let tmp_dir = Some(tmp.to_string_lossy().into_owned());
+        let cfg = FetchCfg { retries: 2, backoff_ms: 1, backoff_max_ms: 5, max_body_mb: 1, tmp_dir, ..Default::default() };
+        let http = client(&cfg).unwrap();
+
+        // Dos 503 y a la tercera el cuerpo, con su SHA-256 y su ETag; la caché no se toca
+        let f = get_with_cache(&http, &cfg, &format!("{base}/flaky"), &CacheCtl::default()).await.unwrap().unwrap();
+        assert_eq!((&f.body[..], hits.load(Ordering::SeqCst)), (&b"date\n"[..], 3));
+        assert_eq!(f.sha256, hex::encode(Sha256::digest(b"date\n")));
+        assert_eq!(f.cache_ctl().etag.as_deref(), Some("\"v1\""));
+
+        // Checksum que no cuadra: se reintenta y al final falla
+        hits.store(0, Ordering::SeqCst);
+        let e = get_with_cache(&http, &cfg, &format!("{base}/bad"), &CacheCtl::default()).await.unwrap_err();
+        assert!(format!("{e:#}").contains("SHA-256"), "{e:#}");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // Sin Content-Length el checksum también se comprueba
+        hits.store(0, Ordering::SeqCst);
+        let e = get_with_cache(&http, &cfg, &format!("{base}/chunked"), &CacheCtl::default()).await.unwrap_err();
+        assert!(format!("{e:#}").contains("SHA-256"), "{e:#}");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // gzip: checksum sobre lo recibido, cuerpo y SHA-256 ya descomprimidos
+        let f = get_with_cache(&http, &cfg, &format!("{base}/gz"), &CacheCtl::default()).await.unwrap().unwrap();
+        assert_eq!((&f.body[..], f.sha256.as_str()), (&b"date\n"[..], hex::encode(Sha256::digest(b"date\n")).as_str()));
+        assert!(f.headers.get(header::CONTENT_ENCODING).is_none());
+        let e = get_with_cache(&http, &cfg, &format!("{base}/bomb"), &CacheCtl::default()).await.unwrap_err();
+        assert!(format!("{e:#}").contains("max_body_mb"), "{e:#}");
+
+        // Demasiado grande: falla sin reintentar
+        let e = get_with_cache(&http, &cfg, &format!("{base}/big"), &CacheCtl::default()).await.unwrap_err();
+        assert!(format!("{e:#}").contains("max_body_mb"), "{e:#}");
+
+        // Los temporales se borran siempre, también tras un fallo
+        assert_eq!(std::fs::read_dir(&tmp).unwrap().count(), 0);
+        std::fs::remove_dir_all(&tmp).ok();
+
+        let b = backoff(&FetchCfg { backoff_ms: 100, backoff_max_ms: 1000, ..Default::default() }, 5);
+        assert!((500..=1000).contains(&b.as_millis()), "{b:?}");
+    }
 }
-}
\ No newline at end of file
@@ -0,0 +1,272 @@
+//! state.rs — Estado de una región entre reinicios (`state_dir`)
+//!
+//! En `<state_dir>/<región>/`:
+//! - `fetch.json`: `od_url`, fichero (nombre, tamaño, SHA-256) y lo que la fuente ya vio
+//!   (`ETag` / `Last-Modified` en HTTP, ETags en S3)
+//! - `od.payload`: el último O/D leído con éxito, tal cual llegó
+//! - `od_rows.json`: con marca de agua, las filas acumuladas de todos los lotes (con directorio o
+//!   S3 cada fichero trae solo las nuevas, así que el último no basta para rehacer el snapshot)
+//! - `snapshot.json`: el último snapshot publicado (métricas, GeoJSON, hotspots y KPIs)
+//!
+//! Al arrancar se publica el snapshot guardado (así `/map/hex` no responde 204 tras un deploy) y la
+//! fuente retoma su caché: si el O/D no cambió no se descarga ni se consulta TomTom de nuevo.
+//! Solo se guarda el mapa diario publicado: `?date=`, `?hour=` y `/od/flows` responden 503 hasta el
+//! primer recálculo.
+//! Cada fichero se escribe a un `.tmp` y se renombra, para no dejar nunca uno a medias.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use chrono::NaiveDate;
+use h3o::CellIndex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::models::h3types::{DayResult, DelayCfg, H3DailyRow, H3Metrics, ODRecord};
+use crate::models::types::{DataState, IngestReport, Kpis, SnapshotInfo, SourceFile};
+
+const FETCH_FILE: &str = "fetch.json";
+const PAYLOAD_FILE: &str = "od.payload";
+const ROWS_FILE: &str = "od_rows.json";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// Caché de la fuente y datos del último O/D leído
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedFetch {
+    pub od_url: String,
+    pub file: SourceFile,
+    pub content_type: Option<String>,
+    /// `OdSource::checkpoint`
+    pub source: serde_json::Value,
+    /// Ingesta incremental: las filas a restaurar son las de `od_rows.json`, no las del payload
+    #[serde(default)]
+    pub accumulated: bool,
+}
+
+/// Filas O/D acumuladas por la marca de agua hasta el fichero `sha256`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedRows {
+    pub sha256: String,
+    pub rows: Vec<ODRecord>,
+    pub report: IngestReport,
+}
+
+/// Último snapshot publicado
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedSnapshot {
+    pub snapshot_id: u64,
+    pub snapshot_ts_utc: String,
+    pub date: NaiveDate,
+    /// Config con la que se calculó: si la vigente es otra, se recalcula con el O/D guardado
+    pub delay_cfg: DelayCfg,
+    pub kpis: Kpis,
+    pub hotspots: Vec<CellIndex>,
+    pub rows: Vec<H3DailyRow>,
+    pub geojson: String,
+}
+
+impl SavedSnapshot {
+    pub fn new(snapshot_id: u64, date: NaiveDate, cfg: &DelayCfg, kpis: &Kpis, day: &DayResult) -> Self {
+        Self {
+            snapshot_id,
+            snapshot_ts_utc: kpis.snapshot_ts_utc.clone(),
+            date,
+            delay_cfg: cfg.clone(),
+            kpis: kpis.clone(),
+            hotspots: day.hotspots.clone(),
+            rows: day.metrics.values().map(|m| H3DailyRow::from_metrics(date, snapshot_id, m)).collect(),
+            geojson: day.geojson.clone(),
+        }
+    }
+
+    /// SHA-256 del O/D que lo produjo
+    pub fn od_sha256(&self) -> Option<&str> {
+        self.kpis.ingest.file.as_ref().map(|f| f.sha256.as_str())
+    }
+
+    /// Publica el snapshot en un `DataState` recién creado
+    pub fn restore(self, d: &mut DataState) {
+        let metrics = Arc::new(self.rows.iter().map(|r| (r.h3, H3Metrics::from_row(r))).collect());
+        d.snapshot_log.push_back(SnapshotInfo {
+            id: self.snapshot_id,
+            snapshot_ts_utc: self.snapshot_ts_utc.clone(),
+            date: self.date,
+            cfg_version: self.kpis.cfg_version,
+            cells: self.kpis.cells,
+        });
+        d.retained.push_back((self.snapshot_id, Arc::clone(&metrics)));
+        d.metrics = metrics;
+        d.hex_geojson = self.geojson;
+        d.hotspots = self.hotspots;
+        d.snapshot_id = self.snapshot_id;
+        d.snapshot_ts_utc = self.snapshot_ts_utc;
+        d.last_ingest = Some(self.kpis.ingest.clone());
+        d.snapshot_cfg = self.delay_cfg;
+        d.kpis = self.kpis;
+    }
+}
+
+pub struct StateDir {
+    dir: PathBuf,
+}
+
+impl StateDir {
+    pub fn new(root: &str, region: &str) -> Self {
+        Self { dir: Path::new(root).join(region) }
+    }
+
+    /// Guarda el O/D leído (y las filas acumuladas, si `saved.accumulated`) y, después, la caché
+    /// que apunta a él
+    pub async fn save_fetch(&self, saved: &SavedFetch, payload: &[u8], rows: Option<&SavedRows>) -> Result<()> {
+        self.write(PAYLOAD_FILE, payload).await?;
+        if let Some(rows) = rows.filter(|_| saved.accumulated) {
+            self.write(ROWS_FILE, &serde_json::to_vec(rows)?).await?;
+        }
+        self.write(FETCH_FILE, &serde_json::to_vec_pretty(saved)?).await
+    }
+
+    /// Filas acumuladas hasta el fichero de `saved`
+    pub fn load_rows(&self, saved: &SavedFetch) -> Option<SavedRows> {
+        let rows: SavedRows = self.read_json(ROWS_FILE)?;
+        if rows.sha256 != saved.file.sha256 {
+            warn!("estado: {ROWS_FILE} no corresponde al último O/D de {FETCH_FILE}, se ignora");
+            return None;
+        }
+        Some(rows)
+    }
+
+    /// Caché y O/D guardados, si el O/D sigue cuadrando con su SHA-256
+    pub fn load_fetch(&self) -> Option<(SavedFetch, Bytes)> {
+        let saved: SavedFetch = self.read_json(FETCH_FILE)?;
+        let path = self.dir.join(PAYLOAD_FILE);
+        let payload = match std::fs::read(&path) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("estado: {}: {e}", path.display());
+                return None;
+            }
+        };
+        if hex::encode(Sha256::digest(&payload)) != saved.file.sha256 {
+            warn!("estado: {} no coincide con el SHA-256 de {FETCH_FILE}, se ignora", path.display());
+            return None;
+        }
+        Some((saved, Bytes::from(payload)))
+    }
+
+    pub async fn save_snapshot(&self, s: &SavedSnapshot) -> Result<()> {
+        self.write(SNAPSHOT_FILE, &serde_json::to_vec(s)?).await
+    }
+
+    pub fn load_snapshot(&self) -> Option<SavedSnapshot> {
+        self.read_json(SNAPSHOT_FILE)
+    }
+
+    async fn write(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await.with_context(|| format!("no se pudo crear {}", self.dir.display()))?;
+        let path = self.dir.join(name);
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, bytes).await.with_context(|| format!("no se pudo escribir {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, &path).await.with_context(|| format!("no se pudo renombrar {}", tmp.display()))?;
+        Ok(())
+    }
+
+    /// Un fichero ausente no es un error (primer arranque); uno ilegible se avisa y se ignora
+    fn read_json<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        let path = self.dir.join(name);
+        let text = std::fs::read(&path).ok()?;
+        match serde_json::from_slice(&text) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("estado: {}: {e}", path.display());
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use h3o::{LatLng, Resolution};
+    use std::collections::{BTreeMap, HashMap};
+
+    #[tokio::test]
+    async fn state_roundtrip_and_restore() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("madgrid_state_{}", std::process::id()));
+        std::fs::remove_dir_all(&root).ok();
+        let st = StateDir::new(root.to_str().unwrap(), "logrono");
+        assert!(st.load_fetch().is_none() && st.load_snapshot().is_none());
+
+        let payload = b"date,origin_h3,dest_h3,n_trucks,n_cars\n";
+        let file = SourceFile { name: "od.csv".into(), bytes: payload.len(), sha256: hex::encode(Sha256::digest(payload)) };
+        let saved = SavedFetch {
+            od_url: "http://localhost/od.csv".into(),
+            file: file.clone(),
+            content_type: Some("text/csv".into()),
+            source: serde_json::json!({ "etag": "\"v1\"" }),
+            accumulated: false,
+        };
+        st.save_fetch(&saved, payload, None).await?;
+        let (back, bytes) = st.load_fetch().expect("fetch guardado");
+        assert_eq!((back.file, &bytes[..], back.source["etag"].as_str()), (file.clone(), &payload[..], Some("\"v1\"")));
+
+        // Por lotes: las filas acumuladas van aparte y solo valen con el mismo último fichero
+        let rec = ODRecord {
+            date: NaiveDate::from_ymd_opt(2025, 10, 28).unwrap(),
+            origin_h3: "871f24ac5ffffff".into(),
+            dest_h3: "871f24ac5ffffff".into(),
+            origin_lat: None,
+            origin_lon: None,
+            dest_lat: None,
+            dest_lon: None,
+            n_trucks: 1.0,
+            n_cars: 2.0,
+            conf: None,
+            hour: None,
+        };
+        let rows = SavedRows { sha256: file.sha256.clone(), rows: vec![rec.clone(), rec], report: IngestReport::default() };
+        let batch = SavedFetch { accumulated: true, ..saved.clone() };
+        st.save_fetch(&batch, payload, Some(&rows)).await?;
+        let (back, _) = st.load_fetch().expect("fetch guardado");
+        assert_eq!(st.load_rows(&back).map(|r| r.rows.len()), Some(2));
+        let other = SavedFetch { file: SourceFile { sha256: "00".into(), ..file.clone() }, ..back };
+        assert!(st.load_rows(&other).is_none());
+
+        // Un payload que no cuadra con su SHA-256 no se usa
+        std::fs::write(root.join("logrono").join(PAYLOAD_FILE), "otra cosa")?;
+        assert!(st.load_fetch().is_none());
+
+        let cell = LatLng::new(42.4627, -2.44498)?.to_cell(Resolution::Seven);
+        let mut m = H3Metrics::new(cell);
+        m.trips_total = 12.0;
+        m.delay_final = 1.3;
+        let day = DayResult {
+            metrics: HashMap::from([(cell, m)]),
+            geojson: r#"{"type":"FeatureCollection","features":[]}"#.into(),
+            hotspots: vec![cell],
+            hours: BTreeMap::new(),
+            hourly_input: false,
+            aggregates: HashMap::new(),
+            capacity: 0.0,
+            mean_vol: 0.0,
+        };
+        let date = NaiveDate::from_ymd_opt(2025, 10, 28).unwrap();
+        let mut kpis = Kpis { cells: 1, snapshot_ts_utc: "2025-10-28T06:00:00Z".into(), ..Default::default() };
+        kpis.ingest.file = Some(file);
+        let cfg = DelayCfg { bpr_a: 0.3, ..Default::default() };
+        st.save_snapshot(&SavedSnapshot::new(7, date, &cfg, &kpis, &day)).await?;
+
+        let snap = st.load_snapshot().expect("snapshot guardado");
+        assert_eq!(snap.od_sha256(), Some(saved.file.sha256.as_str()));
+        let mut d = DataState::default();
+        snap.restore(&mut d);
+        assert_eq!((d.snapshot_id, d.hotspots.as_slice(), d.hex_geojson.as_str()), (7, &[cell][..], day.geojson.as_str()));
+        assert_eq!((d.metrics[&cell].trips_total, d.metrics[&cell].delay_final), (12.0, 1.3));
+        assert_eq!((d.retained.len(), d.snapshot_log.len(), d.kpis.cells), (1, 1, 1));
+        assert_eq!(d.snapshot_cfg, cfg);
+        std::fs::remove_dir_all(&root).ok();
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+//! status.rs — Estado operativo del loop O/D de cada región (`/status`, `/health/ready`)
+//!
+//! El loop de `main.rs` rellena `DataState::status` en cada ciclo: resultado de la fuente, error
+//! del cálculo, último ciclo correcto, llamadas a TomTom y salud de cada sink histórico (un sink que
+//! falla no interrumpe el ciclo).
+//! TomTom y los sinks se envuelven (`CountingProvider`, `TrackedSink`) para contar sin tocarlos.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use h3o::CellIndex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::h3grid::{HistorySink, TrafficProvider};
+use crate::models::h3types::H3DailyRow;
+use crate::models::types::{DataState, SourceFile};
+
+/// Lo que registra el loop O/D
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LoopStatus {
+    pub last_fetch: Option<FetchStatus>,
+    /// Último fallo tras leer el fichero (parse, cálculo); se conserva tras un ciclo correcto.
+    /// Los fallos de los sinks no abortan el ciclo: van en `sinks`
+    pub last_compute_error: Option<ComputeError>,
+    /// Fin del último ciclo sin errores (haya o no O/D nuevo)
+    pub last_success_utc: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+    pub tomtom: ProviderStats,
+    pub sinks: BTreeMap<String, SinkHealth>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchResult {
+    /// Fichero nuevo
+    New,
+    /// Sin cambios (304, bandeja vacía, sin objetos nuevos)
+    NotModified,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FetchStatus {
+    pub ts_utc: DateTime<Utc>,
+    pub result: FetchResult,
+    pub file: Option<SourceFile>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ComputeError {
+    pub ts_utc: DateTime<Utc>,
+    pub error: String,
+}
+
+/// Llamadas al proveedor desde el arranque
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ProviderStats {
+    pub calls: u64,
+    /// Con delay
+    pub ok: u64,
+    /// Sin cobertura para la celda
+    pub empty: u64,
+    pub errors: u64,
+    /// `(ok + empty) / calls`; `None` sin llamadas
+    pub success_rate: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SinkHealth {
+    /// La última escritura fue bien (o aún no hubo ninguna)
+    pub ok: bool,
+    pub last_ok_utc: Option<DateTime<Utc>>,
+    pub last_error_utc: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub failures: u64,
+}
+
+// ===============================
+// Envoltorios que cuentan
+// ===============================
+
+pub struct CountingProvider<'a> {
+    inner: &'a dyn TrafficProvider,
+    ok: AtomicU64,
+    empty: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl<'a> CountingProvider<'a> {
+    pub fn new(inner: &'a dyn TrafficProvider) -> Self {
+        Self { inner, ok: AtomicU64::new(0), empty: AtomicU64::new(0), errors: AtomicU64::new(0) }
+    }
+
+    pub fn stats(&self) -> ProviderStats {
+        let (ok, empty, errors) =
+            (self.ok.load(Ordering::Relaxed), self.empty.load(Ordering::Relaxed), self.errors.load(Ordering::Relaxed));
+        let calls = ok + empty + errors;
+        let success_rate = (calls > 0).then(|| (ok + empty) as f32 / calls as f32);
+        ProviderStats { calls, ok, empty, errors, success_rate }
+    }
+}
+
+#[async_trait]
+impl TrafficProvider for CountingProvider<'_> {
+    async fn delay_for_cell(&self, cell: CellIndex) -> anyhow::Result<Option<(f32, f32)>> {
+        let r = self.inner.delay_for_cell(cell).await;
+        let counter = match &r {
+            Ok(Some(_)) => &self.ok,
+            Ok(None) => &self.empty,
+            Err(_) => &self.errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        r
+    }
+}
+
+pub struct TrackedSink<'a> {
+    pub name: &'static str,
+    inner: &'a dyn HistorySink,
+    health: Mutex<SinkHealth>,
+}
+
+impl<'a> TrackedSink<'a> {
+    pub fn new(name: &'static str, inner: &'a dyn HistorySink) -> Self {
+        Self { name, inner, health: Mutex::new(SinkHealth { ok: true, ..Default::default() }) }
+    }
+
+    pub fn health(&self) -> SinkHealth {
+        self.health.lock().expect("SinkHealth").clone()
+    }
+}
+
+#[async_trait]
+impl HistorySink for TrackedSink<'_> {
+    async fn persist(&self, rows: &[H3DailyRow]) -> anyhow::Result<()> {
+        let r = self.inner.persist(rows).await;
+        let mut h = self.health.lock().expect("SinkHealth");
+        let now = Utc::now();
+        match &r {
+            Ok(()) => {
+                h.ok = true;
+                h.last_ok_utc = Some(now);
+            }
+            Err(e) => {
+                h.ok = false;
+                h.last_error_utc = Some(now);
+                h.last_error = Some(format!("{e:#}"));
+                h.failures += 1;
+            }
+        }
+        r
+    }
+}
+
+// ===============================
+// Readiness y /status
+// ===============================
+
+/// Edad del snapshot publicado (s); `None` sin snapshot
+pub fn snapshot_age_s(d: &DataState, now: DateTime<Utc>) -> Option<i64> {
+    if d.snapshot_id == 0 {
+        return None;
+    }
+    let ts = DateTime::parse_from_rfc3339(&d.snapshot_ts_utc).ok()?;
+    Some((now - ts.with_timezone(&Utc)).num_seconds().max(0))
+}
+
+/// Lista para servir: hay snapshot y no pasa de `max_age_s` (0 = sin límite de edad)
+pub fn readiness(d: &DataState, max_age_s: u64, now: DateTime<Utc>) -> Result<(), String> {
+    match snapshot_age_s(d, now) {
+        None => Err("sin snapshot".into()),
+        Some(age) if max_age_s > 0 && age as u64 > max_age_s => {
+            Err(format!("snapshot de hace {age} s (máximo {max_age_s} s)"))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Respuesta de `/status` para una región
+#[derive(Clone, Debug, Serialize)]
+pub struct StatusReport {
+    pub region: String,
+    pub ready: bool,
+    pub not_ready: Option<String>,
+    pub snapshot_id: u64,
+    pub snapshot_ts_utc: Option<String>,
+    pub snapshot_age_s: Option<i64>,
+    pub since_last_success_s: Option<i64>,
+    /// Error del último refresco fallido (fuente o cálculo; se limpia al publicar un snapshot)
+    pub last_error: Option<String>,
+    #[serde(flatten)]
+    pub status: LoopStatus,
+}
+
+impl StatusReport {
+    pub fn new(region: &str, d: &DataState, max_age_s: u64, now: DateTime<Utc>) -> Self {
+        let ready = readiness(d, max_age_s, now);
+        Self {
+            region: region.to_string(),
+            ready: ready.is_ok(),
+            not_ready: ready.err(),
+            snapshot_id: d.snapshot_id,
+            snapshot_ts_utc: (d.snapshot_id > 0).then(|| d.snapshot_ts_utc.clone()),
+            snapshot_age_s: snapshot_age_s(d, now),
+            since_last_success_s: d.status.last_success_utc.map(|t| (now - t).num_seconds().max(0)),
+            last_error: d.last_error.clone(),
+            status: d.status.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use h3o::{LatLng, Resolution};
+
+    struct Flaky;
+
+    #[async_trait]
+    impl TrafficProvider for Flaky {
+        async fn delay_for_cell(&self, cell: CellIndex) -> anyhow::Result<Option<(f32, f32)>> {
+            match u8::from(cell.resolution()) {
+                7 => Ok(Some((1.4, 0.9))),
+                8 => Ok(None),
+                _ => bail!("HTTP 429"),
+            }
+        }
+    }
+
+    struct Broken;
+
+    #[async_trait]
+    impl HistorySink for Broken {
+        async fn persist(&self, _rows: &[H3DailyRow]) -> anyhow::Result<()> {
+            bail!("disco lleno")
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_calls_tracks_sinks_and_readiness() {
+        let ll = LatLng::new(42.4627, -2.44498).unwrap();
+        let p = CountingProvider::new(&Flaky);
+        for res in [Resolution::Seven, Resolution::Seven, Resolution::Eight, Resolution::Nine] {
+            let _ = p.delay_for_cell(ll.to_cell(res)).await;
+        }
+        let s = p.stats();
+        assert_eq!((s.calls, s.ok, s.empty, s.errors, s.success_rate), (4, 2, 1, 1, Some(0.75)));
+
+        let sink = TrackedSink::new("jsonl", &Broken);
+        assert!(sink.persist(&[]).await.is_err());
+        // En el fan-out el fallo no aborta el cálculo, pero queda en la salud del sink
+        let fanout = crate::h3grid::FanoutSink { sinks: vec![&sink as &dyn HistorySink] };
+        assert!(fanout.persist(&[]).await.is_ok());
+        let h = sink.health();
+        assert_eq!((h.ok, h.failures, h.last_error.as_deref()), (false, 2, Some("disco lleno")));
+
+        let now = Utc::now();
+        let mut d = DataState::default();
+        assert_eq!(readiness(&d, 60, now), Err("sin snapshot".into()));
+        d.snapshot_id = 1;
+        d.snapshot_ts_utc = (now - chrono::Duration::seconds(120)).to_rfc3339();
+        assert!(readiness(&d, 60, now).is_err());
+        assert!(readiness(&d, 600, now).is_ok() && readiness(&d, 0, now).is_ok());
+        d.status.last_success_utc = Some(now - chrono::Duration::seconds(30));
+        let r = StatusReport::new("logrono", &d, 600, now);
+        assert_eq!((r.ready, r.snapshot_age_s, r.since_last_success_s), (true, Some(120), Some(30)));
+    }
+}
@@ -0,0 +1,408 @@
+//! tiles.rs — Teselas vectoriales (Mapbox Vector Tile v2) de la capa H3 y de las zonas S2
+//!
+//! Codificación protobuf mínima escrita a mano (solo polígonos y propiedades escalares).
+//! Los polígonos no se recortan: el cliente recorta con `extent` + buffer, como permite la spec.
+//! Cada tesela se genera una vez por snapshot y se guarda en un `TileCache`.
+
+use bytes::Bytes;
+use h3o::{CellIndex, Resolution};
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+use std::sync::Mutex;
+
+use crate::h3grid::{cell_polygon_coords, color_from_norm};
+use crate::models::h3types::{DelayCfg, H3Metrics};
+
+/// Resolución de coordenadas dentro de la tesela
+pub const EXTENT: u32 = 4096;
+/// Margen (en unidades de tesela) para incluir polígonos que tocan el borde
+const BUFFER: f64 = 64.0;
+/// Zoom máximo aceptado
+pub const MAX_ZOOM: u8 = 24;
+/// Teselas por caché antes de vaciarla
+const CACHE_MAX_TILES: usize = 4096;
+
+pub const MVT_CONTENT_TYPE: &str = "application/vnd.mapbox-vector-tile";
+
+// ===============================
+// Tesela z/x/y (Web Mercator)
+// ===============================
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TileId {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileId {
+    /// `z`, `x`, `y` tal y como llegan en la ruta (`y` admite el sufijo `.mvt`)
+    pub fn parse(z: &str, x: &str, y: &str) -> Result<Self, String> {
+        let y = y.strip_suffix(".mvt").unwrap_or(y);
+        let z: u8 = z.parse().map_err(|_| format!("z inválido: {z:?}"))?;
+        let x: u32 = x.parse().map_err(|_| format!("x inválido: {x:?}"))?;
+        let y: u32 = y.parse().map_err(|_| format!("y inválido: {y:?}"))?;
+        if z > MAX_ZOOM {
+            return Err(format!("z debe ser <= {MAX_ZOOM}"));
+        }
+        let n = 1u64 << z;
+        if u64::from(x) >= n || u64::from(y) >= n {
+            return Err(format!("tesela {z}/{x}/{y} fuera de rango"));
+        }
+        Ok(Self { z, x, y })
+    }
+
+    /// Coordenadas de tesela (0..EXTENT) de un punto lon/lat
+    fn project(&self, lon: f64, lat: f64) -> (i64, i64) {
+        let n = (1u64 << self.z) as f64;
+        let lat = lat.clamp(-85.051_128_78, 85.051_128_78).to_radians();
+        let fx = (lon + 180.0) / 360.0 * n;
+        let fy = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * n;
+        let e = f64::from(EXTENT);
+        (((fx - f64::from(self.x)) * e).round() as i64, ((fy - f64::from(self.y)) * e).round() as i64)
+    }
+
+    /// El anillo cae (con buffer) dentro de la tesela
+    fn touches(&self, pts: &[(i64, i64)]) -> bool {
+        let (lo, hi) = (-BUFFER as i64, i64::from(EXTENT) + BUFFER as i64);
+        let min_x = pts.iter().map(|p| p.0).min().unwrap_or(i64::MAX);
+        let max_x = pts.iter().map(|p| p.0).max().unwrap_or(i64::MIN);
+        let min_y = pts.iter().map(|p| p.1).min().unwrap_or(i64::MAX);
+        let max_y = pts.iter().map(|p| p.1).max().unwrap_or(i64::MIN);
+        max_x >= lo && min_x <= hi && max_y >= lo && min_y <= hi
+    }
+}
+
+// ===============================
+// Protobuf
+// ===============================
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u32, wire: u8) {
+    put_varint(buf, (u64::from(field) << 3) | u64::from(wire));
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u32, data: &[u8]) {
+    put_key(buf, field, 2);
+    put_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn put_uint(buf: &mut Vec<u8>, field: u32, v: u64) {
+    put_key(buf, field, 0);
+    put_varint(buf, v);
+}
+
+fn put_packed(buf: &mut Vec<u8>, field: u32, vals: &[u32]) {
+    let mut inner = Vec::with_capacity(vals.len() * 2);
+    for v in vals {
+        put_varint(&mut inner, u64::from(*v));
+    }
+    put_bytes(buf, field, &inner);
+}
+
+fn zigzag(v: i64) -> u32 {
+    ((v << 1) ^ (v >> 63)) as u32
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+// ===============================
+// Capas
+// ===============================
+
+/// Valor de propiedad de una feature
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    F64(f64),
+    U64(u64),
+    Bool(bool),
+}
+
+impl Value {
+    fn intern_key(&self) -> (u8, u64, String) {
+        match self {
+            Self::Str(s) => (0, 0, s.clone()),
+            Self::F64(f) => (1, f.to_bits(), String::new()),
+            Self::U64(u) => (2, *u, String::new()),
+            Self::Bool(b) => (3, u64::from(*b), String::new()),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Self::Str(s) => put_bytes(&mut buf, 1, s.as_bytes()),
+            Self::F64(f) => {
+                put_key(&mut buf, 3, 1);
+                buf.extend_from_slice(&f.to_le_bytes());
+            }
+            Self::U64(u) => put_uint(&mut buf, 5, *u),
+            Self::Bool(b) => put_uint(&mut buf, 7, u64::from(*b)),
+        }
+        buf
+    }
+}
+
+/// Redondeo a 2 decimales, como en el GeoJSON
+fn round2(v: f32) -> Value {
+    Value::F64((f64::from(v) * 100.0).round() / 100.0)
+}
+
+/// Capa en construcción: claves/valores deduplicados y features ya codificadas
+pub struct LayerBuilder {
+    name: String,
+    keys: Vec<String>,
+    key_idx: HashMap<String, u32>,
+    values: Vec<Value>,
+    value_idx: HashMap<(u8, u64, String), u32>,
+    features: Vec<Vec<u8>>,
+}
+
+impl LayerBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            keys: Vec::new(),
+            key_idx: HashMap::new(),
+            values: Vec::new(),
+            value_idx: HashMap::new(),
+            features: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    fn key(&mut self, k: &str) -> u32 {
+        if let Some(i) = self.key_idx.get(k) {
+            return *i;
+        }
+        let i = self.keys.len() as u32;
+        self.keys.push(k.to_string());
+        self.key_idx.insert(k.to_string(), i);
+        i
+    }
+
+    fn value(&mut self, v: Value) -> u32 {
+        let k = v.intern_key();
+        if let Some(i) = self.value_idx.get(&k) {
+            return *i;
+        }
+        let i = self.values.len() as u32;
+        self.values.push(v);
+        self.value_idx.insert(k, i);
+        i
+    }
+
+    /// Añade un polígono (anillo exterior lon/lat) si toca la tesela. Devuelve si se añadió.
+    pub fn add_polygon(&mut self, tile: &TileId, id: u64, ring: &[[f64; 2]], props: Vec<(&str, Value)>) -> bool {
+        let Some(geom) = ring_geometry(tile, ring) else { return false };
+
+        let mut tags = Vec::with_capacity(props.len() * 2);
+        for (k, v) in props {
+            tags.push(self.key(k));
+            tags.push(self.value(v));
+        }
+
+        let mut feat = Vec::new();
+        put_uint(&mut feat, 1, id);
+        put_packed(&mut feat, 2, &tags);
+        put_uint(&mut feat, 3, 3); // POLYGON
+        put_packed(&mut feat, 4, &geom);
+        self.features.push(feat);
+        true
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_uint(&mut buf, 15, 2); // version
+        put_bytes(&mut buf, 1, self.name.as_bytes());
+        for f in &self.features {
+            put_bytes(&mut buf, 2, f);
+        }
+        for k in &self.keys {
+            put_bytes(&mut buf, 3, k.as_bytes());
+        }
+        for v in &self.values {
+            put_bytes(&mut buf, 4, &v.encode());
+        }
+        put_uint(&mut buf, 5, u64::from(EXTENT));
+        buf
+    }
+}
+
+/// Comandos MVT (MoveTo, LineTo, ClosePath) de un anillo exterior, o `None` si no toca la tesela
+fn ring_geometry(tile: &TileId, ring: &[[f64; 2]]) -> Option<Vec<u32>> {
+    let mut pts: Vec<(i64, i64)> = Vec::with_capacity(ring.len());
+    for [lon, lat] in ring {
+        let p = tile.project(*lon, *lat);
+        if pts.last() != Some(&p) {
+            pts.push(p);
+        }
+    }
+    // El anillo se cierra con ClosePath, sin repetir el primer punto
+    if pts.len() > 1 && pts.first() == pts.last() {
+        pts.pop();
+    }
+    if pts.len() < 3 || !tile.touches(&pts) {
+        return None;
+    }
+    // Exterior con área positiva en coordenadas de tesela (y hacia abajo)
+    let area: i64 = (0..pts.len())
+        .map(|i| {
+            let (a, b) = (pts[i], pts[(i + 1) % pts.len()]);
+            a.0 * b.1 - b.0 * a.1
+        })
+        .sum();
+    if area == 0 {
+        return None;
+    }
+    if area < 0 {
+        pts.reverse();
+    }
+
+    let mut geom = Vec::with_capacity(pts.len() * 2 + 3);
+    let (mut cx, mut cy) = (0i64, 0i64);
+    for (i, (x, y)) in pts.iter().enumerate() {
+        if i == 0 {
+            geom.push(command(1, 1));
+        } else if i == 1 {
+            geom.push(command(2, pts.len() as u32 - 1));
+        }
+        geom.push(zigzag(x - cx));
+        geom.push(zigzag(y - cy));
+        (cx, cy) = (*x, *y);
+    }
+    geom.push(command(7, 1));
+    Some(geom)
+}
+
+/// Tesela completa (las capas vacías se omiten)
+pub fn encode_tile(layers: &[LayerBuilder]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for l in layers.iter().filter(|l| !l.is_empty()) {
+        put_bytes(&mut buf, 3, &l.encode());
+    }
+    buf
+}
+
+/// Capa `hex_delay_h3`: mismas propiedades que `/map/hex` más `res`, `hotspot` y `color`
+pub fn hex_layer(
+    tile: &TileId,
+    metrics: &HashMap<CellIndex, H3Metrics>,
+    hotspots: &[CellIndex],
+    cfg: &DelayCfg,
+) -> LayerBuilder {
+    let base_res = Resolution::try_from(cfg.res).unwrap_or(Resolution::Seven);
+    let hotspots: HashSet<CellIndex> = hotspots.iter().copied().collect();
+    let mut layer = LayerBuilder::new("hex_delay_h3");
+    for (c, m) in metrics {
+        let d = m.delay_final;
+        let norm = ((d - 1.0) / (cfg.delay_max - 1.0)).clamp(0.0, 1.0);
+        let parent = c.parent(base_res).unwrap_or(*c);
+        let props = vec![
+            ("h3", Value::Str(c.to_string())),
+            ("res", Value::U64(u64::from(u8::from(c.resolution())))),
+            ("delay_final", round2(d)),
+            ("delay_orange", round2(m.delay_orange)),
+            ("delay_tomtom", round2(m.delay_tomtom)),
+            ("vol_norm", round2(m.vol_norm)),
+            ("truck_share", round2(m.truck_share)),
+            ("used_tomtom", Value::Bool(m.delay_tomtom > 0.0)),
+            ("conf", round2(m.conf_cell())),
+            ("hotspot", Value::Bool(hotspots.contains(c) || hotspots.contains(&parent))),
+            ("color", Value::Str(color_from_norm(norm).to_string())),
+        ];
+        layer.add_polygon(tile, u64::from(*c), &cell_polygon_coords(*c), props);
+    }
+    layer
+}
+
+// ===============================
+// Caché por snapshot
+// ===============================
+
+/// Teselas ya codificadas de un snapshot. Cambiar de snapshot vacía la caché.
+#[derive(Default)]
+pub struct TileCache {
+    inner: Mutex<(u64, HashMap<TileId, Bytes>)>,
+}
+
+impl TileCache {
+    pub fn get_or_render(&self, snapshot: u64, tile: TileId, render: impl FnOnce() -> Vec<u8>) -> Bytes {
+        {
+            let g = self.inner.lock().expect("caché de teselas");
+            if g.0 == snapshot {
+                if let Some(b) = g.1.get(&tile) {
+                    return b.clone();
+                }
+            }
+        }
+        let body = Bytes::from(render());
+        let mut g = self.inner.lock().expect("caché de teselas");
+        if g.0 != snapshot {
+            *g = (snapshot, HashMap::new());
+        }
+        if g.1.len() >= CACHE_MAX_TILES {
+            g.1.clear();
+        }
+        g.1.insert(tile, body.clone());
+        body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use h3o::LatLng;
+
+    #[test]
+    fn encodes_hex_in_covering_tile_only() {
+        let ll = LatLng::new(42.4627, -2.44498).unwrap();
+        let c = ll.to_cell(Resolution::Seven);
+        let metrics = HashMap::from([(c, H3Metrics::new(c))]);
+        let cfg = DelayCfg::default();
+
+        // z=12 que contiene Logroño
+        let n = 4096.0;
+        let x = ((-2.44498 + 180.0) / 360.0 * n) as u32;
+        let lat = 42.4627f64.to_radians();
+        let y = ((1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * n) as u32;
+        let tile = TileId::parse("12", &x.to_string(), &format!("{y}.mvt")).unwrap();
+
+        let layer = hex_layer(&tile, &metrics, &[], &cfg);
+        assert_eq!(layer.features.len(), 1);
+        let bytes = encode_tile(&[layer]);
+        assert_eq!(bytes[0], 0x1a); // campo 3 (layers), length-delimited
+        assert!(bytes.windows(12).any(|w| w == b"hex_delay_h3"));
+
+        let far = TileId::parse("12", "0", "0").unwrap();
+        assert!(hex_layer(&far, &metrics, &[], &cfg).is_empty());
+        assert!(TileId::parse("3", "8", "0").is_err());
+    }
+
+    #[test]
+    fn polygon_geometry_commands() {
+        let tile = TileId { z: 0, x: 0, y: 0 };
+        // Cuadrado en sentido antihorario (lon/lat): se invierte para que el área sea positiva
+        let ring = [[-90.0, -40.0], [90.0, -40.0], [90.0, 40.0], [-90.0, 40.0], [-90.0, -40.0]];
+        let g = ring_geometry(&tile, &ring).unwrap();
+        assert_eq!(g.len(), 1 + 2 + 1 + 6 + 1);
+        assert_eq!((g[0], g[3], g[10]), (command(1, 1), command(2, 3), command(7, 1)));
+        // Primer punto (-90, 40) -> (1024, y<2048)
+        assert_eq!(g[1], zigzag(1024));
+        assert!(g[2] < zigzag(2048));
+    }
+}
@@ -0,0 +1,182 @@
+//! tuning.rs — Ajuste en caliente del `DelayCfg` H3 (BPR, camiones, capacidad, límites)
+//!
+//! Dos vías de cambio: el fichero de configuración (sondeo de mtime) y `PUT /admin/delay-cfg`.
+//! Cada cambio se valida, recibe una versión nueva y se publica por un `watch`; el loop O/D
+//! lo recibe y relanza `compute_day` en el momento, sin esperar al siguiente refresco.
+//! `res`, `min_conf_for_pure_orange` y `max_concurrent_calls` vienen de `AppCfg` y no cambian en caliente.
+
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::config::{self, ConfigError, Settings};
+use crate::models::h3types::DelayCfg as ODDelayCfg;
+use crate::region::Regions;
+
+/// Cambios de config que se conservan para `/admin/delay-cfg/history`
+const HISTORY_LEN: usize = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CfgOrigin {
+    Startup,
+    File,
+    Api,
+}
+
+/// Una versión publicada del DelayCfg
+#[derive(Clone, Debug, Serialize)]
+pub struct CfgVersion {
+    pub version: u64,
+    pub applied_utc: String,
+    pub origin: CfgOrigin,
+    pub cfg: ODDelayCfg,
+}
+
+pub struct DelayTuning {
+    tx: watch::Sender<Arc<CfgVersion>>,
+    history: Mutex<VecDeque<CfgVersion>>,
+}
+
+impl DelayTuning {
+    pub fn new(initial: ODDelayCfg) -> Self {
+        let v = CfgVersion {
+            version: 1,
+            applied_utc: Utc::now().to_rfc3339(),
+            origin: CfgOrigin::Startup,
+            cfg: initial,
+        };
+        let (tx, _rx) = watch::channel(Arc::new(v.clone()));
+        Self { tx, history: Mutex::new(VecDeque::from([v])) }
+    }
+
+    pub fn current(&self) -> Arc<CfgVersion> {
+        self.tx.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<CfgVersion>> {
+        self.tx.subscribe()
+    }
+
+    /// Historial de versiones (más antigua primero)
+    pub fn history(&self) -> Vec<CfgVersion> {
+        self.history.lock().expect("historial DelayCfg").iter().cloned().collect()
+    }
+
+    /// Valida y publica un DelayCfg completo. Si no cambia nada, devuelve la versión actual.
+    pub fn apply(&self, mut cfg: ODDelayCfg, origin: CfgOrigin) -> Result<Arc<CfgVersion>, ConfigError> {
+        let cur = self.current();
+        cfg.res = cur.cfg.res;
+        cfg.min_conf_for_pure_orange = cur.cfg.min_conf_for_pure_orange;
+        cfg.max_concurrent_calls = cur.cfg.max_concurrent_calls;
+        config::validate_delay(&cfg)?;
+
+        if cfg == cur.cfg {
+            return Ok(cur);
+        }
+
+        let next = CfgVersion {
+            version: cur.version + 1,
+            applied_utc: Utc::now().to_rfc3339(),
+            origin,
+            cfg,
+        };
+        {
+            let mut h = self.history.lock().expect("historial DelayCfg");
+            h.push_back(next.clone());
+            while h.len() > HISTORY_LEN {
+                h.pop_front();
+            }
+        }
+        let next = Arc::new(next);
+        self.tx.send_replace(next.clone());
+        Ok(next)
+    }
+
+    /// Aplica un parche JSON parcial (`{"bpr_a": 0.2}`) sobre la versión actual.
+    pub fn patch(&self, patch: serde_json::Value, origin: CfgOrigin) -> Result<Arc<CfgVersion>, ConfigError> {
+        let serde_json::Value::Object(fields) = patch else {
+            return Err(ConfigError::Invalid {
+                field: ".".into(),
+                reason: "se esperaba un objeto JSON".into(),
+            });
+        };
+        let mut base = serde_json::to_value(&self.current().cfg).unwrap_or_default();
+        if let Some(obj) = base.as_object_mut() {
+            obj.extend(fields);
+        }
+        let cfg: ODDelayCfg = serde_path_to_error::deserialize(base).map_err(|e| ConfigError::Invalid {
+            field: e.path().to_string(),
+            reason: e.into_inner().to_string(),
+        })?;
+        self.apply(cfg, origin)
+    }
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Vigila el fichero de configuración y publica el `[delay]` nuevo de cada región cuando cambia.
+/// Añadir o quitar regiones requiere reiniciar.
+pub async fn watch_config_file(settings: Settings, regions: Arc<Regions>, every: Duration) {
+    let Some(path) = settings.source.clone() else { return };
+    let mut last = mtime(&path);
+    info!("Vigilando {} cada {:?} para recargar [delay]", path.display(), every);
+
+    loop {
+        tokio::time::sleep(every).await;
+        let now = mtime(&path);
+        if now == last {
+            continue;
+        }
+        last = now;
+
+        let reloaded = match settings.reload() {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Recarga de {} descartada: {e}", path.display());
+                continue;
+            }
+        };
+        for rs in reloaded.regions {
+            let Some(region) = regions.get(&rs.id) else {
+                warn!("Región nueva {:?} en {}: requiere reinicio", rs.id, path.display());
+                continue;
+            };
+            match region.tuning.apply(rs.delay, CfgOrigin::File) {
+                Ok(v) => info!("[{}] DelayCfg recargado de {} (v{})", rs.id, path.display(), v.version),
+                Err(e) => warn!("[{}] recarga de {} descartada: {e}", rs.id, path.display()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patch_validates_and_versions() {
+        let t = DelayTuning::new(ODDelayCfg::default());
+        let mut rx = t.subscribe();
+
+        let v = t.patch(serde_json::json!({ "bpr_a": 0.3, "res": 12 }), CfgOrigin::Api).unwrap();
+        assert_eq!(v.version, 2);
+        assert_eq!(v.cfg.bpr_a, 0.3);
+        assert_eq!(v.cfg.res, ODDelayCfg::default().res);
+        assert!(rx.has_changed().unwrap());
+        rx.mark_unchanged();
+
+        let err = t.patch(serde_json::json!({ "vc_cap": -1.0 }), CfgOrigin::Api).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { ref field, .. } if field == "vc_cap"));
+        assert_eq!(t.current().version, 2);
+        assert!(!rx.has_changed().unwrap());
+        assert_eq!(t.history().len(), 2);
+    }
+}